@@ -0,0 +1,300 @@
+// Keeping count of the memory an `AddressSpace` uses.
+//
+// The counters are updated as mappings come and go and pages are faulted in and dropped, so
+// reading them is cheap enough for a scheduler to do on every tick. The per-mapping breakdown
+// behind `AddressSpace::smaps` is worked out when it's asked for instead.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use crate::address_space::{FlagBuilder, PAGE_SIZE};
+use crate::cacher::{CacheCoordinator, LruList, LruLists};
+use crate::memcg::MemoryGroup;
+use crate::numa::MemoryPolicy;
+use crate::physical::{PhysicalMemory, Placement};
+
+/// Pages covered by one leaf page table, and by one table a level up from that.
+const LEAF_TABLE_PAGES: usize = 512;
+const MID_TABLE_PAGES: usize = 512 * LEAF_TABLE_PAGES;
+
+/// How much memory an `AddressSpace` is using. Every count is in pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Pages covered by mappings, whether resident or not.
+    pub virtual_pages: usize,
+    /// Resident pages of private mappings of files.
+    pub resident_file: usize,
+    /// Resident pages of private anonymous mappings.
+    pub resident_anon: usize,
+    /// Resident pages of shared mappings.
+    pub resident_shared: usize,
+    /// Pages written out to swap.
+    pub swapped: usize,
+    /// Pages locked with `lock_range` or `lock_all`.
+    pub locked: usize,
+    /// Page tables needed to translate the resident pages, assuming three levels of 512 entries.
+    pub page_tables: usize,
+    /// The most `virtual_pages` has been.
+    pub peak_virtual: usize,
+    /// The most resident pages there have been.
+    pub peak_resident: usize,
+}
+
+impl MemoryUsage {
+    /// All resident pages.
+    #[must_use]
+    pub const fn resident(&self) -> usize {
+        self.resident_file + self.resident_anon + self.resident_shared
+    }
+}
+
+/// How much memory one mapping is using, like an entry of `/proc/<pid>/smaps`. Counts are in
+/// pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappingUsage {
+    pub start: usize,
+    pub end: usize,
+    /// Where in its `DataSource` the mapping starts.
+    pub offset: usize,
+    pub flags: FlagBuilder,
+    pub anonymous: bool,
+    pub size: usize,
+    pub resident: usize,
+    pub dirty: usize,
+    pub swapped: usize,
+    pub locked: usize,
+}
+
+/// Which resident counter and which LRU lists a mapping's pages go under.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageKind {
+    File,
+    Anon,
+    SharedFile,
+    SharedAnon,
+}
+
+impl PageKind {
+    /// Is this page cached from a file, rather than anonymous memory?
+    pub const fn is_file(self) -> bool {
+        matches!(self, PageKind::File | PageKind::SharedFile)
+    }
+
+    const fn inactive_list(self) -> LruList {
+        match self {
+            PageKind::File | PageKind::SharedFile => LruList::InactiveFile,
+            PageKind::Anon | PageKind::SharedAnon => LruList::InactiveAnon,
+        }
+    }
+}
+
+/// The running counters behind `MemoryUsage`, along with the LRU lists of resident pages, the
+/// `CacheCoordinator` their frames are taken from, the `MemoryGroup` they're charged to and the
+/// `PhysicalMemory` they're kept in.
+pub struct Accounting {
+    usage: MemoryUsage,
+    leaf_tables: BTreeMap<usize, usize>, // resident pages under each leaf table
+    mid_tables: BTreeMap<usize, usize>,  // resident pages under each table a level up
+    pub lru: LruLists,
+    frames: Option<Arc<CacheCoordinator>>,
+    group: Option<Arc<MemoryGroup>>,
+    memory: Option<Arc<PhysicalMemory>>,
+    node: usize, // of the CPU faulting pages in
+}
+
+impl Accounting {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            usage: MemoryUsage {
+                page_tables: 1, // the root table is always there
+                ..MemoryUsage::default()
+            },
+            leaf_tables: BTreeMap::new(),
+            mid_tables: BTreeMap::new(),
+            lru: LruLists::default(),
+            frames: None,
+            group: None,
+            memory: None,
+            node: 0,
+        }
+    }
+
+    /// Take frames for resident pages from `frames` from now on, starting with the pages that
+    /// are already resident.
+    pub fn set_coordinator(&mut self, frames: Arc<CacheCoordinator>) {
+        frames.take(self.usage.resident());
+        if let Some(old) = self.frames.replace(frames) {
+            old.give_back(self.usage.resident());
+        }
+    }
+
+    #[must_use]
+    pub fn coordinator(&self) -> Option<&Arc<CacheCoordinator>> {
+        self.frames.as_ref()
+    }
+
+    /// Charge everything resident or swapped out to `group` from now on, taking it back out of
+    /// the group it was charged to before.
+    pub fn set_group(&mut self, group: Arc<MemoryGroup>) {
+        let (anon, file) = self.resident_split();
+        group.join(anon, file, self.usage.swapped);
+        if let Some(old) = self.group.replace(group) {
+            old.leave(anon, file, self.usage.swapped);
+        }
+    }
+
+    #[must_use]
+    pub fn group(&self) -> Option<&Arc<MemoryGroup>> {
+        self.group.as_ref()
+    }
+
+    /// Keep pages made resident from now on in frames of `memory`.
+    pub fn set_memory(&mut self, memory: Arc<PhysicalMemory>) {
+        self.memory = Some(memory);
+    }
+
+    #[must_use]
+    pub fn memory(&self) -> Option<&Arc<PhysicalMemory>> {
+        self.memory.as_ref()
+    }
+
+    pub fn set_node(&mut self, node: usize) {
+        self.node = node;
+    }
+
+    #[must_use]
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
+    /// Where to put pages faulted in under `policy`, if they go in `PhysicalMemory`.
+    #[must_use]
+    pub fn placement(&self, policy: MemoryPolicy) -> Option<Placement<'_>> {
+        self.memory.as_ref().map(|memory| Placement {
+            memory,
+            policy,
+            node: self.node,
+        })
+    }
+
+    /// How many more pages can be made resident under the group's hard limit.
+    #[must_use]
+    pub fn room(&self) -> usize {
+        self.group.as_ref().map_or(usize::MAX, |group| group.room())
+    }
+
+    /// The counters, with `locked` pages filled in.
+    #[must_use]
+    pub fn usage(&self, locked: usize) -> MemoryUsage {
+        MemoryUsage {
+            locked,
+            ..self.usage
+        }
+    }
+
+    #[must_use]
+    pub fn virtual_pages(&self) -> usize {
+        self.usage.virtual_pages
+    }
+
+    pub fn map(&mut self, pages: usize) {
+        self.usage.virtual_pages += pages;
+        self.usage.peak_virtual = self.usage.peak_virtual.max(self.usage.virtual_pages);
+    }
+
+    pub fn unmap(&mut self, pages: usize) {
+        self.usage.virtual_pages -= pages;
+    }
+
+    /// Count the page at `addr` as resident.
+    pub fn page_in(&mut self, kind: PageKind, addr: usize) {
+        *self.resident_mut(kind) += 1;
+        self.lru.push(addr, kind.inactive_list());
+        if let Some(frames) = &self.frames {
+            frames.take(1);
+        }
+        if let Some(group) = &self.group {
+            group.charge(kind);
+        }
+        let page = addr / PAGE_SIZE;
+        for (tables, index) in [
+            (&mut self.leaf_tables, page / LEAF_TABLE_PAGES),
+            (&mut self.mid_tables, page / MID_TABLE_PAGES),
+        ] {
+            let count = tables.entry(index).or_insert(0);
+            if *count == 0 {
+                self.usage.page_tables += 1;
+            }
+            *count += 1;
+        }
+        self.usage.peak_resident = self.usage.peak_resident.max(self.usage.resident());
+    }
+
+    /// Stop counting the page at `addr` as resident.
+    pub fn page_out(&mut self, kind: PageKind, addr: usize) {
+        *self.resident_mut(kind) -= 1;
+        self.lru.remove(addr);
+        if let Some(frames) = &self.frames {
+            frames.give_back(1);
+        }
+        if let Some(group) = &self.group {
+            group.uncharge(kind);
+        }
+        let page = addr / PAGE_SIZE;
+        for (tables, index) in [
+            (&mut self.leaf_tables, page / LEAF_TABLE_PAGES),
+            (&mut self.mid_tables, page / MID_TABLE_PAGES),
+        ] {
+            let count = tables.get_mut(&index).expect("Bad things are happening.");
+            *count -= 1;
+            if *count == 0 {
+                tables.remove(&index);
+                self.usage.page_tables -= 1;
+            }
+        }
+    }
+
+    /// Count a page as written out to swap.
+    pub fn swapped_out(&mut self) {
+        self.usage.swapped += 1;
+        if let Some(group) = &self.group {
+            group.charge_swap();
+        }
+    }
+
+    /// Stop counting a page as written out to swap.
+    pub fn swap_released(&mut self) {
+        self.usage.swapped -= 1;
+        if let Some(group) = &self.group {
+            group.uncharge_swap();
+        }
+    }
+
+    /// Resident pages of anonymous memory, and cached from files.
+    fn resident_split(&self) -> (usize, usize) {
+        let file = self.lru.len(LruList::ActiveFile) + self.lru.len(LruList::InactiveFile);
+        (self.usage.resident() - file, file)
+    }
+
+    fn resident_mut(&mut self, kind: PageKind) -> &mut usize {
+        match kind {
+            PageKind::File => &mut self.usage.resident_file,
+            PageKind::Anon => &mut self.usage.resident_anon,
+            PageKind::SharedFile | PageKind::SharedAnon => &mut self.usage.resident_shared,
+        }
+    }
+}
+
+impl Drop for Accounting {
+    /// Give the frames of every page still resident back, and leave the group.
+    fn drop(&mut self) {
+        if let Some(frames) = &self.frames {
+            frames.give_back(self.usage.resident());
+        }
+        if let Some(group) = &self.group {
+            let (anon, file) = self.resident_split();
+            group.leave(anon, file, self.usage.swapped);
+        }
+    }
+}
@@ -1,30 +1,359 @@
-use std::collections::LinkedList;
-use std::sync::Arc;
+use alloc::collections::{BTreeMap, LinkedList};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::Range;
 
-use crate::data_source::DataSource;
+use crate::accounting::{Accounting, MappingUsage, MemoryUsage, PageKind};
+use crate::cacher::{self, CacheCoordinator, LruList, Page, ReadAhead};
+use crate::commit::{CommitAccountant, Overcommit};
+use crate::data_source::{self, AnonymousDataSource, DataSource};
+use crate::limits::{self, Limits};
+use crate::memcg::{self, MemoryGroup};
+use crate::numa::{self, MemoryPolicy};
+use crate::physical::{PageData, PhysicalMemory};
+use crate::swap::SwapSpace;
+use crate::tlb::{TlbBatch, TlbInvalidator};
+use crate::userfault::{self, UserFaultEvent, UserFaultKind, UserFaultMode, UserFaultQueue};
 
 type VirtualAddress = usize;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// The gap kept free below every stack, so that it has room to grow and runs into unmapped
+/// memory rather than another mapping if it overflows.
+pub const STACK_GUARD_GAP: usize = 256 * PAGE_SIZE;
+
 struct MapEntry {
     source: Arc<dyn DataSource>,
     offset: usize,
     span: usize,
     addr: usize,
     flags: FlagBuilder,
+    pages: BTreeMap<usize, Page>, // resident pages, keyed by page index within the mapping
+    readahead: ReadAhead,
+    advice: Advice,
+    locked: bool, // pinned resident, see `AddressSpace::lock_range`
+    kind: MapKind,
+    charged: bool, // counted by the `CommitAccountant`
+    swapped: BTreeMap<usize, (Arc<SwapSpace>, usize)>, // where pages written out went, keyed like `pages`
+    policy: MemoryPolicy,                              // which nodes new frames come from
+    userfault: Option<UserFaultMode>, // faults handed to user space, see `register_userfault`
+}
+
+/// What an entry in `AddressSpace::mappings` is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MapKind {
+    /// Mapped by `add_mapping` and friends.
+    Normal,
+    /// Part of the program break heap, see `AddressSpace::set_brk`.
+    Heap,
+    /// Part of a stack that grows down towards `top - max_size`, see `AddressSpace::add_stack`.
+    Stack {
+        top: VirtualAddress,
+        max_size: usize,
+    },
+}
+
+impl MapKind {
+    /// Do mappings of this kind with `flags` count towards `Limits::data`?
+    fn counts_as_data(self, flags: FlagBuilder) -> bool {
+        match self {
+            MapKind::Heap => true,
+            MapKind::Normal => flags.private && flags.write,
+            MapKind::Stack { .. } => false,
+        }
+    }
 }
 
 impl MapEntry {
     #[must_use]
-    pub fn new(source: Arc<dyn DataSource>, offset: usize, span: usize, addr: usize, flags: FlagBuilder) -> MapEntry {
-      MapEntry {
-        source: source.clone(),
-        offset,
-        span,
-        addr,
-        flags,
-      }
+    pub fn new(
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        addr: usize,
+        flags: FlagBuilder,
+    ) -> MapEntry {
+        MapEntry {
+            source: source.clone(),
+            offset,
+            span,
+            addr,
+            flags,
+            pages: BTreeMap::new(),
+            readahead: ReadAhead::new(),
+            advice: Advice::Normal,
+            locked: false,
+            kind: MapKind::Normal,
+            charged: false,
+            swapped: BTreeMap::new(),
+            policy: MemoryPolicy::Local,
+            userfault: None,
+        }
+    }
+
+    /// Number of pages covered by this mapping.
+    fn page_count(&self) -> usize {
+        self.span / PAGE_SIZE
+    }
+
+    /// Does this mapping cover any part of `[start, end)`?
+    fn overlaps(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        self.addr < end && start < self.addr + self.span
+    }
+
+    /// Indices of the pages of this mapping that `[start, end)` touches.
+    fn touched_pages(&self, start: VirtualAddress, end: VirtualAddress) -> Range<usize> {
+        let first = start.saturating_sub(self.addr) / PAGE_SIZE;
+        let last = (end.saturating_sub(self.addr))
+            .div_ceil(PAGE_SIZE)
+            .min(self.page_count());
+        first..last.max(first)
+    }
+
+    /// Which resident counter this mapping's pages go under.
+    fn page_kind(&self) -> PageKind {
+        if self.flags.shared && self.source.is_anonymous() {
+            PageKind::SharedAnon
+        } else if self.flags.shared {
+            PageKind::SharedFile
+        } else if self.source.is_anonymous() {
+            PageKind::Anon
+        } else {
+            PageKind::File
+        }
+    }
+
+    /// Would this mapping be charged for commit with `flags`? Only pages that may need copying
+    /// or zeroing are: those of private writable mappings and of anonymous memory. `noreserve`
+    /// mappings aren't, unless overcommit is `Never`, which ignores it.
+    fn needs_charge(&self, flags: FlagBuilder, policy: Option<Overcommit>) -> bool {
+        (!flags.noreserve || policy == Some(Overcommit::Never))
+            && ((flags.private && flags.write) || self.source.is_anonymous())
+    }
+
+    /// How much room must be left free below this mapping.
+    fn guard_gap(&self) -> usize {
+        match self.kind {
+            MapKind::Stack { .. } => STACK_GUARD_GAP,
+            MapKind::Normal | MapKind::Heap => PAGE_SIZE,
+        }
+    }
+
+    /// Extend this mapping down by `len` bytes.
+    fn grow_down(&mut self, len: usize) {
+        let pages = len / PAGE_SIZE;
+        self.addr -= len;
+        self.span += len;
+        self.offset = self.offset.saturating_sub(len);
+        self.pages = core::mem::take(&mut self.pages)
+            .into_iter()
+            .map(|(i, page)| (i + pages, page))
+            .collect();
+        self.swapped = core::mem::take(&mut self.swapped)
+            .into_iter()
+            .map(|(i, slot)| (i + pages, slot))
+            .collect();
+        self.readahead = ReadAhead::new();
+    }
+
+    /// How many pages starting at `index`, up to `limit`, are neither resident nor swapped out,
+    /// and so can be read from the `DataSource`.
+    fn missing_run(&self, index: usize, limit: usize) -> usize {
+        (index..self.page_count())
+            .take(limit)
+            .take_while(|i| !self.pages.contains_key(i) && !self.swapped.contains_key(i))
+            .count()
+    }
+
+    /// Read page `index` back in from swap if it was swapped out, returning whether it was.
+    fn swap_in(&mut self, index: usize, usage: &mut Accounting) -> Result<bool, &'static str> {
+        let Some((swap, slot)) = self.swapped.get(&index) else {
+            return Ok(false);
+        };
+        let page = swap.read_in(*slot, usage.placement(self.policy))?;
+        swap.release(*slot);
+        self.swapped.remove(&index);
+        usage.swap_released();
+        self.add_pages(index, vec![page], usage);
+        Ok(true)
+    }
+
+    /// Let go of the swap slots of every page of this mapping that's swapped out.
+    fn discard_swapped(&mut self, usage: &mut Accounting) {
+        for (swap, slot) in core::mem::take(&mut self.swapped).into_values() {
+            swap.release(slot);
+            usage.swap_released();
+        }
+    }
+
+    /// Make page `index` of this mapping resident, reading ahead if the access pattern looks
+    /// sequential. A write access marks the page dirty, and gives it a frame of its own if it
+    /// was sharing one.
+    fn fault_in(
+        &mut self,
+        index: usize,
+        write: bool,
+        usage: &mut Accounting,
+    ) -> Result<(), &'static str> {
+        let was_resident = self.pages.contains_key(&index);
+        if !was_resident && !self.swap_in(index, usage)? {
+            // read-ahead mustn't take the memory group over its hard limit
+            let window = self
+                .readahead
+                .on_miss(index, self.advice)
+                .min(usage.room().max(1));
+            let count = self.missing_run(index, window);
+            let placement = usage.placement(self.policy);
+            let pages = cacher::fetch(
+                self.source.as_ref(),
+                self.offset + index * PAGE_SIZE,
+                count,
+                placement,
+            )?;
+            self.add_pages(index, pages, usage);
+            for page in self.pages.range_mut(index + 1..index + count) {
+                page.1.speculative = true;
+            }
+            self.readahead.on_fetch(index, count);
+        }
+        let page = self
+            .pages
+            .get_mut(&index)
+            .expect("Bad things are happening.");
+        if page.speculative {
+            page.speculative = false;
+            self.readahead.on_hit();
+        }
+        if write {
+            page.data.make_exclusive(self.policy, usage.node())?;
+        }
+        page.dirty |= write;
+        page.referenced |= was_resident;
+        Ok(())
+    }
+
+    /// Write page `index` out to `swap` and drop it, returning whether it could be.
+    fn swap_page_out(
+        &mut self,
+        index: usize,
+        swap: &Arc<SwapSpace>,
+        batch: &mut TlbBatch,
+        usage: &mut Accounting,
+    ) -> bool {
+        let Some(slot) = swap.allocate() else {
+            return false;
+        };
+        if swap.write_out(slot, &self.pages[&index]).is_err() {
+            swap.release(slot);
+            return false;
+        }
+        self.swapped.insert(index, (swap.clone(), slot));
+        usage.swapped_out();
+        self.drop_pages(batch, usage, |i, _| i == index);
+        true
+    }
+
+    /// Drop the resident pages that `f` picks out by index, handing them to `batch` until their
+    /// translations have been invalidated. Returns how many were dropped.
+    fn drop_pages<F>(&mut self, batch: &mut TlbBatch, usage: &mut Accounting, f: F) -> usize
+    where
+        F: FnMut(usize, &Page) -> bool,
+    {
+        let kind = self.page_kind();
+        drop_pages(&mut self.pages, self.addr, kind, batch, usage, f)
+    }
+
+    /// Make page `index` resident with `bytes` in it, for a user fault handler.
+    fn install(
+        &mut self,
+        index: usize,
+        bytes: &[u8],
+        dirty: bool,
+        usage: &mut Accounting,
+    ) -> Result<(), &'static str> {
+        let page = Page {
+            data: PageData::new(usage.placement(self.policy), bytes)?,
+            dirty,
+            speculative: false,
+            referenced: false,
+            write_protected: false,
+        };
+        self.add_pages(index, vec![page], usage);
+        Ok(())
+    }
+
+    /// Make `pages` resident, starting at page `index`.
+    fn add_pages(&mut self, index: usize, pages: Vec<Page>, usage: &mut Accounting) {
+        let kind = self.page_kind();
+        for (i, page) in (index..).zip(pages) {
+            usage.page_in(kind, self.addr + i * PAGE_SIZE);
+            self.pages.insert(i, page);
+        }
+    }
+
+    /// Read in every page of this mapping that isn't resident, with one `DataSource` read per
+    /// run of missing pages and pages that were swapped out read back from swap. Fails once the
+    /// memory group has no more room.
+    fn prefetch(&mut self, usage: &mut Accounting) -> Result<(), &'static str> {
+        let mut index = 0;
+        while index < self.page_count() {
+            if usage.room() == 0 && !self.pages.contains_key(&index) {
+                return Err(memcg::GROUP_LIMIT);
+            }
+            if self.swap_in(index, usage)? {
+                index += 1;
+                continue;
+            }
+            let count = self.missing_run(index, usage.room());
+            if count > 0 {
+                let placement = usage.placement(self.policy);
+                let pages = cacher::fetch(
+                    self.source.as_ref(),
+                    self.offset + index * PAGE_SIZE,
+                    count,
+                    placement,
+                )?;
+                self.add_pages(index, pages, usage);
+            }
+            index += count.max(1);
+        }
+        Ok(())
+    }
+
+    /// Split this mapping at page `index`, keeping the pages before it and returning a new
+    /// mapping for the rest.
+    fn split_off(&mut self, index: usize) -> MapEntry {
+        let mut tail = MapEntry::new(
+            self.source.clone(),
+            self.offset + index * PAGE_SIZE,
+            self.span - index * PAGE_SIZE,
+            self.addr + index * PAGE_SIZE,
+            self.flags,
+        );
+        tail.advice = self.advice;
+        tail.policy = self.policy;
+        tail.userfault = self.userfault;
+        tail.locked = self.locked;
+        tail.kind = self.kind;
+        tail.charged = self.charged;
+        tail.swapped = self
+            .swapped
+            .split_off(&index)
+            .into_iter()
+            .map(|(i, slot)| (i - index, slot))
+            .collect();
+        tail.pages = self
+            .pages
+            .split_off(&index)
+            .into_iter()
+            .map(|(i, page)| (i - index, page))
+            .collect();
+        self.span = index * PAGE_SIZE;
+        tail
     }
 }
 
@@ -32,6 +361,18 @@ impl MapEntry {
 pub struct AddressSpace {
     name: String,
     mappings: LinkedList<MapEntry>, // see below for comments
+    locked_pages: usize,
+    lock_future: bool, // lock new mappings as they're added, like MCL_FUTURE
+    brk_start: VirtualAddress, // initial program break; 0 until `init_brk`
+    brk: VirtualAddress, // current program break
+    asid: usize,
+    invalidator: Option<Arc<dyn TlbInvalidator>>,
+    tlb: TlbBatch, // translations to invalidate, and pages to free once they are
+    usage: Accounting,
+    limits: Limits,
+    commit: Option<Arc<CommitAccountant>>,
+    swap: Option<Arc<SwapSpace>>,
+    userfaults: UserFaultQueue,
 }
 
 // comments about storing mappings
@@ -51,137 +392,256 @@ impl AddressSpace {
         Self {
             name: name.to_string(),
             mappings: LinkedList::new(),
+            locked_pages: 0,
+            lock_future: false,
+            brk_start: 0,
+            brk: 0,
+            asid: 0,
+            invalidator: None,
+            tlb: TlbBatch::default(),
+            usage: Accounting::new(),
+            limits: Limits::default(),
+            commit: None,
+            swap: None,
+            userfaults: UserFaultQueue::default(),
         }
     }
 
     fn round_up(addr: VirtualAddress) -> VirtualAddress {
         let floor = addr / PAGE_SIZE;
         if floor * PAGE_SIZE == addr {
-          addr
+            addr
         } else {
-          (floor + 1) * PAGE_SIZE
+            (floor + 1) * PAGE_SIZE
         }
     }
 
     /// Add a mapping from a `DataSource` into this `AddressSpace`.
     ///
     /// # Errors
-    /// If the desired mapping is invalid.
-    pub fn add_mapping<D: DataSource + 'static>(
+    /// If the desired mapping is invalid, including if `source` doesn't allow `flags` or, unless
+    /// it's resizable, doesn't reach the end of the mapping.
+    pub fn add_mapping(
         &mut self,
-        source: Arc<D>,
+        source: Arc<dyn DataSource>,
         offset: usize,
         span: usize,
         flags: FlagBuilder,
     ) -> Result<VirtualAddress, &str> {
         let span = Self::round_up(span);
+        Self::check_source(source.as_ref(), offset, span, flags)?;
+        self.check_growth(span / PAGE_SIZE, flags, MapKind::Normal, 1)?;
+        let charged = self.charge(span / PAGE_SIZE, flags, source.is_anonymous())?;
         let mut curs = self.mappings.cursor_front_mut();
         let empty: bool = curs.current().is_none(); // curs starts pointing at first entry, only None if LL is empty
         while curs.current().is_some() {
-          let this_ending: usize = {
-            let entry = curs.current().expect("Bad things are happening.");
-            entry.addr + entry.span
-          };
-          let next_address = if curs.peek_next().is_some() {
-            curs.peek_next().expect("Bad things are happening.").addr
-          } else {
-            usize::MAX
-          };
-          //println!("{}",this_ending);
-          //println!("{}",next_address);
-          if next_address - this_ending >= span + 2 * PAGE_SIZE {
-            break;
-          }
-          curs.move_next();
+            let this_ending: usize = {
+                let entry = curs.current().expect("Bad things are happening.");
+                entry.addr + entry.span
+            };
+            let next_address = if curs.peek_next().is_some() {
+                curs.peek_next().expect("Bad things are happening.").addr
+            } else {
+                usize::MAX
+            };
+            //println!("{}",this_ending);
+            //println!("{}",next_address);
+            let next_gap = curs
+                .peek_next()
+                .map_or(PAGE_SIZE, |entry| entry.guard_gap());
+            if next_address - this_ending >= span + PAGE_SIZE + next_gap {
+                break;
+            }
+            curs.move_next();
         }
         let next_addr: usize = if curs.peek_next().is_some() {
-          let entry = curs.peek_next().expect("Bad things are happening.");
-          entry.addr
+            let entry = curs.peek_next().expect("Bad things are happening.");
+            entry.addr
         } else {
-          usize::MAX // What is the size of the address space? Max usize? 
+            usize::MAX // What is the size of the address space? Max usize?
         };
         let this_ending: usize = if curs.current().is_some() {
-          let entry = curs.current().expect("Bad things are happening.");
-          entry.addr + entry.span
+            let entry = curs.current().expect("Bad things are happening.");
+            entry.addr + entry.span
         } else {
-          0
+            0
         };
-        if next_addr - this_ending >= span + 2 * PAGE_SIZE || empty {
-          let address = MapEntry::new(
-            source,
-            offset,
-            span,
-            this_ending + PAGE_SIZE,
-            flags
-          );
-          curs.insert_after(address);
-          Ok(this_ending + PAGE_SIZE)
+        let next_gap = curs
+            .peek_next()
+            .map_or(PAGE_SIZE, |entry| entry.guard_gap());
+        if next_addr - this_ending >= span + PAGE_SIZE + next_gap || empty {
+            let mut address = MapEntry::new(source, offset, span, this_ending + PAGE_SIZE, flags);
+            address.charged = charged;
+            curs.insert_after(address);
+            self.usage.map(span / PAGE_SIZE);
+            self.lock_if_future(this_ending + PAGE_SIZE, span)?;
+            Ok(this_ending + PAGE_SIZE)
         } else {
-          println!("{}",this_ending);
-          println!("{}",next_addr);
-          Err("No memory chunk available.")
+            self.uncharge(span / PAGE_SIZE, charged);
+            Err("No memory chunk available.")
         }
     }
 
     /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address.
+    /// Like `add_mapping`, it keeps a free page between it and the mappings either side.
     ///
     /// # Errors
-    /// If there is insufficient room subsequent to `start`.
-    pub fn add_mapping_at<D: DataSource + 'static>(
+    /// If there is insufficient room subsequent to `start`, the mapping would come within a page
+    /// of another one or into a stack's guard gap, or `source` can't back it, as for
+    /// `add_mapping`.
+    pub fn add_mapping_at(
         &mut self,
-        source: Arc<D>,
+        source: Arc<dyn DataSource>,
         offset: usize,
         span: usize,
         start: VirtualAddress,
-        flags: FlagBuilder
+        flags: FlagBuilder,
     ) -> Result<(), &str> {
+        self.map_at(source, offset, span, start, flags, true)
+    }
+
+    /// Map `source` at `start`. With `spaced`, a free page is kept either side, as
+    /// `add_mapping_at` does; without, the mapping may sit right against its neighbours, as
+    /// `replace_mapping_at` needs. Stack guard gaps are kept free either way.
+    fn map_at(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        flags: FlagBuilder,
+        spaced: bool,
+    ) -> Result<(), &'static str> {
         let span = Self::round_up(span);
+        Self::check_source(source.as_ref(), offset, span, flags)?;
+        self.check_growth(span / PAGE_SIZE, flags, MapKind::Normal, 1)?;
+        let charged = self.charge(span / PAGE_SIZE, flags, source.is_anonymous())?;
         let mut curs = self.mappings.cursor_front_mut();
         let empty: bool = curs.current().is_none();
         while curs.current().is_some() {
-          let last_addr = {
-            let entry = curs.current().expect("Bad things are happening.");
-            entry.addr
-          };
-          let next_addr = if curs.peek_next().is_some() {
-            curs.peek_next().expect("Bad things are happening.").addr
-          } else {
-            usize::MAX
-          };
-          if next_addr > start && last_addr < start {
-            break;
-          }
-          curs.move_next();
+            let last_addr = {
+                let entry = curs.current().expect("Bad things are happening.");
+                entry.addr
+            };
+            let next_addr = if curs.peek_next().is_some() {
+                curs.peek_next().expect("Bad things are happening.").addr
+            } else {
+                usize::MAX
+            };
+            if next_addr > start && last_addr < start {
+                break;
+            }
+            curs.move_next();
         }
         let next_start = match curs.peek_next() {
-          Some(x) => x.addr,
-          None => usize::MAX,
+            Some(x) => x.addr,
+            None => usize::MAX,
         };
         let prev_end = match curs.current() {
-          Some(x) => x.addr + x.span,
-          None => 0,
+            Some(x) => x.addr + x.span,
+            None => 0,
         };
-        if prev_end >= start - PAGE_SIZE || (next_start < start + span + PAGE_SIZE && !empty) {
-          println!("{}",prev_end);
-          println!("{}",next_start);
-          Err("Insufficient free memory in desired region.") 
+        let (too_close, next_gap) = match spaced {
+            true => (
+                prev_end >= start.saturating_sub(PAGE_SIZE),
+                curs.peek_next()
+                    .map_or(PAGE_SIZE, |entry| entry.guard_gap()),
+            ),
+            false => (
+                prev_end > start,
+                curs.peek_next().map_or(0, |entry| match entry.kind {
+                    MapKind::Stack { .. } => STACK_GUARD_GAP,
+                    MapKind::Normal | MapKind::Heap => 0,
+                }),
+            ),
+        };
+        if too_close || (next_start < start + span + next_gap && !empty) {
+            self.uncharge(span / PAGE_SIZE, charged);
+            Err("Insufficient free memory in desired region.")
         } else {
-          let new_map = MapEntry::new(
-            source,
-            offset,
-            span,
-            start,
-            flags
-          );
-          curs.insert_after(new_map);
-          Ok(())
+            let mut new_map = MapEntry::new(source, offset, span, start, flags);
+            new_map.charged = charged;
+            curs.insert_after(new_map);
+            self.usage.map(span / PAGE_SIZE);
+            self.lock_if_future(start, span)?;
+            Ok(())
+        }
+    }
+
+    /// Map `source` at `start` like `add_mapping_at`, unmapping whatever was there first, like
+    /// `MAP_FIXED`. Unlike `add_mapping_at`, the new mapping may sit right against the ones
+    /// either side. If it can't be made, the old ones are left as they were.
+    ///
+    /// # Errors
+    /// If the range wraps around the address space, or the new mapping can't be made, as for
+    /// `add_mapping_at`.
+    pub fn replace_mapping_at(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        flags: FlagBuilder,
+    ) -> Result<(), &str> {
+        let span = Self::round_up(span);
+        let end = start
+            .checked_add(span)
+            .ok_or("Range wraps around the address space.")?;
+        Self::check_source(source.as_ref(), offset, span, flags)?;
+        let splits = self.mappings.iter().any(|entry| {
+            let touched = entry.touched_pages(start, end);
+            entry.overlaps(start, end) && touched.start > 0 && touched.end < entry.page_count()
+        });
+        if self.mappings.len() + usize::from(splits) > self.limits.max_map_count {
+            return Err(limits::MAP_COUNT_LIMIT);
+        }
+        // the old mappings are set aside rather than released, so that they can be put back
+        self.split_range(start, end);
+        let mut replaced = Vec::new();
+        let mut curs = self.mappings.cursor_front_mut();
+        while let Some(entry) = curs.current() {
+            if entry.overlaps(start, end) {
+                replaced.extend(curs.remove_current());
+            } else {
+                curs.move_next();
+            }
+        }
+        for entry in &replaced {
+            self.set_aside(entry);
+        }
+        match self.map_at(source, offset, span, start, flags, false) {
+            Ok(()) => {
+                for entry in replaced {
+                    self.release_pages(entry);
+                }
+                self.finish_tlb();
+                Ok(())
+            }
+            Err(e) => {
+                for entry in replaced {
+                    self.restore(entry);
+                }
+                Err(e)
+            }
         }
     }
 
+    /// Add a zero-filled anonymous mapping into this `AddressSpace`.
+    ///
+    /// # Errors
+    /// If the desired mapping is invalid.
+    pub fn add_anonymous_mapping(
+        &mut self,
+        span: usize,
+        flags: FlagBuilder,
+    ) -> Result<VirtualAddress, &str> {
+        self.add_mapping(Arc::new(AnonymousDataSource), 0, span, flags)
+    }
+
     /// Remove the mapping to `DataSource` that starts at the given address.
     ///
     /// # Errors
-    /// If the mapping could not be removed.
+    /// If the mapping could not be removed, or is of a different `DataSource`.
     pub fn remove_mapping<D: DataSource>(
         &mut self,
         source: Arc<D>,
@@ -189,91 +649,1871 @@ impl AddressSpace {
     ) -> Result<(), &str> {
         let mut curs = self.mappings.cursor_front_mut();
         while curs.current().is_some() {
-          let this_mapping = curs.current().expect("Bad things are happening.");
-          if this_mapping.addr == start {
-            break;
-          }
-          curs.move_next();
+            let this_mapping = curs.current().expect("Bad things are happening.");
+            if this_mapping.addr == start {
+                break;
+            }
+            curs.move_next();
         }
         let mapping = curs.current();
-        if mapping.is_none() ||  mapping.unwrap().addr != start {
-          Err("No mapping with target address.")
+        if mapping.is_none() || mapping.unwrap().addr != start {
+            Err("No mapping with target address.")
+        } else if curs
+            .current()
+            .is_some_and(|entry| entry.source.id() != source.id())
+        {
+            Err("The mapping at target address is of another data source.")
+        } else {
+            let removed = curs.remove_current().expect("Bad things are happening.");
+            self.release(removed);
+            self.finish_tlb();
+            Ok(()) //Do we have to drop a reference??
+        }
+    }
+
+    /// Remove every page touched by `[start, start + len)` from this `AddressSpace`, like
+    /// `munmap`, splitting mappings that straddle either end of the range. Parts of the range
+    /// that aren't mapped are skipped.
+    ///
+    /// # Errors
+    /// If the range wraps around the address space.
+    pub fn remove_range(&mut self, start: VirtualAddress, len: usize) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        // punching a hole in the middle of a mapping leaves one more mapping than before
+        let splits = self.mappings.iter().any(|entry| {
+            let touched = entry.touched_pages(start, end);
+            entry.overlaps(start, end) && touched.start > 0 && touched.end < entry.page_count()
+        });
+        if self.mappings.len() + usize::from(splits) > self.limits.max_map_count {
+            return Err(limits::MAP_COUNT_LIMIT);
+        }
+        self.unmap_pages(start, end);
+        Ok(())
+    }
+
+    fn unmap_pages(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        if start >= end {
+            return;
+        }
+        self.split_range(start, end);
+        let mut removed = Vec::new();
+        let mut curs = self.mappings.cursor_front_mut();
+        while let Some(entry) = curs.current() {
+            if entry.overlaps(start, end) {
+                removed.extend(curs.remove_current());
+            } else {
+                curs.move_next();
+            }
+        }
+        for entry in removed {
+            self.release(entry);
+        }
+        self.finish_tlb();
+    }
+
+    /// Set the read, write and execute permissions of every page touched by
+    /// `[start, start + len)` to those in `perms`, like `mprotect`. Other flags are left alone.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped, or if the resulting flags wouldn't be valid.
+    pub fn protect(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        perms: FlagBuilder,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        if self
+            .mappings
+            .iter()
+            .any(|entry| entry.overlaps(start, end) && !entry.flags.with_perms(perms).is_valid())
+        {
+            return Err("Invalid flags for mapping.");
+        }
+        for entry in self
+            .mappings
+            .iter()
+            .filter(|entry| entry.overlaps(start, end))
+        {
+            Self::check_source_flags(entry.source.as_ref(), entry.flags.with_perms(perms))?;
+        }
+        let newly_data: usize = self
+            .mappings
+            .iter()
+            .filter(|entry| {
+                entry.overlaps(start, end)
+                    && !entry.kind.counts_as_data(entry.flags)
+                    && entry.kind.counts_as_data(entry.flags.with_perms(perms))
+            })
+            .map(|entry| entry.touched_pages(start, end).len())
+            .sum();
+        if newly_data > 0
+            && (self.data_pages() + newly_data).saturating_mul(PAGE_SIZE) > self.limits.data
+        {
+            return Err(limits::DATA_LIMIT);
+        }
+        let policy = self.commit.as_ref().map(|commit| commit.policy());
+        let to_charge: usize = self
+            .mappings
+            .iter()
+            .filter(|entry| {
+                entry.overlaps(start, end)
+                    && !entry.charged
+                    && entry.needs_charge(entry.flags.with_perms(perms), policy)
+            })
+            .map(|entry| entry.touched_pages(start, end).len())
+            .sum();
+        let charged = if to_charge > 0 && self.is_range_mapped(start, end) {
+            self.commit
+                .as_ref()
+                .map_or(Ok(false), |commit| commit.charge(to_charge, false))?
         } else {
-          curs.remove_current();
-          Ok(()) //Do we have to drop a reference??
+            false
+        };
+        let mut weakened = Vec::new();
+        let result = self.for_each_mapping_in(start, end, |mapping, _| {
+            let old = mapping.flags;
+            if charged && !mapping.charged && mapping.needs_charge(old.with_perms(perms), policy) {
+                mapping.charged = true;
+            }
+            if ((old.read && !perms.read)
+                || (old.write && !perms.write)
+                || (old.execute && !perms.execute))
+                && !mapping.pages.is_empty()
+            {
+                weakened.push(mapping.addr..mapping.addr + mapping.span);
+            }
+            mapping.flags = old.with_perms(perms);
+            Ok(())
+        });
+        if result.is_err() {
+            // nothing was changed
+            self.uncharge(to_charge, charged);
+        }
+        for range in weakened {
+            self.tlb.add_range(range);
         }
+        self.finish_tlb();
+        result
+    }
+
+    /// Is every byte of `[start, start + len)` mapped?
+    #[must_use]
+    pub fn is_mapped(&self, start: VirtualAddress, len: usize) -> bool {
+        start
+            .checked_add(len)
+            .is_some_and(|end| self.is_range_mapped(start, end))
     }
 
     /// Look up the DataSource and offset within that DataSource for a
     /// VirtualAddress / AccessType in this AddressSpace
-    /// 
+    ///
     /// # Errors
     /// If this VirtualAddress does not have a valid mapping in &self,
-    /// or if this AccessType is not permitted by the mapping
+    /// if the source there isn't a `D`, if this AccessType is not permitted
+    /// by the mapping, or, with `data_source::NOT_PERMITTED`, if the source's
+    /// `Capabilities` don't allow it
     pub fn get_source_for_addr<D: DataSource>(
         &self,
         addr: VirtualAddress,
-        access_type: FlagBuilder
+        access_type: FlagBuilder,
     ) -> Result<(Arc<dyn DataSource>, usize), &str> {
-        let mapping = self.get_mapping_for_addr(addr).expect("No mapping with target address.");
+        let mapping = self.get_mapping_for_addr(addr)?;
+        let source: &dyn Any = mapping.source.as_ref();
+        if !source.is::<D>() {
+            return Err("The data source at target address isn't of the type asked for.");
+        }
         let but_not_flags = access_type.but_not(mapping.flags);
-        let any_disallowed = but_not_flags.read || but_not_flags.write || but_not_flags.execute || but_not_flags.cow || but_not_flags.private || but_not_flags.shared;
-        match any_disallowed {
-          true => Err("Given access type is not allowed for the data source at target address."),
-          false => Ok((mapping.source.clone(), mapping.offset)),
+        let any_disallowed = but_not_flags.read
+            || but_not_flags.write
+            || but_not_flags.execute
+            || but_not_flags.cow
+            || but_not_flags.private
+            || but_not_flags.shared;
+        if any_disallowed {
+            return Err("Given access type is not allowed for the data source at target address.");
         }
+        Self::check_source_flags(
+            mapping.source.as_ref(),
+            mapping.flags.with_perms(access_type),
+        )?;
+        Ok((mapping.source.clone(), mapping.offset))
     }
 
-    /// Helper function for looking up mappings
-    fn get_mapping_for_addr(&self, addr: VirtualAddress) -> Result<&MapEntry, &str> {
-        let mut curs = self.mappings.cursor_front();
-        while curs.current().is_some() && curs.current().expect("Bad things are happening.").addr < addr {
-          curs.move_next();
+    /// Resolve a page fault at `addr` for the given kind of access.
+    ///
+    /// If the page isn't resident it is fetched from the mapping's `DataSource`, together with
+    /// as many following pages as the mapping's read-ahead window allows.
+    ///
+    /// # Errors
+    /// If this VirtualAddress does not have a valid mapping in &self, if this AccessType is not
+    /// permitted by the mapping, if the `DataSource` read fails, or, with `memcg::GROUP_OOM`, if
+    /// reclaiming this address space can't make room under the memory group's hard limit.
+    pub fn fault(&mut self, addr: VirtualAddress, access_type: FlagBuilder) -> Result<(), &str> {
+        self.page_for_access(addr, access_type)?;
+        Ok(())
+    }
+
+    /// Copy bytes out of this `AddressSpace` starting at `addr` to fill `buffer`, faulting pages
+    /// in as needed, like `copy_from_user`.
+    ///
+    /// # Errors
+    /// If some byte can't be read, along with how many bytes were copied before it.
+    pub fn read_bytes(
+        &mut self,
+        addr: VirtualAddress,
+        buffer: &mut [u8],
+    ) -> Result<(), (usize, &str)> {
+        let mut copied = 0;
+        while copied < buffer.len() {
+            let at = addr
+                .checked_add(copied)
+                .ok_or((copied, "No mapping with target address."))?;
+            let (page, within) = self
+                .page_for_access(at, FlagBuilder::read())
+                .map_err(|e| (copied, e))?;
+            let count = (PAGE_SIZE - within).min(buffer.len() - copied);
+            page.data.read(within, &mut buffer[copied..copied + count]);
+            copied += count;
         }
-        match curs.current() {
-          Some(addr) => Ok(curs.current().expect("Bad things are happening.")),
-          _ => Err("No mapping with target address."),
+        Ok(())
+    }
+
+    /// Copy `buffer` into this `AddressSpace` starting at `addr`, faulting pages in as needed,
+    /// like `copy_to_user`.
+    ///
+    /// # Errors
+    /// If some byte can't be written, along with how many bytes were copied before it.
+    pub fn write_bytes(
+        &mut self,
+        addr: VirtualAddress,
+        buffer: &[u8],
+    ) -> Result<(), (usize, &str)> {
+        let mut copied = 0;
+        while copied < buffer.len() {
+            let at = addr
+                .checked_add(copied)
+                .ok_or((copied, "No mapping with target address."))?;
+            let (page, within) = self
+                .page_for_access(at, FlagBuilder::write())
+                .map_err(|e| (copied, e))?;
+            let count = (PAGE_SIZE - within).min(buffer.len() - copied);
+            page.data.write(within, &buffer[copied..copied + count]);
+            copied += count;
         }
+        Ok(())
     }
-}
 
-/// Build flags for address space maps.
-///
-/// We recommend using this builder type as follows:
-/// ```
-/// # use reedos_address_space::FlagBuilder;
-/// let flags = FlagBuilder::new()
-///     .toggle_read()
-///     .toggle_write();
-/// ```
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-#[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
-                                         // here because these directly correspond to yes/no
-                                         // hardware flags
-pub struct FlagBuilder {
-    // TODO: should there be some sanity checks that conflicting flags are never toggled? can we do
-    // this at compile-time? (the second question is maybe hard)
-    read: bool,
-    write: bool,
-    execute: bool,
-    cow: bool,
-    private: bool,
-    shared: bool,
-}
+    /// Fault in the page holding `addr` for `access_type`, returning it along with the offset of
+    /// `addr` within it.
+    fn page_for_access(
+        &mut self,
+        addr: VirtualAddress,
+        access_type: FlagBuilder,
+    ) -> Result<(&mut Page, usize), &'static str> {
+        if self.get_mapping_for_addr(addr).is_err() {
+            self.grow_stack(addr)?;
+        }
+        self.check_userfault(addr, access_type)?;
+        if !self.is_resident(addr) {
+            self.make_room(1)?;
+        }
+        let (mapping, usage) = self.get_mapping_for_addr_mut(addr)?;
+        if !mapping.flags.check_access_perms(access_type) {
+            return Err("Given access type is not allowed for the data source at target address.");
+        }
+        let index = (addr - mapping.addr) / PAGE_SIZE;
+        mapping.fault_in(index, access_type.write, usage)?;
+        let page = mapping
+            .pages
+            .get_mut(&index)
+            .expect("Bad things are happening.");
+        Ok((page, (addr - mapping.addr) % PAGE_SIZE))
+    }
 
-impl FlagBuilder {
-    pub fn check_access_perms(&self, access_perms: FlagBuilder) -> bool {
-        if access_perms.read && !self.read || access_perms.write && !self.write || access_perms.execute && !self.execute {
-            return false;
-        }    
-        true    
+    /// Zero `[start, start + len)` regardless of the mappings' permissions, for the kernel to
+    /// set up memory before handing it to user space.
+    pub(crate) fn zero_fill(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+    ) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < len {
+            let at = start + done;
+            if !self.is_resident(at) {
+                self.make_room(1)?;
+            }
+            let (mapping, usage) = self.get_mapping_for_addr_mut(at)?;
+            let index = (at - mapping.addr) / PAGE_SIZE;
+            let within = (at - mapping.addr) % PAGE_SIZE;
+            mapping.fault_in(index, true, usage)?;
+            let page = mapping
+                .pages
+                .get_mut(&index)
+                .expect("Bad things are happening.");
+            let count = (PAGE_SIZE - within).min(len - done);
+            page.data.fill(within, count, 0);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Give the `AddressSpace` a hint about how `[start, start + len)` will be used, like
+    /// `madvise`.
+    ///
+    /// The hint is recorded on every page the range touches, splitting mappings where the range
+    /// begins or ends inside one. `WillNeed` also reads the pages in, `DontNeed` drops them (so
+    /// anonymous memory reads back as zeros), and `Free` lets `reclaim_lazy_free` discard them
+    /// while they stay clean.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped, if `Free` is given for memory that isn't anonymous,
+    /// or if prefetching from a `DataSource` fails.
+    pub fn advise(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        advice: Advice,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        if advice == Advice::Free
+            && self
+                .mappings
+                .iter()
+                .any(|entry| entry.overlaps(start, end) && !entry.source.is_anonymous())
+        {
+            return Err("Only anonymous memory can be lazily freed.");
+        }
+        if matches!(advice, Advice::DontNeed | Advice::Free)
+            && self
+                .mappings
+                .iter()
+                .any(|entry| entry.overlaps(start, end) && entry.locked)
+        {
+            return Err("Locked pages can't be dropped.");
+        }
+        let mut dropped = TlbBatch::default();
+        let result = self.for_each_mapping_in(start, end, |mapping, usage| {
+            match advice {
+                Advice::WillNeed => mapping.prefetch(usage)?,
+                Advice::DontNeed => {
+                    mapping.drop_pages(&mut dropped, usage, |_, _| true);
+                    mapping.discard_swapped(usage);
+                }
+                Advice::Free => {
+                    // the dirty bits cached with the translations need clearing too
+                    if !mapping.pages.is_empty() {
+                        dropped.add_range(mapping.addr..mapping.addr + mapping.span);
+                    }
+                    for page in mapping.pages.values_mut() {
+                        page.dirty = false;
+                    }
+                }
+                Advice::Normal | Advice::Sequential | Advice::Random => {}
+            }
+            mapping.advice = advice;
+            Ok(())
+        });
+        self.tlb.append(dropped);
+        self.finish_tlb();
+        result
+    }
+
+    /// Discard the clean pages of anonymous memory advised with `Advice::Free`, returning how
+    /// many pages were reclaimed. Pages written to since the advice are kept.
+    pub fn reclaim_lazy_free(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for mapping in self
+            .mappings
+            .iter_mut()
+            .filter(|entry| entry.advice == Advice::Free && !entry.locked)
+        {
+            reclaimed += mapping.drop_pages(&mut self.tlb, &mut self.usage, |_, page| !page.dirty);
+        }
+        self.finish_tlb();
+        reclaimed
+    }
+
+    /// Is the page containing `addr` currently resident?
+    #[must_use]
+    pub fn is_resident(&self, addr: VirtualAddress) -> bool {
+        self.get_mapping_for_addr(addr).is_ok_and(|mapping| {
+            mapping
+                .pages
+                .contains_key(&((addr - mapping.addr) / PAGE_SIZE))
+        })
+    }
+
+    /// Walk the translation for `addr`, returning the physical address it's kept at, if its page
+    /// is resident in a frame of `PhysicalMemory`.
+    #[must_use]
+    pub fn translate(&self, addr: VirtualAddress) -> Option<usize> {
+        let mapping = self.get_mapping_for_addr(addr).ok()?;
+        let frame = mapping
+            .pages
+            .get(&((addr - mapping.addr) / PAGE_SIZE))?
+            .data
+            .frame()?;
+        Some(frame.addr() + (addr - mapping.addr) % PAGE_SIZE)
+    }
+
+    /// Drop up to `max` clean pages that can be read back from their `DataSource`, returning how
+    /// many were evicted. Pages in locked ranges are never evicted.
+    pub fn evict_clean_pages(&mut self, max: usize) -> usize {
+        let mut evicted = 0;
+        for mapping in self.mappings.iter_mut().filter(|entry| !entry.locked) {
+            mapping.drop_pages(&mut self.tlb, &mut self.usage, |_, page| {
+                if evicted < max && !page.dirty {
+                    evicted += 1;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        self.finish_tlb();
+        evicted
+    }
+
+    /// Take frames for resident pages from `frames`, which may be shared with other
+    /// `AddressSpace`s and decides when they're reclaimed.
+    pub fn set_cache_coordinator(&mut self, frames: Arc<CacheCoordinator>) {
+        self.usage.set_coordinator(frames);
+    }
+
+    /// Charge resident and swapped out pages to `group` from now on, moving what's charged
+    /// already out of any group this `AddressSpace` was in before. Joining may leave the group
+    /// over its hard limit until it's reclaimed from.
+    pub fn join_memory_group(&mut self, group: Arc<MemoryGroup>) {
+        self.usage.set_group(group);
+    }
+
+    #[must_use]
+    pub fn memory_group(&self) -> Option<&Arc<MemoryGroup>> {
+        self.usage.group()
+    }
+
+    /// Keep pages in frames of `memory`, which may be shared with other `AddressSpace`s, as
+    /// they're faulted in from now on. Pages that are already resident stay where they are.
+    pub fn set_physical_memory(&mut self, memory: Arc<PhysicalMemory>) {
+        self.usage.set_memory(memory);
+    }
+
+    /// Note that this `AddressSpace` is now running on a CPU of `node`, which `MemoryPolicy::Local`
+    /// and `MemoryPolicy::Bind` allocate from first.
+    ///
+    /// # Errors
+    /// `numa::NO_SUCH_NODE` if the physical memory has no such node. Without physical memory
+    /// there's only node 0.
+    pub fn set_current_node(&mut self, node: usize) -> Result<(), &str> {
+        if node >= self.usage.memory().map_or(1, |memory| memory.node_count()) {
+            return Err(numa::NO_SUCH_NODE);
+        }
+        self.usage.set_node(node);
+        Ok(())
+    }
+
+    /// Have frames for pages of `[start, start + len)` faulted in from now on come from the nodes
+    /// `policy` picks, like `mbind`. The policy is recorded on every page the range touches,
+    /// splitting mappings where the range begins or ends inside one. Pages already resident
+    /// aren't moved.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped, or the policy names no nodes, or a node the physical
+    /// memory doesn't have. Without physical memory there's only node 0.
+    pub fn set_memory_policy(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        policy.check(self.usage.memory().map_or(1, |memory| memory.node_count()))?;
+        self.for_each_mapping_in(start, end, |mapping, _| {
+            mapping.policy = policy;
+            Ok(())
+        })
+    }
+
+    /// Hand the faults `mode` picks out in `[start, start + len)` to a user fault handler, like
+    /// registering the range with `userfaultfd`. Mappings are split where the range begins or
+    /// ends inside one. Only faults from accesses are handed over; the kernel's own prefetching
+    /// and locking read pages in as usual.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped.
+    pub fn register_userfault(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        mode: UserFaultMode,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        self.for_each_mapping_in(start, end, |mapping, _| {
+            mapping.userfault = Some(mode);
+            Ok(())
+        })
+    }
+
+    /// Stop handing faults in `[start, start + len)` to the handler, waking any that were
+    /// waiting.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped.
+    pub fn unregister_userfault(&mut self, start: VirtualAddress, len: usize) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        self.userfaults.wake(start..end);
+        self.for_each_mapping_in(start, end, |mapping, _| {
+            mapping.userfault = None;
+            for page in mapping.pages.values_mut() {
+                page.write_protected = false;
+            }
+            Ok(())
+        })
+    }
+
+    /// Take the oldest fault waiting on the handler that it hasn't seen yet.
+    pub fn read_userfault(&mut self) -> Option<UserFaultEvent> {
+        self.userfaults.read()
+    }
+
+    /// Is a fault waiting on the handler to resolve the page starting at `page`?
+    #[must_use]
+    pub fn userfault_waiting(&self, page: VirtualAddress) -> bool {
+        self.userfaults.is_waiting(page)
+    }
+
+    /// Fill the missing pages starting at `start` with `data`, a whole number of pages, like
+    /// `UFFDIO_COPY`. The pages are dirty, as nothing else holds their contents. With `wake`,
+    /// faults waiting on them are woken.
+    ///
+    /// # Errors
+    /// If the pages aren't all missing pages of a range registered for missing-page faults, if
+    /// they don't start on a page, or if there's no room for them.
+    pub fn userfault_copy(
+        &mut self,
+        start: VirtualAddress,
+        data: &[u8],
+        wake: bool,
+    ) -> Result<(), &str> {
+        if !data.len().is_multiple_of(PAGE_SIZE) {
+            return Err("Only whole pages can be copied.");
+        }
+        self.resolve_userfault(start, data.len() / PAGE_SIZE, Some(data), wake)
+    }
+
+    /// Fill the missing pages of `[start, start + len)` with zeros, like `UFFDIO_ZEROPAGE`.
+    ///
+    /// # Errors
+    /// As for `userfault_copy`.
+    pub fn userfault_zeropage(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        wake: bool,
+    ) -> Result<(), &str> {
+        self.resolve_userfault(start, len.div_ceil(PAGE_SIZE), None, wake)
+    }
+
+    /// Wake the faults waiting on pages starting in `[start, start + len)` without resolving
+    /// them, like `UFFDIO_WAKE`. A page that's still missing faults to the handler again.
+    pub fn userfault_wake(&mut self, start: VirtualAddress, len: usize) {
+        self.userfaults.wake(start..start.saturating_add(len));
+    }
+
+    /// Write-protect the resident pages of `[start, start + len)`, or with `protect` unset, let
+    /// them be written again and wake the faults waiting on them, like `UFFDIO_WRITEPROTECT`.
+    /// Writes to write-protected pages go to the handler.
+    ///
+    /// # Errors
+    /// If any part of the range isn't registered for write-protect tracking.
+    pub fn userfault_write_protect(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        protect: bool,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        if !self.is_range_mapped(start, end)
+            || self.mappings.iter().any(|entry| {
+                entry.overlaps(start, end)
+                    && !entry.userfault.is_some_and(|mode| mode.write_protect)
+            })
+        {
+            return Err("Range isn't registered for write-protect tracking.");
+        }
+        for mapping in self
+            .mappings
+            .iter_mut()
+            .filter(|entry| entry.overlaps(start, end))
+        {
+            let touched = mapping.touched_pages(start, end);
+            let mut protected = false;
+            for page in mapping.pages.range_mut(touched).map(|(_, page)| page) {
+                protected |= protect && !page.write_protected;
+                page.write_protected = protect;
+            }
+            if protected {
+                // cached translations may still allow writes
+                self.tlb
+                    .add_range(mapping.addr..mapping.addr + mapping.span);
+            }
+        }
+        if !protect {
+            self.userfaults.wake(start..end);
+        }
+        self.finish_tlb();
+        Ok(())
+    }
+
+    /// Queue a fault on `addr` for the user fault handler if it's one the handler takes, and
+    /// fail with `userfault::WAITING` if so or if a fault on the page is already waiting.
+    fn check_userfault(
+        &mut self,
+        addr: VirtualAddress,
+        access_type: FlagBuilder,
+    ) -> Result<(), &'static str> {
+        let Ok(mapping) = self.get_mapping_for_addr(addr) else {
+            return Ok(());
+        };
+        let Some(mode) = mapping
+            .userfault
+            .filter(|_| mapping.flags.check_access_perms(access_type))
+        else {
+            return Ok(());
+        };
+        let index = (addr - mapping.addr) / PAGE_SIZE;
+        let page = mapping.addr + index * PAGE_SIZE;
+        if self.userfaults.is_waiting(page) {
+            // resolved without being woken, or not resolved yet
+            return Err(userfault::WAITING);
+        }
+        let kind = match mapping.pages.get(&index) {
+            None if mode.missing && !mapping.swapped.contains_key(&index) => UserFaultKind::Missing,
+            Some(page) if mode.write_protect && access_type.write && page.write_protected => {
+                UserFaultKind::WriteProtect
+            }
+            _ => return Ok(()),
+        };
+        self.userfaults.deliver(UserFaultEvent {
+            addr: page,
+            write: access_type.write,
+            kind,
+        });
+        Err(userfault::WAITING)
+    }
+
+    /// Fill `pages` missing pages starting at `start` with `data`, or zeros if it's `None`.
+    fn resolve_userfault(
+        &mut self,
+        start: VirtualAddress,
+        pages: usize,
+        data: Option<&[u8]>,
+        wake: bool,
+    ) -> Result<(), &'static str> {
+        let end = pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|len| start.checked_add(len))
+            .ok_or("Range wraps around the address space.")?;
+        for addr in (start..end).step_by(PAGE_SIZE) {
+            let mapping = self.get_mapping_for_addr(addr)?;
+            let index = (addr - mapping.addr) / PAGE_SIZE;
+            if !mapping.userfault.is_some_and(|mode| mode.missing) {
+                return Err("Range isn't registered for missing-page faults.");
+            }
+            if !(addr - mapping.addr).is_multiple_of(PAGE_SIZE) {
+                return Err("Pages must be filled from their start.");
+            }
+            if mapping.pages.contains_key(&index) || mapping.swapped.contains_key(&index) {
+                return Err("A page is already there.");
+            }
+        }
+        self.make_room(pages)?;
+        let zeros = [0; PAGE_SIZE];
+        for (i, addr) in (start..end).step_by(PAGE_SIZE).enumerate() {
+            let (mapping, usage) = self.get_mapping_for_addr_mut(addr)?;
+            let index = (addr - mapping.addr) / PAGE_SIZE;
+            let bytes = data.map_or(&zeros[..], |data| &data[i * PAGE_SIZE..(i + 1) * PAGE_SIZE]);
+            mapping.install(index, bytes, data.is_some(), usage)?;
+        }
+        if wake {
+            self.userfaults.wake(start..end);
+        }
+        Ok(())
+    }
+
+    /// Scan up to `scan` pages from the front of the inactive lists, file pages first, evicting
+    /// those that haven't been referenced since they were last looked at, until `enough` says
+    /// to stop. Returns how many pages were scanned and how many of those were evicted.
+    ///
+    /// Referenced pages, and pages that can't be evicted right now, go to the back of the active
+    /// list. The active lists are aged as the scan goes, so that neither grows bigger than its
+    /// inactive list.
+    pub(crate) fn shrink_lists<F>(&mut self, scan: usize, enough: F) -> (usize, usize)
+    where
+        F: Fn() -> bool,
+    {
+        let mut scanned = 0;
+        let mut evicted = 0;
+        while scanned < scan && !enough() {
+            for list in [LruList::InactiveFile, LruList::InactiveAnon] {
+                if self.usage.lru.len(list) < self.usage.lru.len(list.active()) {
+                    self.age_active(list);
+                }
+            }
+            let Some((addr, list)) = [LruList::InactiveFile, LruList::InactiveAnon]
+                .into_iter()
+                .find_map(|list| self.usage.lru.pop(list).map(|addr| (addr, list)))
+            else {
+                break;
+            };
+            scanned += 1;
+            if self.reclaim_page(addr) {
+                evicted += 1;
+            } else {
+                self.usage.lru.activate(addr, list);
+            }
+        }
+        self.finish_tlb();
+        (scanned, evicted)
+    }
+
+    /// Move the page at the front of the active list matching `list` to the inactive list,
+    /// unless it's been referenced since it was last looked at.
+    fn age_active(&mut self, list: LruList) {
+        let active = list.active();
+        let Some(addr) = self.usage.lru.pop(active) else {
+            return;
+        };
+        let referenced = self
+            .get_mapping_for_addr_mut(addr)
+            .ok()
+            .and_then(|(mapping, _)| mapping.pages.get_mut(&((addr - mapping.addr) / PAGE_SIZE)))
+            .is_some_and(|page| core::mem::take(&mut page.referenced));
+        if referenced {
+            self.usage.lru.activate(addr, list);
+        } else {
+            self.usage.lru.deactivate(addr, list);
+        }
+    }
+
+    /// Evict the resident page at `addr` if it hasn't been referenced since it was last looked
+    /// at, writing it back or out to swap first if it must be. Returns whether it was evicted.
+    fn reclaim_page(&mut self, addr: VirtualAddress) -> bool {
+        let Some(mapping) = self
+            .mappings
+            .iter_mut()
+            .find(|entry| entry.addr <= addr && addr - entry.addr < entry.span)
+        else {
+            return false;
+        };
+        let index = (addr - mapping.addr) / PAGE_SIZE;
+        let Some(page) = mapping.pages.get_mut(&index) else {
+            return false;
+        };
+        if core::mem::take(&mut page.referenced) || mapping.locked {
+            return false;
+        }
+        if page.dirty && mapping.flags.shared && !mapping.source.is_anonymous() {
+            let source = mapping.source.clone();
+            if cacher::write_back(
+                source.as_ref(),
+                mapping.offset,
+                &mut mapping.pages,
+                index..index + 1,
+            )
+            .is_err()
+            {
+                return false;
+            }
+        }
+        if !mapping.pages[&index].dirty {
+            mapping.drop_pages(&mut self.tlb, &mut self.usage, |i, _| i == index);
+            return true;
+        }
+        match &self.swap {
+            Some(swap) => mapping.swap_page_out(index, swap, &mut self.tlb, &mut self.usage),
+            None => false,
+        }
+    }
+
+    /// Reclaim from this `AddressSpace` before `pages` more pages are made resident: until the
+    /// `CacheCoordinator`'s minimum of free frames is reached again if it's been passed, and
+    /// until the pages fit under the memory group's hard limit.
+    ///
+    /// Only this address space is reclaimed from, not the rest of its memory group.
+    ///
+    /// # Errors
+    /// `memcg::GROUP_OOM` if nothing more can be evicted here and the pages still don't fit under
+    /// the hard limit.
+    fn make_room(&mut self, pages: usize) -> Result<(), &'static str> {
+        // every page can need a second look, once its referenced bit is cleared
+        let scan = 2 * self.usage.usage(0).resident();
+        if let Some(frames) = self
+            .usage
+            .coordinator()
+            .cloned()
+            .filter(|frames| frames.below_min())
+        {
+            self.shrink_lists(scan, || !frames.below_min());
+        }
+        let Some(group) = self.usage.group().cloned() else {
+            return Ok(());
+        };
+        if group.room() >= pages {
+            return Ok(());
+        }
+        group.note_limit_hit();
+        let (_, evicted) = self.shrink_lists(scan, || group.room() >= pages);
+        group.note_reclaimed(evicted);
+        if group.room() >= pages {
+            Ok(())
+        } else {
+            Err(memcg::GROUP_OOM)
+        }
+    }
+
+    /// Swap pages out to `swap`, which may be shared with other `AddressSpace`s, from now on.
+    /// Pages already swapped out stay on the swap space they went to until they come back in.
+    pub fn set_swap(&mut self, swap: Arc<SwapSpace>) {
+        self.swap = Some(swap);
+    }
+
+    /// Write up to `max` pages that can't be read back from their `DataSource` out to swap and
+    /// drop them, returning how many were swapped out. These are the dirty pages of private and
+    /// anonymous mappings; pages in locked ranges are never swapped. Stops early if swap is full
+    /// or writing to it fails.
+    pub fn swap_out(&mut self, max: usize) -> usize {
+        let Some(swap) = self.swap.clone() else {
+            return 0;
+        };
+        let mut swapped = 0;
+        let mut full = false;
+        for mapping in self
+            .mappings
+            .iter_mut()
+            .filter(|entry| !entry.locked && (!entry.flags.shared || entry.source.is_anonymous()))
+        {
+            if swapped >= max || full {
+                break;
+            }
+            let candidates: Vec<usize> = mapping
+                .pages
+                .iter()
+                .filter(|(_, page)| page.dirty)
+                .map(|(index, _)| *index)
+                .take(max - swapped)
+                .collect();
+            for index in candidates {
+                if !mapping.swap_page_out(index, &swap, &mut self.tlb, &mut self.usage) {
+                    full = true;
+                    break;
+                }
+                swapped += 1;
+            }
+        }
+        self.finish_tlb();
+        swapped
+    }
+
+    /// Make a copy of this `AddressSpace` for a child process, like `fork`.
+    ///
+    /// Private mappings are copied page by page, and pages that are swapped out share their swap
+    /// slots with the child. Shared mappings of files are written back first, so that the child
+    /// sees the same data. The child starts with nothing locked, and without a `TlbInvalidator`.
+    /// It joins the same memory group, and its copies are charged to the group even if that
+    /// takes it over its hard limit. Pages kept in `PhysicalMemory` share their frames with the
+    /// child until one side writes to them.
+    ///
+    /// # Errors
+    /// If there's shared anonymous memory, which can't be shared with the child yet, if writing
+    /// back a shared mapping fails, or if the child's mappings can't be committed to.
+    pub fn fork(&mut self, name: &str) -> Result<AddressSpace, &str> {
+        if self
+            .mappings
+            .iter()
+            .any(|entry| entry.flags.shared && entry.source.is_anonymous())
+        {
+            return Err("Shared anonymous memory can't be forked yet.");
+        }
+        for entry in self.mappings.iter_mut().filter(|entry| entry.flags.shared) {
+            let all = 0..entry.page_count();
+            cacher::write_back(entry.source.as_ref(), entry.offset, &mut entry.pages, all)
+                .map_err(|_| "DataSource write failed.")?;
+        }
+        let mut child = AddressSpace::new(name);
+        child.brk_start = self.brk_start;
+        child.brk = self.brk;
+        child.limits = self.limits;
+        child.commit.clone_from(&self.commit);
+        child.swap.clone_from(&self.swap);
+        if let Some(frames) = self.usage.coordinator() {
+            child.usage.set_coordinator(frames.clone());
+        }
+        if let Some(group) = self.usage.group() {
+            child.usage.set_group(group.clone());
+        }
+        if let Some(memory) = self.usage.memory() {
+            child.usage.set_memory(memory.clone());
+        }
+        child.usage.set_node(self.usage.node());
+        for entry in &self.mappings {
+            let mut copy = MapEntry::new(
+                entry.source.clone(),
+                entry.offset,
+                entry.span,
+                entry.addr,
+                entry.flags,
+            );
+            copy.advice = entry.advice;
+            copy.policy = entry.policy;
+            copy.kind = entry.kind;
+            if let Some(commit) = child.commit.as_ref().filter(|_| entry.charged) {
+                commit.charge(entry.page_count(), false)?;
+                copy.charged = true;
+            }
+            if !entry.flags.shared {
+                copy.pages = entry.pages.clone();
+                copy.swapped = entry.swapped.clone();
+            }
+            let kind = copy.page_kind();
+            for index in copy.pages.keys() {
+                child.usage.page_in(kind, copy.addr + index * PAGE_SIZE);
+            }
+            for (swap, slot) in copy.swapped.values() {
+                swap.duplicate(*slot);
+                child.usage.swapped_out();
+            }
+            child.usage.map(copy.page_count());
+            child.mappings.push_back(copy);
+        }
+        Ok(child)
+    }
+
+    /// Have `invalidator` flush other harts' cached translations, tagged with `asid`, whenever
+    /// pages are unmapped or dropped or lose a permission.
+    pub fn set_tlb_invalidator(&mut self, asid: usize, invalidator: Arc<dyn TlbInvalidator>) {
+        self.asid = asid;
+        self.invalidator = Some(invalidator);
+    }
+
+    /// Hold off invalidating translations until `flush_tlb`, so that several changes share one
+    /// round of invalidations. Pages dropped in the meantime aren't freed until then either.
+    pub fn begin_tlb_batch(&mut self) {
+        self.tlb.open();
+    }
+
+    /// Invalidate every translation that's gone stale since the batch began, then free the
+    /// pages that were dropped.
+    pub fn flush_tlb(&mut self) {
+        self.tlb.flush(self.asid, self.invalidator.as_deref());
+    }
+
+    /// Number of dropped pages that can't be reused until their translations are invalidated.
+    #[must_use]
+    pub fn pending_tlb_pages(&self) -> usize {
+        self.tlb.pending_pages()
+    }
+
+    /// Fault in every page of `[start, start + len)` and keep them resident until they're
+    /// unlocked, like `mlock`.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped, if locking it would go over the lock limit or the
+    /// memory group's hard limit, or if reading from a `DataSource` fails.
+    pub fn lock_range(&mut self, start: VirtualAddress, len: usize) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        self.lock_pages(start, end)
+    }
+
+    /// Let the pages of `[start, start + len)` be evicted again, like `munlock`.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped.
+    pub fn unlock_range(&mut self, start: VirtualAddress, len: usize) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        self.unlock_pages(start, end)
+    }
+
+    /// Lock every current mapping, and if `future` is set, every mapping added from now on, like
+    /// `mlockall`.
+    ///
+    /// # Errors
+    /// If locking would go over the lock limit, or if reading from a `DataSource` fails.
+    pub fn lock_all(&mut self, future: bool) -> Result<(), &str> {
+        let ranges: Vec<_> = self
+            .mappings
+            .iter()
+            .map(|entry| (entry.addr, entry.addr + entry.span))
+            .collect();
+        for (start, end) in ranges {
+            self.lock_pages(start, end)?;
+        }
+        self.lock_future = future;
+        Ok(())
+    }
+
+    /// Unlock every mapping and stop locking new ones, like `munlockall`.
+    pub fn unlock_all(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.locked = false;
+        }
+        self.locked_pages = 0;
+        self.lock_future = false;
+    }
+
+    /// Set the most memory, in bytes, that may be locked in this `AddressSpace`. The same as
+    /// setting `Limits::memlock`.
+    pub fn set_lock_limit(&mut self, limit: usize) {
+        self.limits.memlock = limit;
+    }
+
+    /// Charge private writable and anonymous mappings added from now on to `commit`, which may
+    /// be shared with other `AddressSpace`s. Mappings that are already here aren't charged.
+    pub fn set_commit_accountant(&mut self, commit: Arc<CommitAccountant>) {
+        self.commit = Some(commit);
+    }
+
+    /// The limits on how much memory this `AddressSpace` may use.
+    #[must_use]
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Replace the limits on how much memory this `AddressSpace` may use, like `setrlimit`.
+    /// Memory already in use isn't taken away if it's over a new limit, but it can't grow.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Number of pages currently locked in this `AddressSpace`.
+    #[must_use]
+    pub fn locked_pages(&self) -> usize {
+        self.locked_pages
+    }
+
+    /// How much memory this `AddressSpace` is using, and the most it has used.
+    #[must_use]
+    pub fn usage(&self) -> MemoryUsage {
+        self.usage.usage(self.locked_pages)
+    }
+
+    /// A breakdown of the memory each mapping is using, in address order, like
+    /// `/proc/<pid>/smaps`.
+    #[must_use]
+    pub fn smaps(&self) -> Vec<MappingUsage> {
+        self.mappings
+            .iter()
+            .map(|entry| MappingUsage {
+                start: entry.addr,
+                end: entry.addr + entry.span,
+                offset: entry.offset,
+                flags: entry.flags,
+                anonymous: entry.source.is_anonymous(),
+                size: entry.page_count(),
+                resident: entry.pages.len(),
+                dirty: entry.pages.values().filter(|page| page.dirty).count(),
+                swapped: entry.swapped.len(),
+                locked: if entry.locked { entry.page_count() } else { 0 },
+            })
+            .collect()
+    }
+
+    fn lock_pages(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Result<(), &'static str> {
+        let newly_locked: usize = self
+            .mappings
+            .iter()
+            .filter(|entry| entry.overlaps(start, end) && !entry.locked)
+            .map(|entry| entry.touched_pages(start, end).len())
+            .sum();
+        if (self.locked_pages + newly_locked).saturating_mul(PAGE_SIZE) > self.limits.memlock {
+            return Err(limits::MEMLOCK_LIMIT);
+        }
+        let missing = self
+            .mappings
+            .iter()
+            .filter(|entry| entry.overlaps(start, end) && !entry.locked)
+            .map(|entry| {
+                entry
+                    .touched_pages(start, end)
+                    .filter(|i| !entry.pages.contains_key(i))
+                    .count()
+            })
+            .sum();
+        self.make_room(missing)?;
+        let mut locked = 0;
+        let result = self.for_each_mapping_in(start, end, |mapping, usage| {
+            if !mapping.locked {
+                mapping.prefetch(usage)?;
+                mapping.locked = true;
+                locked += mapping.page_count();
+            }
+            Ok(())
+        });
+        self.locked_pages += locked;
+        result
+    }
+
+    fn unlock_pages(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Result<(), &'static str> {
+        let mut unlocked = 0;
+        let result = self.for_each_mapping_in(start, end, |mapping, _| {
+            if mapping.locked {
+                mapping.locked = false;
+                unlocked += mapping.page_count();
+            }
+            Ok(())
+        });
+        self.locked_pages -= unlocked;
+        result
+    }
+
+    /// Lock a mapping that was just added if `lock_all` asked for future mappings to be locked,
+    /// taking the mapping back out again if that fails.
+    fn lock_if_future(&mut self, start: VirtualAddress, span: usize) -> Result<(), &'static str> {
+        if !self.lock_future {
+            return Ok(());
+        }
+        let result = self.lock_pages(start, start + span);
+        if result.is_err() {
+            let _ = self.unlock_pages(start, start + span);
+            let mut curs = self.mappings.cursor_front_mut();
+            while curs.current().is_some_and(|entry| entry.addr != start) {
+                curs.move_next();
+            }
+            if let Some(entry) = curs.remove_current() {
+                self.release(entry);
+                self.finish_tlb();
+            }
+        }
+        result
+    }
+
+    /// Set up the program break heap to start at `start`, usually just past the end of the
+    /// program's data.
+    pub fn init_brk(&mut self, start: VirtualAddress) {
+        self.brk_start = start;
+        self.brk = start;
+    }
+
+    /// The initial program break.
+    #[must_use]
+    pub fn brk_start(&self) -> VirtualAddress {
+        self.brk_start
+    }
+
+    /// The current program break.
+    #[must_use]
+    pub fn brk(&self) -> VirtualAddress {
+        self.brk
+    }
+
+    /// Move the program break to `new_end`, returning the new break, like `brk`.
+    ///
+    /// Growing the heap extends its last anonymous mapping, or adds a new one if the heap is
+    /// empty. Shrinking it unmaps the pages that are no longer below the break. New pages are
+    /// locked and faulted in if the heap is locked, or if `lock_all` asked for new mappings to be.
+    ///
+    /// # Errors
+    /// If there's no heap, if `new_end` is below the initial break, if growing would run into
+    /// the next mapping or the guard page below it, or if the new pages can't be locked.
+    pub fn set_brk(&mut self, new_end: VirtualAddress) -> Result<VirtualAddress, &str> {
+        if self.brk_start == 0 {
+            return Err("The heap hasn't been set up.");
+        }
+        if new_end < self.brk_start {
+            return Err("Can't move the break below where the heap starts.");
+        }
+        let old_top = Self::round_up(self.brk);
+        let new_top = new_end
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or("No memory chunk available.")?;
+        if new_top > old_top {
+            if self
+                .mappings
+                .iter()
+                .any(|entry| entry.overlaps(old_top, new_top.saturating_add(entry.guard_gap())))
+            {
+                return Err("No memory chunk available.");
+            }
+            let flags = FlagBuilder::new()
+                .toggle_read()
+                .toggle_write()
+                .toggle_private();
+            let pages = (new_top - old_top) / PAGE_SIZE;
+            let top_charged = self
+                .mappings
+                .iter()
+                .find(|entry| entry.kind == MapKind::Heap && entry.addr + entry.span == old_top)
+                .map(|entry| entry.charged);
+            self.check_growth(
+                pages,
+                flags,
+                MapKind::Heap,
+                usize::from(top_charged.is_none()),
+            )?;
+            // extending a heap that was mapped before there was an accountant stays uncharged
+            let charged = top_charged != Some(false) && self.charge(pages, flags, true)?;
+            let heap_top = self
+                .mappings
+                .iter_mut()
+                .find(|entry| entry.kind == MapKind::Heap && entry.addr + entry.span == old_top);
+            let grew_locked = heap_top.as_ref().is_some_and(|entry| entry.locked);
+            if let Some(heap_top) = heap_top {
+                heap_top.span += new_top - old_top;
+                self.usage.map(pages);
+            } else {
+                let mut heap = MapEntry::new(
+                    Arc::new(AnonymousDataSource),
+                    0,
+                    new_top - old_top,
+                    old_top,
+                    flags,
+                );
+                heap.kind = MapKind::Heap;
+                heap.charged = charged;
+                self.insert_mapping(heap);
+            }
+            // new heap pages are locked if the heap was, or if `lock_all` asked for new mappings
+            // to be
+            let locked = if grew_locked {
+                self.lock_grown(old_top, new_top)
+            } else if self.lock_future {
+                self.lock_pages(old_top, new_top)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = locked {
+                self.unmap_pages(old_top, new_top);
+                return Err(e);
+            }
+        } else if new_top < old_top {
+            self.unmap_pages(new_top, old_top);
+        }
+        self.brk = new_end;
+        Ok(new_end)
+    }
+
+    /// Add an anonymous stack mapping of `size` bytes ending at `top`, which grows down on
+    /// faults below it until it's `max_size` bytes long. Returns the lowest address of the stack.
+    ///
+    /// `STACK_GUARD_GAP` bytes below the stack are kept free: other mappings can't be placed
+    /// there, and the stack won't grow to within that distance of the mapping below it. Each
+    /// stack, such as one per thread, has its own `max_size`.
+    ///
+    /// # Errors
+    /// If `top` isn't page aligned, `size` is zero or bigger than `max_size`, or there isn't
+    /// room for the stack and its guard gap.
+    pub fn add_stack_at(
+        &mut self,
+        top: VirtualAddress,
+        size: usize,
+        max_size: usize,
+        flags: FlagBuilder,
+    ) -> Result<VirtualAddress, &str> {
+        let size = Self::round_up(size);
+        if !top.is_multiple_of(PAGE_SIZE) || size == 0 || size > max_size {
+            return Err("Invalid stack size or address.");
+        }
+        let bottom = top
+            .checked_sub(size)
+            .ok_or("Invalid stack size or address.")?;
+        if bottom < STACK_GUARD_GAP
+            || self.mappings.iter().any(|entry| {
+                entry.overlaps(
+                    bottom - STACK_GUARD_GAP,
+                    top.saturating_add(entry.guard_gap()),
+                )
+            })
+        {
+            return Err("Insufficient free memory in desired region.");
+        }
+        if size > self.limits.stack {
+            return Err(limits::STACK_LIMIT);
+        }
+        let kind = MapKind::Stack { top, max_size };
+        self.check_growth(size / PAGE_SIZE, flags, kind, 1)?;
+        let charged = self.charge(size / PAGE_SIZE, flags, true)?;
+        let mut stack = MapEntry::new(Arc::new(AnonymousDataSource), 0, size, bottom, flags);
+        stack.kind = kind;
+        stack.charged = charged;
+        self.insert_mapping(stack);
+        self.lock_if_future(bottom, size)?;
+        Ok(bottom)
+    }
+
+    /// Add a stack like `add_stack_at`, wherever there's room for it to grow to `max_size`.
+    ///
+    /// # Errors
+    /// If `size` is zero or bigger than `max_size`, or there's no room for the stack.
+    pub fn add_stack(
+        &mut self,
+        size: usize,
+        max_size: usize,
+        flags: FlagBuilder,
+    ) -> Result<VirtualAddress, &str> {
+        let max_size = Self::round_up(max_size);
+        let needed = max_size
+            .checked_add(STACK_GUARD_GAP)
+            .ok_or("No memory chunk available.")?;
+        let mut prev_end = 0;
+        let mut top = None;
+        for entry in &self.mappings {
+            if entry.addr.saturating_sub(prev_end + PAGE_SIZE) >= needed + entry.guard_gap() {
+                top = Some(prev_end + PAGE_SIZE + needed);
+                break;
+            }
+            prev_end = entry.addr + entry.span;
+        }
+        let top = top
+            .or_else(|| (prev_end + PAGE_SIZE).checked_add(needed))
+            .ok_or("No memory chunk available.")?;
+        self.add_stack_at(top, size, max_size, flags)
+    }
+
+    /// Grow the stack above `addr` down to cover it, if it's allowed to.
+    fn grow_stack(&mut self, addr: VirtualAddress) -> Result<(), &'static str> {
+        let new_bottom = addr - addr % PAGE_SIZE;
+        let bounds = self.limits;
+        let virtual_pages = self.usage.virtual_pages();
+        let commit = self.commit.clone();
+        let mut curs = self.mappings.cursor_front_mut();
+        while curs.current().is_some_and(|entry| entry.addr <= addr) {
+            curs.move_next();
+        }
+        let prev_end = curs.peek_prev().map_or(0, |entry| entry.addr + entry.span);
+        let Some(stack) = curs.current() else {
+            return Err("No mapping with target address.");
+        };
+        let MapKind::Stack { top, max_size } = stack.kind else {
+            return Err("No mapping with target address.");
+        };
+        if top - new_bottom > bounds.stack {
+            return Err(limits::STACK_LIMIT);
+        }
+        if top - new_bottom > max_size {
+            return Err("The stack can't grow past its maximum size.");
+        }
+        if new_bottom < prev_end.saturating_add(STACK_GUARD_GAP) {
+            return Err("The stack can't grow into its guard gap.");
+        }
+        let len = stack.addr - new_bottom;
+        if (virtual_pages + len / PAGE_SIZE).saturating_mul(PAGE_SIZE) > bounds.address_space {
+            return Err(limits::ADDRESS_SPACE_LIMIT);
+        }
+        if let Some(commit) = commit.filter(|_| stack.charged) {
+            commit.charge(len / PAGE_SIZE, false)?;
+        }
+        let locked = stack.locked;
+        stack.grow_down(len);
+        self.usage.map(len / PAGE_SIZE);
+        if locked {
+            if let Err(e) = self.lock_grown(new_bottom, new_bottom + len) {
+                self.unmap_pages(new_bottom, new_bottom + len);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lock `[start, end)`, just added to a locked mapping by growing it, the way `lock_pages`
+    /// locks a range: the pages count towards the lock limit and are faulted in. They're counted
+    /// even if that fails, since they're part of a locked mapping, and the caller should unmap
+    /// them again.
+    fn lock_grown(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Result<(), &'static str> {
+        let pages = (end - start) / PAGE_SIZE;
+        self.locked_pages += pages;
+        if self.locked_pages.saturating_mul(PAGE_SIZE) > self.limits.memlock {
+            return Err(limits::MEMLOCK_LIMIT);
+        }
+        self.make_room(pages)?;
+        let (mapping, usage) = self.get_mapping_for_addr_mut(start)?;
+        mapping.prefetch(usage)
+    }
+
+    /// Check that `source` can back a mapping of `span` bytes at `offset` with `flags`: it must
+    /// allow the access, and hold the range unless it can grow to.
+    pub(crate) fn check_source(
+        source: &dyn DataSource,
+        offset: usize,
+        span: usize,
+        flags: FlagBuilder,
+    ) -> Result<(), &'static str> {
+        Self::check_source_flags(source, flags)?;
+        let end = offset.checked_add(span).ok_or(data_source::PAST_END)?;
+        match source.length() {
+            Some(length) if end > Self::round_up(length) && !source.capabilities().resizable => {
+                Err(data_source::PAST_END)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `source` allows a mapping with `flags`. Anonymous memory is never written
+    /// back, so any mapping of it may be writable.
+    pub(crate) fn check_source_flags(
+        source: &dyn DataSource,
+        flags: FlagBuilder,
+    ) -> Result<(), &'static str> {
+        let capabilities = source.capabilities();
+        let writable = capabilities.writable || source.is_anonymous();
+        if !capabilities.readable
+            || (flags.execute && !capabilities.executable)
+            || (flags.shared && flags.write && !writable)
+        {
+            return Err(data_source::NOT_PERMITTED);
+        }
+        Ok(())
+    }
+
+    /// Check that `entries` more mappings, and `pages` more pages of mappings of `kind` with
+    /// `flags`, would stay within this `AddressSpace`'s limits.
+    fn check_growth(
+        &self,
+        pages: usize,
+        flags: FlagBuilder,
+        kind: MapKind,
+        entries: usize,
+    ) -> Result<(), &'static str> {
+        if self.mappings.len() + entries > self.limits.max_map_count {
+            return Err(limits::MAP_COUNT_LIMIT);
+        }
+        if (self.usage.virtual_pages() + pages).saturating_mul(PAGE_SIZE)
+            > self.limits.address_space
+        {
+            return Err(limits::ADDRESS_SPACE_LIMIT);
+        }
+        if kind.counts_as_data(flags)
+            && (self.data_pages() + pages).saturating_mul(PAGE_SIZE) > self.limits.data
+        {
+            return Err(limits::DATA_LIMIT);
+        }
+        Ok(())
+    }
+
+    /// Charge `pages` pages of a new mapping with `flags` to the `CommitAccountant`, if it needs
+    /// charging, returning whether it was.
+    fn charge(
+        &self,
+        pages: usize,
+        flags: FlagBuilder,
+        anonymous: bool,
+    ) -> Result<bool, &'static str> {
+        match &self.commit {
+            Some(commit) if (flags.private && flags.write) || anonymous => {
+                commit.charge(pages, flags.noreserve)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn uncharge(&self, pages: usize, charged: bool) {
+        if let Some(commit) = self.commit.as_ref().filter(|_| charged) {
+            commit.uncharge(pages);
+        }
+    }
+
+    /// Pages counted towards `Limits::data`.
+    fn data_pages(&self) -> usize {
+        self.mappings
+            .iter()
+            .filter(|entry| entry.kind.counts_as_data(entry.flags))
+            .map(MapEntry::page_count)
+            .sum()
+    }
+
+    /// Put `entry` into `self.mappings` in address order, without checking for room around it.
+    fn insert_mapping(&mut self, entry: MapEntry) {
+        let mut curs = self.mappings.cursor_front_mut();
+        while curs
+            .current()
+            .is_some_and(|current| current.addr < entry.addr)
+        {
+            curs.move_next();
+        }
+        self.usage.map(entry.page_count());
+        curs.insert_before(entry);
+    }
+
+    /// Write the dirty pages of shared mappings in `[start, start + len)` back to their
+    /// `DataSource`s, like `msync`.
+    ///
+    /// `SyncMode::Sync` and `SyncMode::Invalidate` also flush each `DataSource` over the range,
+    /// and `SyncMode::Invalidate` then drops the clean, unlocked pages of every mapping in the
+    /// range so that they are read again on the next access.
+    ///
+    /// # Errors
+    /// The first error a `DataSource` reported, or if there was none, if any part of the range
+    /// is unmapped. Every mapping in the range is still attempted after an error.
+    pub fn sync_range(
+        &mut self,
+        start: VirtualAddress,
+        len: usize,
+        mode: SyncMode,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        let mapped = self.is_range_mapped(start, end);
+        let mut first_error = None;
+        for entry in self
+            .mappings
+            .iter_mut()
+            .filter(|entry| entry.overlaps(start, end))
+        {
+            let touched = entry.touched_pages(start, end);
+            let kind = entry.page_kind();
+            let MapEntry {
+                source,
+                offset,
+                addr,
+                flags,
+                pages,
+                locked,
+                ..
+            } = entry;
+            let source = &**source;
+            if flags.shared && !source.is_anonymous() {
+                if let Err(e) = cacher::write_back(source, *offset, pages, touched.clone()) {
+                    first_error.get_or_insert(e);
+                }
+                if mode != SyncMode::Async {
+                    let flushed = source.flush(
+                        *offset + touched.start * PAGE_SIZE,
+                        touched.len() * PAGE_SIZE,
+                    );
+                    if let Err(e) = flushed {
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            if mode == SyncMode::Invalidate && !*locked {
+                drop_pages(
+                    pages,
+                    *addr,
+                    kind,
+                    &mut self.tlb,
+                    &mut self.usage,
+                    |index, page| touched.contains(&index) && !page.dirty,
+                );
+            }
+        }
+        // not `finish_tlb`, as `first_error` may still borrow from `self.mappings`
+        if !self.tlb.is_open() {
+            self.tlb.flush(self.asid, self.invalidator.as_deref());
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None if !mapped => Err("Range is not entirely mapped."),
+            None => Ok(()),
+        }
+    }
+
+    /// Is every byte of `[start, end)` covered by some mapping?
+    fn is_range_mapped(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        let mut covered = start;
+        for entry in &self.mappings {
+            if covered >= end {
+                break;
+            }
+            if entry.addr <= covered && covered - entry.addr < entry.span {
+                covered = entry.addr + entry.span;
+            }
+        }
+        covered >= end
+    }
+
+    /// Split mappings that straddle either end of `[start, end)`, so that every mapping the range
+    /// touches lies entirely inside it, rounded out to whole pages.
+    fn split_range(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        let mut curs = self.mappings.cursor_front_mut();
+        while let Some(entry) = curs.current() {
+            if entry.addr >= end {
+                break;
+            }
+            if entry.overlaps(start, end) {
+                let touched = entry.touched_pages(start, end);
+                if touched.start > 0 {
+                    let tail = entry.split_off(touched.start);
+                    curs.insert_after(tail);
+                } else if touched.end < entry.page_count() {
+                    let tail = entry.split_off(touched.end);
+                    curs.insert_after(tail);
+                }
+            }
+            curs.move_next();
+        }
+    }
+
+    /// Split mappings so that every page touched by `[start, end)` lies in a mapping entirely
+    /// inside the range, then call `f` on each of those mappings in address order.
+    ///
+    /// Nothing is split unless the whole range is mapped.
+    fn for_each_mapping_in<F>(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        mut f: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(&mut MapEntry, &mut Accounting) -> Result<(), &'static str>,
+    {
+        if start >= end {
+            return Ok(());
+        }
+        if !self.is_range_mapped(start, end) {
+            return Err("Range is not entirely mapped.");
+        }
+        let splits: usize = self
+            .mappings
+            .iter()
+            .filter(|entry| entry.overlaps(start, end))
+            .map(|entry| {
+                let touched = entry.touched_pages(start, end);
+                usize::from(touched.start > 0) + usize::from(touched.end < entry.page_count())
+            })
+            .sum();
+        if self.mappings.len() + splits > self.limits.max_map_count {
+            return Err(limits::MAP_COUNT_LIMIT);
+        }
+        self.split_range(start, end);
+        for mapping in self
+            .mappings
+            .iter_mut()
+            .filter(|entry| entry.overlaps(start, end))
+        {
+            f(mapping, &mut self.usage)?;
+        }
+        Ok(())
+    }
+
+    /// Tidy up after a mapping has been taken out of `self.mappings`, writing back any dirty
+    /// pages of a shared mapping and queueing its translations for invalidation.
+    fn release(&mut self, entry: MapEntry) {
+        self.set_aside(&entry);
+        self.release_pages(entry);
+    }
+
+    /// Stop counting `entry`, which has been taken out of `self.mappings`, towards this
+    /// `AddressSpace`'s limits and commit charge, leaving its pages alone.
+    fn set_aside(&mut self, entry: &MapEntry) {
+        if entry.locked {
+            self.locked_pages -= entry.page_count();
+        }
+        self.uncharge(entry.page_count(), entry.charged);
+        self.usage.unmap(entry.page_count());
+    }
+
+    /// Put back a mapping set aside with `set_aside`, counting it again.
+    fn restore(&mut self, entry: MapEntry) {
+        if entry.locked {
+            self.locked_pages += entry.page_count();
+        }
+        if let Some(commit) = self.commit.as_ref().filter(|_| entry.charged) {
+            commit.restore(entry.page_count());
+        }
+        self.insert_mapping(entry);
+    }
+
+    /// Free the pages of a mapping set aside with `set_aside`, writing shared ones back first.
+    fn release_pages(&mut self, mut entry: MapEntry) {
+        entry.discard_swapped(&mut self.usage);
+        if entry.flags.shared && !entry.source.is_anonymous() {
+            let all = 0..entry.page_count();
+            let _ = cacher::write_back(entry.source.as_ref(), entry.offset, &mut entry.pages, all);
+        }
+        let kind = entry.page_kind();
+        for index in entry.pages.keys() {
+            self.usage.page_out(kind, entry.addr + index * PAGE_SIZE);
+        }
+        if !entry.pages.is_empty() {
+            self.tlb.add_range(entry.addr..entry.addr + entry.span);
+            self.tlb.defer_free(entry.pages.into_values());
+        }
+    }
+
+    /// Invalidate what's been gathered in `self.tlb`, unless a batch is being held open.
+    fn finish_tlb(&mut self) {
+        if !self.tlb.is_open() {
+            self.tlb.flush(self.asid, self.invalidator.as_deref());
+        }
+    }
+
+    /// Helper function for looking up mappings
+    fn get_mapping_for_addr(&self, addr: VirtualAddress) -> Result<&MapEntry, &'static str> {
+        self.mappings
+            .iter()
+            .find(|entry| entry.addr <= addr && addr - entry.addr < entry.span)
+            .ok_or("No mapping with target address.")
+    }
+
+    /// Like `get_mapping_for_addr`, along with the counters to charge any faults on it to.
+    fn get_mapping_for_addr_mut(
+        &mut self,
+        addr: VirtualAddress,
+    ) -> Result<(&mut MapEntry, &mut Accounting), &'static str> {
+        let mapping = self
+            .mappings
+            .iter_mut()
+            .find(|entry| entry.addr <= addr && addr - entry.addr < entry.span)
+            .ok_or("No mapping with target address.")?;
+        Ok((mapping, &mut self.usage))
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Give back the commit charges and swap slots of every mapping still here.
+    fn drop(&mut self) {
+        let charged = self
+            .mappings
+            .iter()
+            .filter(|entry| entry.charged)
+            .map(MapEntry::page_count)
+            .sum();
+        self.uncharge(charged, true);
+        for mapping in &mut self.mappings {
+            mapping.discard_swapped(&mut self.usage);
+        }
+    }
+}
+
+/// Drop the pages of a mapping at `addr` that `f` picks out by index. See `MapEntry::drop_pages`.
+fn drop_pages<F>(
+    pages: &mut BTreeMap<usize, Page>,
+    addr: VirtualAddress,
+    kind: PageKind,
+    batch: &mut TlbBatch,
+    usage: &mut Accounting,
+    mut f: F,
+) -> usize
+where
+    F: FnMut(usize, &Page) -> bool,
+{
+    let doomed: Vec<usize> = pages
+        .iter()
+        .filter(|(i, page)| f(**i, page))
+        .map(|(i, _)| *i)
+        .collect();
+    for &index in &doomed {
+        let page_addr = addr + index * PAGE_SIZE;
+        usage.page_out(kind, page_addr);
+        batch.add_range(page_addr..page_addr + PAGE_SIZE);
+        batch.defer_free(pages.remove(&index));
+    }
+    doomed.len()
+}
+
+/// A hint about how a range of an `AddressSpace` will be used. See `AddressSpace::advise`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Advice {
+    /// No special treatment; read-ahead adapts to the access pattern.
+    #[default]
+    Normal,
+    /// Expect sequential access: always read ahead as far as possible.
+    Sequential,
+    /// Expect random access: never read ahead.
+    Random,
+    /// The pages will be needed soon, so read them in now.
+    WillNeed,
+    /// The pages won't be needed, so drop them now.
+    DontNeed,
+    /// The contents of the pages can be thrown away if memory is needed.
+    Free,
+}
+
+/// How `AddressSpace::sync_range` should write data back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Write dirty pages back without waiting for the `DataSource` to flush them.
+    Async,
+    /// Write dirty pages back and flush the `DataSource`.
+    Sync,
+    /// Like `Sync`, then drop the cached pages so they're read again on the next access.
+    Invalidate,
+}
+
+/// Build flags for address space maps.
+///
+/// We recommend using this builder type as follows:
+/// ```
+/// # use reedos_address_space::FlagBuilder;
+/// let flags = FlagBuilder::new()
+///     .toggle_read()
+///     .toggle_write();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
+                                         // here because these directly correspond to yes/no
+                                         // hardware flags
+pub struct FlagBuilder {
+    // TODO: should there be some sanity checks that conflicting flags are never toggled? can we do
+    // this at compile-time? (the second question is maybe hard)
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) execute: bool,
+    pub(crate) cow: bool,
+    pub(crate) private: bool,
+    pub(crate) shared: bool,
+    pub(crate) noreserve: bool, // don't charge the mapping for commit, like MAP_NORESERVE
+}
+
+impl FlagBuilder {
+    pub fn check_access_perms(&self, access_perms: FlagBuilder) -> bool {
+        if access_perms.read && !self.read
+            || access_perms.write && !self.write
+            || access_perms.execute && !self.execute
+        {
+            return false;
+        }
+        true
+    }
+
+    /// These flags with read, write and execute taken from `perms` instead.
+    pub(crate) const fn with_perms(self, perms: FlagBuilder) -> Self {
+        Self {
+            read: perms.read,
+            write: perms.write,
+            execute: perms.execute,
+            ..self
+        }
     }
 
     pub fn is_valid(&self) -> bool {
         if self.private && self.shared {
             return false;
         }
-        if self.cow && self.write { // for COW to work, write needs to be off until after the copy
+        if self.cow && self.write {
+            // for COW to work, write needs to be off until after the copy
             return false;
         }
         true
@@ -320,6 +2560,7 @@ impl FlagBuilder {
     flag!(cow, toggle_cow);
     flag!(private, toggle_private);
     flag!(shared, toggle_shared);
+    flag!(noreserve, toggle_noreserve);
 
     #[must_use]
     /// Combine two `FlagBuilder`s by boolean or-ing each of their flags.
@@ -341,6 +2582,7 @@ impl FlagBuilder {
         let cow = self.cow || other.cow;
         let private = self.private || other.private;
         let shared = self.shared || other.shared;
+        let noreserve = self.noreserve || other.noreserve;
 
         Self {
             read,
@@ -349,6 +2591,7 @@ impl FlagBuilder {
             cow,
             private,
             shared,
+            noreserve,
         }
     }
 
@@ -371,6 +2614,7 @@ impl FlagBuilder {
         let cow = self.cow && !other.cow;
         let private = self.private && !other.private;
         let shared = self.shared && !other.shared;
+        let noreserve = self.noreserve && !other.noreserve;
 
         Self {
             read,
@@ -379,6 +2623,7 @@ impl FlagBuilder {
             cow,
             private,
             shared,
+            noreserve,
         }
     }
 }
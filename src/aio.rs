@@ -0,0 +1,444 @@
+// Asynchronous reads and writes, so that a slow source doesn't hold up faults behind it.
+//
+// An `AsyncDataSource` takes a request and a completion to call once it's done, and is free to
+// call it from any thread, or before the request even returns. A `PageFetcher` sits in front of
+// one and keeps as many page reads in flight as are asked for: a second request for a page that's
+// already being read waits on the same read, and everyone waiting on a page is woken when its
+// data arrives, whether they're polling a future or blocked in `wait`. It keeps only so many of
+// the pages that have arrived, dropping the oldest first.
+//
+// A `PageFetcher` is itself a `DataSource`, so it can back a mapping: a fault then reads through
+// it, and starts every page of its read-ahead window before waiting on the first. The fault still
+// waits for its own pages; it's the pages behind it that no longer queue up one at a time.
+//
+// `SyncAdapter` lets any `DataSource` be used where an `AsyncDataSource` is wanted, by doing the
+// work on the spot.
+
+use std::boxed::Box;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::vec;
+use std::vec::Vec;
+
+use crate::address_space::PAGE_SIZE;
+use crate::concurrent::lock;
+use crate::data_source::{Capabilities, DataSource};
+
+/// Called with the data read, or why it couldn't be.
+pub type ReadCompletion = Box<dyn FnOnce(Result<Vec<u8>, &'static str>) + Send>;
+/// Called once a write has finished, or failed.
+pub type WriteCompletion = Box<dyn FnOnce(Result<(), &'static str>) + Send>;
+
+/// A `DataSource` whose requests complete later.
+pub trait AsyncDataSource: Send + Sync {
+    /// Start reading `length` bytes at `offset`, and call `done` with them when they arrive.
+    fn read_async(&self, offset: usize, length: usize, done: ReadCompletion);
+    /// Start writing `data` at `offset`, and call `done` when it's been written.
+    fn write_async(&self, offset: usize, data: Vec<u8>, done: WriteCompletion);
+}
+
+/// Serves the requests of an `AsyncDataSource` from a synchronous `DataSource`, completing each
+/// before returning.
+pub struct SyncAdapter(pub Arc<dyn DataSource>);
+
+impl AsyncDataSource for SyncAdapter {
+    fn read_async(&self, offset: usize, length: usize, done: ReadCompletion) {
+        let mut buffer = vec![0; length];
+        let result = self
+            .0
+            .read(offset, length, &mut buffer)
+            .map_err(|_| "DataSource read failed.");
+        done(result.map(|()| buffer));
+    }
+
+    fn write_async(&self, offset: usize, data: Vec<u8>, done: WriteCompletion) {
+        done(
+            self.0
+                .write(offset, data.len(), &data)
+                .map_err(|_| "DataSource write failed."),
+        );
+    }
+}
+
+/// A page read that's in flight, or has finished.
+struct PageRead {
+    state: Mutex<ReadState>,
+    arrived: Condvar,
+    started: usize, // when the read was started, for dropping the oldest pages first
+}
+
+#[derive(Default)]
+struct ReadState {
+    result: Option<Result<Arc<[u8]>, &'static str>>,
+    wakers: Vec<Waker>,
+}
+
+impl PageRead {
+    fn finish(&self, result: Result<Arc<[u8]>, &'static str>) {
+        let wakers = {
+            let mut state = lock(&self.state);
+            state.result = Some(result);
+            core::mem::take(&mut state.wakers)
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        self.arrived.notify_all();
+    }
+}
+
+/// Reads pages from an `AsyncDataSource`, many at once, and keeps some of the ones that arrive.
+pub struct PageFetcher {
+    source: Arc<dyn AsyncDataSource>,
+    pages: Arc<Mutex<FetchedPages>>,
+    capacity: usize, // arrived pages kept
+}
+
+#[derive(Default)]
+struct FetchedPages {
+    reads: BTreeMap<usize, Arc<PageRead>>, // by page index, in flight or arrived
+    started: usize,
+}
+
+impl FetchedPages {
+    /// Drop the oldest arrived pages until no more than `capacity` are kept.
+    fn trim(&mut self, capacity: usize) {
+        let mut arrived: Vec<(usize, usize)> = self
+            .reads
+            .iter()
+            .filter(|(_, read)| lock(&read.state).result.is_some())
+            .map(|(&index, read)| (read.started, index))
+            .collect();
+        if arrived.len() > capacity {
+            arrived.sort_unstable();
+            for &(_, index) in &arrived[..arrived.len() - capacity] {
+                self.reads.remove(&index);
+            }
+        }
+    }
+}
+
+impl PageFetcher {
+    /// A fetcher that keeps up to `capacity` arrived pages, besides those still in flight.
+    #[must_use]
+    pub fn new(source: Arc<dyn AsyncDataSource>, capacity: usize) -> Self {
+        Self {
+            source,
+            pages: Arc::new(Mutex::new(FetchedPages::default())),
+            capacity,
+        }
+    }
+
+    /// Page `index`, once it's arrived. The read is started now unless it's already in flight or
+    /// done; a read that failed is tried again. A read that doesn't bring back a whole page fails.
+    pub fn fetch(&self, index: usize) -> PageFuture {
+        let read = {
+            let mut pages = lock(&self.pages);
+            match pages.reads.get(&index) {
+                Some(read) if !matches!(lock(&read.state).result, Some(Err(_))) => {
+                    return PageFuture { read: read.clone() };
+                }
+                _ => {}
+            }
+            pages.started += 1;
+            let read = Arc::new(PageRead {
+                state: Mutex::new(ReadState::default()),
+                arrived: Condvar::new(),
+                started: pages.started,
+            });
+            pages.reads.insert(index, read.clone());
+            read
+        };
+        // started with no locks held, as it may complete straight away
+        let done = read.clone();
+        let (pages, capacity) = (self.pages.clone(), self.capacity);
+        self.source.read_async(
+            index * PAGE_SIZE,
+            PAGE_SIZE,
+            Box::new(move |result| {
+                done.finish(result.and_then(|data| match data.len() {
+                    PAGE_SIZE => Ok(Arc::from(data)),
+                    _ => Err("read didn't return a whole page"),
+                }));
+                lock(&pages).trim(capacity);
+            }),
+        );
+        PageFuture { read }
+    }
+
+    /// Number of arrived pages kept.
+    #[must_use]
+    pub fn kept(&self) -> usize {
+        lock(&self.pages)
+            .reads
+            .values()
+            .filter(|read| lock(&read.state).result.is_some())
+            .count()
+    }
+
+    /// Number of pages being read.
+    #[must_use]
+    pub fn in_flight(&self) -> usize {
+        lock(&self.pages)
+            .reads
+            .values()
+            .filter(|read| lock(&read.state).result.is_none())
+            .count()
+    }
+
+    /// Forget page `index`, so that the next fetch reads it again. Anyone already waiting on
+    /// it still gets it.
+    pub fn evict(&self, index: usize) {
+        lock(&self.pages).reads.remove(&index);
+    }
+}
+
+/// Lets a mapping be backed by a `PageFetcher`, so that faults read through it.
+impl DataSource for PageFetcher {
+    /// Starts reading every page the range touches, then waits for them in turn.
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+        let buffer = buffer
+            .get_mut(..length)
+            .ok_or("buffer is shorter than length")?;
+        if length == 0 {
+            return Ok(());
+        }
+        let first = offset / PAGE_SIZE;
+        let last = (offset + length - 1) / PAGE_SIZE;
+        let futures: Vec<PageFuture> = (first..=last).map(|index| self.fetch(index)).collect();
+        let mut copied = 0;
+        for (index, future) in (first..).zip(futures) {
+            let page = future.wait()?;
+            let within = (offset + copied) - index * PAGE_SIZE;
+            let count = (PAGE_SIZE - within).min(length - copied);
+            buffer[copied..copied + count].copy_from_slice(&page[within..within + count]);
+            copied += count;
+        }
+        Ok(())
+    }
+
+    /// Writes through to the source, waiting for it to finish, and forgets the pages written.
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+        let data = buffer
+            .get(..length)
+            .ok_or("buffer is shorter than length")?
+            .to_vec();
+        let (done, finished) = mpsc::channel();
+        self.source.write_async(
+            offset,
+            data,
+            Box::new(move |result| {
+                let _ = done.send(result);
+            }),
+        );
+        let result = finished.recv().unwrap_or(Err("write was dropped"));
+        if length > 0 {
+            for index in offset / PAGE_SIZE..=(offset + length - 1) / PAGE_SIZE {
+                self.evict(index);
+            }
+        }
+        result
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        Ok(())
+    }
+
+    /// Reads and writes both go through to the source, at any offset.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            readable: true,
+            writable: true,
+            executable: false,
+            seekable: true,
+            resizable: false,
+        }
+    }
+}
+
+/// A page on its way from a `PageFetcher`.
+#[derive(Clone)]
+pub struct PageFuture {
+    read: Arc<PageRead>,
+}
+
+impl PageFuture {
+    /// Block until the page arrives.
+    ///
+    /// # Errors
+    /// If the read failed.
+    pub fn wait(&self) -> Result<Arc<[u8]>, &'static str> {
+        let mut state = lock(&self.read.state);
+        loop {
+            if let Some(result) = &state.result {
+                return result.clone();
+            }
+            state = self
+                .read
+                .arrived
+                .wait(state)
+                .expect("Bad things are happening.");
+        }
+    }
+}
+
+impl Future for PageFuture {
+    type Output = Result<Arc<[u8]>, &'static str>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = lock(&self.read.state);
+        match &state.result {
+            Some(result) => Poll::Ready(result.clone()),
+            None => {
+                if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::thread;
+
+    /// An `AsyncDataSource` that holds on to its reads until the test completes them.
+    #[derive(Default)]
+    struct HeldSource {
+        reads: Mutex<Vec<(usize, ReadCompletion)>>,
+    }
+
+    impl HeldSource {
+        /// Complete the oldest read, with every byte set to its page number.
+        fn complete_one(&self) {
+            let (offset, done) = lock(&self.reads).remove(0);
+            done(Ok(vec![(offset / PAGE_SIZE) as u8; PAGE_SIZE]));
+        }
+    }
+
+    impl AsyncDataSource for HeldSource {
+        fn read_async(&self, offset: usize, length: usize, done: ReadCompletion) {
+            lock(&self.reads).push((offset, done));
+        }
+        fn write_async(&self, offset: usize, data: Vec<u8>, done: WriteCompletion) {
+            done(Err("read-only"));
+        }
+    }
+
+    /// Counts how many times it's woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn waiters_on_a_page_share_one_read() {
+        let source = Arc::new(HeldSource::default());
+        let fetcher = PageFetcher::new(source.clone(), 16);
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut first = fetcher.fetch(3);
+        let mut second = fetcher.fetch(3);
+        let mut other = fetcher.fetch(5);
+        assert_eq!(fetcher.in_flight(), 2);
+        assert_eq!(lock(&source.reads).len(), 2);
+        assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut other).poll(&mut cx).is_pending());
+
+        // a blocked waiter and the polled futures all hear about the same read
+        let blocked = {
+            let future = first.clone();
+            thread::spawn(move || future.wait().map(|data| data[0]))
+        };
+        source.complete_one();
+        assert_eq!(blocked.join().unwrap(), Ok(3));
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            Pin::new(&mut second)
+                .poll(&mut cx)
+                .map(|data| data.unwrap()[0]),
+            Poll::Ready(3)
+        );
+        assert_eq!(fetcher.in_flight(), 1);
+
+        // an arrived page is kept until it's evicted
+        assert!(fetcher.fetch(3).wait().is_ok());
+        fetcher.evict(3);
+        let _again = fetcher.fetch(3);
+        assert_eq!(lock(&source.reads).len(), 2);
+    }
+
+    #[test]
+    fn sync_sources_complete_straight_away() {
+        let fetcher = PageFetcher::new(
+            Arc::new(SyncAdapter(Arc::new(crate::AnonymousDataSource))),
+            16,
+        );
+        let waker = Waker::from(Arc::new(CountingWaker::default()));
+        let mut page = fetcher.fetch(1);
+        match Pin::new(&mut page).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Ok(data)) => assert!(data.iter().all(|&byte| byte == 0)),
+            _ => panic!("the read should have finished"),
+        }
+        assert_eq!(fetcher.in_flight(), 0);
+    }
+
+    #[test]
+    fn short_reads_fail() {
+        let source = Arc::new(HeldSource::default());
+        let fetcher = PageFetcher::new(source.clone(), 16);
+        let page = fetcher.fetch(0);
+        let (_, done) = lock(&source.reads).remove(0);
+        done(Ok(vec![0; PAGE_SIZE - 1]));
+        assert!(page.wait().is_err());
+        assert_eq!(fetcher.kept(), 1);
+        // and are tried again
+        let _again = fetcher.fetch(0);
+        assert_eq!(lock(&source.reads).len(), 1);
+    }
+
+    #[test]
+    fn only_the_newest_arrived_pages_are_kept() {
+        let fetcher = PageFetcher::new(
+            Arc::new(SyncAdapter(Arc::new(crate::AnonymousDataSource))),
+            2,
+        );
+        for index in 0..5 {
+            assert!(fetcher.fetch(index).wait().is_ok());
+        }
+        assert_eq!(fetcher.kept(), 2);
+        let pages = lock(&fetcher.pages);
+        assert_eq!(pages.reads.keys().copied().collect::<Vec<_>>(), [3, 4]);
+    }
+
+    #[test]
+    fn faults_read_through_a_fetcher() {
+        use crate::{AddressSpace, FlagBuilder};
+        let file = Arc::new(crate::FileDataSource::new("tests/fixtures/mapped.txt").unwrap());
+        let mut expected = vec![0; 2 * PAGE_SIZE];
+        file.read(PAGE_SIZE, expected.len(), &mut expected).unwrap();
+        let fetcher = Arc::new(PageFetcher::new(Arc::new(SyncAdapter(file)), 16));
+
+        let mut space = AddressSpace::new("fetched");
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let addr = space
+            .add_mapping(fetcher.clone(), PAGE_SIZE, 2 * PAGE_SIZE, flags)
+            .unwrap();
+        let mut buffer = vec![0; 2 * PAGE_SIZE];
+        space.read_bytes(addr, &mut buffer).unwrap();
+        assert_eq!(buffer, expected);
+        assert!(fetcher.kept() >= 1);
+    }
+}
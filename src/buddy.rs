@@ -0,0 +1,357 @@
+// A buddy allocator for physical page frames.
+//
+// Free memory is kept as blocks of 2^order frames, each aligned to its own size, on one free
+// list per order. Allocating takes a block of the smallest order that's big enough, splitting it
+// in halves until it's the size asked for; freeing a block merges it with its buddy, the other
+// half of the block it was split from, for as long as the buddy is free too.
+//
+// The free lists are ordered sets rather than linked lists, so that finding a buddy, or a block
+// at a particular alignment, doesn't mean walking a list.
+//
+// `PhysicalMemory` keeps one of these for each of its nodes, and takes every frame it hands out
+// from them.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::address_space::PAGE_SIZE;
+
+/// The largest order: blocks of 2^18 frames, 1 GiB.
+pub const MAX_ORDER: usize = 18;
+
+/// Number of orders, from single frames up to `MAX_ORDER`.
+pub const ORDERS: usize = MAX_ORDER + 1;
+
+/// Bytes in a block of `order`.
+#[must_use]
+pub const fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order whose blocks hold `bytes`, if there is one.
+#[must_use]
+pub fn order_for(bytes: usize) -> Option<usize> {
+    (0..ORDERS).find(|&order| block_size(order) >= bytes)
+}
+
+/// A snapshot of how free memory is split up. Counts are in frames unless they say otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FragmentationStats {
+    /// Frames in every range the allocator was given, reserved or not.
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub reserved_frames: usize,
+    /// Free blocks of each order.
+    pub free_blocks: [usize; ORDERS],
+}
+
+impl FragmentationStats {
+    /// The biggest order with a free block.
+    #[must_use]
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..ORDERS).rev().find(|&order| self.free_blocks[order] > 0)
+    }
+
+    /// Percentage of free frames that are in blocks too small to satisfy a request of `order`,
+    /// like Linux's unusable free space index. 0 when nothing is free.
+    #[must_use]
+    pub fn unusable_percent(&self, order: usize) -> usize {
+        if self.free_frames == 0 {
+            return 0;
+        }
+        let usable: usize = (order..ORDERS).map(|o| self.free_blocks[o] << o).sum();
+        (self.free_frames - usable) * 100 / self.free_frames
+    }
+}
+
+/// Hands out physical frames from a set of memory ranges, in blocks of 2^order frames.
+pub struct BuddyAllocator {
+    memory: Vec<Range<usize>>,   // the ranges being managed, page aligned
+    reserved: Vec<Range<usize>>, // parts of `memory` never handed out
+    free: [BTreeSet<usize>; ORDERS],
+    allocated: BTreeMap<usize, usize>, // start of every allocated block, to its order
+}
+
+impl BuddyAllocator {
+    /// An allocator over the physical address ranges in `memory`, all free. Ranges are shrunk
+    /// to whole frames.
+    ///
+    /// # Errors
+    /// If any two ranges overlap.
+    pub fn new(memory: &[Range<usize>]) -> Result<Self, &'static str> {
+        let mut aligned: Vec<Range<usize>> = memory
+            .iter()
+            .map(|range| range.start.next_multiple_of(PAGE_SIZE)..range.end / PAGE_SIZE * PAGE_SIZE)
+            .filter(|range| range.start < range.end)
+            .collect();
+        aligned.sort_by_key(|range| range.start);
+        if aligned.windows(2).any(|pair| pair[0].end > pair[1].start) {
+            return Err("Memory ranges overlap.");
+        }
+        let mut allocator = Self {
+            memory: aligned,
+            reserved: Vec::new(),
+            free: core::array::from_fn(|_| BTreeSet::new()),
+            allocated: BTreeMap::new(),
+        };
+        for range in allocator.memory.clone() {
+            allocator.free_range(range);
+        }
+        Ok(allocator)
+    }
+
+    /// Take `range` out of the free memory for good, for the kernel's own use. It's widened to
+    /// whole frames. Parts of it outside the managed memory are ignored.
+    ///
+    /// # Errors
+    /// If any of it has been allocated or reserved already.
+    pub fn reserve(&mut self, range: Range<usize>) -> Result<(), &'static str> {
+        let range = range.start / PAGE_SIZE * PAGE_SIZE..range.end.next_multiple_of(PAGE_SIZE);
+        let in_use = |start: usize, end: usize| start < range.end && range.start < end;
+        if self.reserved.iter().any(|r| in_use(r.start, r.end)) {
+            return Err("Range is already reserved.");
+        }
+        if self
+            .allocated
+            .iter()
+            .any(|(&start, &order)| in_use(start, start + block_size(order)))
+        {
+            return Err("Range is in use.");
+        }
+        for order in 0..ORDERS {
+            let overlapping: Vec<usize> = self.free[order]
+                .iter()
+                .copied()
+                .filter(|&start| in_use(start, start + block_size(order)))
+                .collect();
+            for start in overlapping {
+                self.free[order].remove(&start);
+                let end = start + block_size(order);
+                // the parts of the block outside the range go back as smaller blocks
+                self.free_range(start..range.start.clamp(start, end));
+                self.free_range(range.end.clamp(start, end)..end);
+            }
+        }
+        for memory in &self.memory {
+            let start = range.start.max(memory.start);
+            let end = range.end.min(memory.end);
+            if start < end {
+                self.reserved.push(start..end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a block of 2^`order` frames, returning its physical address.
+    pub fn allocate(&mut self, order: usize) -> Option<usize> {
+        self.allocate_aligned(order, block_size(order))
+    }
+
+    /// Allocate a block of 2^`order` frames whose address is a multiple of `align`, which must
+    /// be a power of two. Blocks are always aligned to their own size, so `align` only matters
+    /// when it's bigger than that.
+    pub fn allocate_aligned(&mut self, order: usize, align: usize) -> Option<usize> {
+        if order > MAX_ORDER || !align.is_power_of_two() {
+            return None;
+        }
+        let (mut current, start) = (order..ORDERS).find_map(|o| {
+            self.free[o]
+                .iter()
+                .find(|&&start| start.is_multiple_of(align))
+                .map(|&start| (o, start))
+        })?;
+        self.free[current].remove(&start);
+        // keep the lower half, which has the alignment, and give back the upper ones
+        while current > order {
+            current -= 1;
+            self.free[current].insert(start + block_size(current));
+        }
+        self.allocated.insert(start, order);
+        Some(start)
+    }
+
+    /// Free the block allocated at `addr`, merging it with its buddies.
+    ///
+    /// # Errors
+    /// If no block was allocated at `addr`.
+    pub fn free(&mut self, addr: usize) -> Result<(), &'static str> {
+        let order = self
+            .allocated
+            .remove(&addr)
+            .ok_or("No block is allocated there.")?;
+        self.free_block(addr, order);
+        Ok(())
+    }
+
+    /// Order of the block allocated at `addr`.
+    #[must_use]
+    pub fn allocated_order(&self, addr: usize) -> Option<usize> {
+        self.allocated.get(&addr).copied()
+    }
+
+    #[must_use]
+    pub fn free_frames(&self) -> usize {
+        (0..ORDERS)
+            .map(|order| self.free[order].len() << order)
+            .sum()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> FragmentationStats {
+        let frames =
+            |ranges: &[Range<usize>]| ranges.iter().map(|r| (r.end - r.start) / PAGE_SIZE).sum();
+        FragmentationStats {
+            total_frames: frames(&self.memory),
+            free_frames: self.free_frames(),
+            reserved_frames: frames(&self.reserved),
+            free_blocks: core::array::from_fn(|order| self.free[order].len()),
+        }
+    }
+
+    /// Make sure the free lists are consistent: every free block is aligned to its size, lies
+    /// inside the managed memory, overlaps no other free, allocated or reserved block, and
+    /// hasn't been left unmerged with a free buddy; and every frame is accounted for.
+    ///
+    /// # Errors
+    /// Which of those doesn't hold.
+    pub fn check(&self) -> Result<(), &'static str> {
+        let mut blocks: Vec<(usize, usize)> = Vec::new(); // (start, end) of every block known
+        for (order, list) in self.free.iter().enumerate() {
+            for &start in list {
+                let end = start + block_size(order);
+                if !start.is_multiple_of(block_size(order)) {
+                    return Err("A free block is misaligned.");
+                }
+                if !self.memory.iter().any(|r| r.start <= start && end <= r.end) {
+                    return Err("A free block is outside the managed memory.");
+                }
+                if order < MAX_ORDER && list.contains(&(start ^ block_size(order))) {
+                    return Err("A free block wasn't merged with its buddy.");
+                }
+                blocks.push((start, end));
+            }
+        }
+        blocks.extend(
+            self.allocated
+                .iter()
+                .map(|(&start, &order)| (start, start + block_size(order))),
+        );
+        blocks.extend(self.reserved.iter().map(|r| (r.start, r.end)));
+        blocks.sort_unstable();
+        if blocks.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+            return Err("Blocks overlap.");
+        }
+        let covered: usize = blocks.iter().map(|(start, end)| end - start).sum();
+        let total: usize = self.memory.iter().map(|r| r.end - r.start).sum();
+        if covered != total {
+            return Err("Some frames are neither free, allocated nor reserved.");
+        }
+        Ok(())
+    }
+
+    /// Put a block back on the free lists, merging it with its buddy as many times as it can.
+    fn free_block(&mut self, mut start: usize, mut order: usize) {
+        while order < MAX_ORDER && self.free[order].remove(&(start ^ block_size(order))) {
+            start &= !block_size(order);
+            order += 1;
+        }
+        self.free[order].insert(start);
+    }
+
+    /// Free every frame of `range`, as the biggest blocks its alignment allows.
+    fn free_range(&mut self, range: Range<usize>) {
+        let mut start = range.start;
+        while start < range.end {
+            let order = (0..ORDERS)
+                .rev()
+                .find(|&order| {
+                    start.is_multiple_of(block_size(order))
+                        && start + block_size(order) <= range.end
+                })
+                .expect("Bad things are happening.");
+            self.free_block(start, order);
+            start += block_size(order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1 << 20;
+
+    #[test]
+    fn split_and_merge() {
+        let memory = 0..MIB;
+        let mut buddy = BuddyAllocator::new(&[memory]).unwrap();
+        assert_eq!(buddy.stats().free_blocks[8], 1);
+
+        let a = buddy.allocate(0).unwrap();
+        let b = buddy.allocate(0).unwrap();
+        assert_eq!(b, a ^ PAGE_SIZE);
+        assert_eq!(buddy.free_frames(), 254);
+        assert_eq!(buddy.stats().free_blocks[..8], [0, 1, 1, 1, 1, 1, 1, 1]);
+        buddy.check().unwrap();
+
+        buddy.free(a).unwrap();
+        assert_eq!(buddy.free(a), Err("No block is allocated there."));
+        buddy.free(b).unwrap();
+        assert_eq!(buddy.stats().free_blocks[8], 1);
+        buddy.check().unwrap();
+    }
+
+    #[test]
+    fn alignment_reservations_and_fragmentation() {
+        // a range that doesn't start on a big boundary is freed as smaller blocks
+        let memory = PAGE_SIZE..2 * MIB + 100;
+        let mut buddy = BuddyAllocator::new(&[memory]).unwrap();
+        assert_eq!(buddy.stats().total_frames, 511);
+        buddy.check().unwrap();
+
+        let aligned = buddy.allocate_aligned(0, MIB).unwrap();
+        assert_eq!(aligned, MIB);
+        assert_eq!(buddy.allocated_order(aligned), Some(0));
+
+        assert_eq!(buddy.reserve(MIB..MIB + 1), Err("Range is in use."));
+        buddy.reserve(16 * PAGE_SIZE..32 * PAGE_SIZE).unwrap();
+        assert_eq!(
+            buddy.reserve(20 * PAGE_SIZE..21 * PAGE_SIZE),
+            Err("Range is already reserved.")
+        );
+        let stats = buddy.stats();
+        assert_eq!(stats.reserved_frames, 16);
+        assert_eq!(stats.free_frames, 511 - 16 - 1);
+        buddy.check().unwrap();
+
+        // nothing as big as 1 MiB is left in one piece
+        assert_eq!(stats.largest_free_order(), Some(7));
+        assert_eq!(stats.unusable_percent(0), 0);
+        assert_eq!(stats.unusable_percent(8), 100);
+        assert!(buddy.allocate(8).is_none());
+        assert!(buddy.allocate(MAX_ORDER + 1).is_none());
+    }
+
+    #[test]
+    fn checker_catches_inconsistencies() {
+        let memory = 0..MIB;
+        let mut buddy = BuddyAllocator::new(&[memory]).unwrap();
+        let a = buddy.allocate(1).unwrap();
+        buddy.free[0].insert(a);
+        assert_eq!(buddy.check(), Err("Blocks overlap."));
+        buddy.free[0].remove(&a);
+        buddy.allocated.remove(&a);
+        assert_eq!(
+            buddy.check(),
+            Err("Some frames are neither free, allocated nor reserved.")
+        );
+        buddy.free[0].insert(a);
+        buddy.free[0].insert(a + PAGE_SIZE);
+        assert_eq!(
+            buddy.check(),
+            Err("A free block wasn't merged with its buddy.")
+        );
+        assert!(BuddyAllocator::new(&[0..MIB, MIB / 2..2 * MIB]).is_err());
+    }
+}
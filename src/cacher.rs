@@ -4,3 +4,453 @@
 // the Physical Page that now has the data to the PageTableEntry of the requesting AddressSpace
 // There could be a further division of labor here, or refactoring, which could simplify things.
 // I'm open to ideas!
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::address_space::{AddressSpace, Advice, PAGE_SIZE};
+use crate::data_source::DataSource;
+use crate::physical::{PageData, Placement};
+
+/// The largest read-ahead window, in pages.
+pub const MAX_READAHEAD: usize = 32;
+
+/// A physical page holding data cached from a `DataSource`.
+#[derive(Clone)]
+pub struct Page {
+    pub data: PageData,
+    pub dirty: bool,
+    /// Fetched by read-ahead and not touched by a fault since.
+    pub speculative: bool,
+    /// Accessed since reclaim last looked at it, like a page table entry's accessed bit.
+    pub referenced: bool,
+    /// Writes go to the user fault handler. See `AddressSpace::userfault_write_protect`.
+    pub write_protected: bool,
+}
+
+/// Per-mapping read-ahead state.
+///
+/// A miss on the page right after the last fetched range counts as sequential and widens the
+/// window, as long as at least half of the pages read ahead last time were actually used; if
+/// they mostly weren't, the window shrinks instead. Any other miss resets the window to a single
+/// page. `Advice::Sequential` and `Advice::Random` override this with the largest and smallest
+/// windows respectively.
+pub struct ReadAhead {
+    /// Page index that would continue the current sequential run.
+    next: Option<usize>,
+    /// Current window size, in pages.
+    window: usize,
+    /// Speculative pages issued by the last fetch.
+    issued: usize,
+    /// How many of those have been touched since.
+    hits: usize,
+}
+
+impl ReadAhead {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            next: None,
+            window: 1,
+            issued: 0,
+            hits: 0,
+        }
+    }
+
+    /// Size the window for a miss on page `index`, returning how many pages to fetch.
+    pub fn on_miss(&mut self, index: usize, advice: Advice) -> usize {
+        if advice == Advice::Sequential {
+            self.window = MAX_READAHEAD;
+        } else if advice == Advice::Random {
+            self.window = 1;
+        } else if self.next == Some(index) {
+            if self.hits * 2 >= self.issued {
+                self.window = (self.window * 2).min(MAX_READAHEAD);
+            } else {
+                self.window = (self.window / 2).max(1);
+            }
+        } else {
+            self.window = 1;
+        }
+        self.window
+    }
+
+    /// Record that `count` pages starting at `index` were fetched.
+    pub fn on_fetch(&mut self, index: usize, count: usize) {
+        self.next = Some(index + count);
+        self.issued = count - 1;
+        self.hits = 0;
+    }
+
+    /// Record a fault on a page that read-ahead brought in.
+    pub fn on_hit(&mut self) {
+        self.hits += 1;
+    }
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fetch `count` consecutive pages starting at `offset` in `source` with one vectored read, into
+/// frames placed by `placement` if it's given.
+///
+/// # Errors
+/// If the `DataSource` read fails, or there aren't enough frames where they may be placed.
+pub fn fetch(
+    source: &dyn DataSource,
+    offset: usize,
+    count: usize,
+    placement: Option<Placement>,
+) -> Result<Vec<Page>, &'static str> {
+    let mut buffers = vec![vec![0; PAGE_SIZE]; count];
+    let mut frames: Vec<&mut [u8]> = buffers.iter_mut().map(Vec::as_mut_slice).collect();
+    source
+        .read_vectored(offset, &mut frames)
+        .map_err(|_| "DataSource read failed.")?;
+    buffers
+        .iter()
+        .map(|buffer| {
+            Ok(Page {
+                data: PageData::new(placement, buffer)?,
+                dirty: false,
+                speculative: false,
+                referenced: false,
+                write_protected: false,
+            })
+        })
+        .collect()
+}
+
+/// Write the dirty pages among `range` back to `source`, where page `i` lives at
+/// `offset + i * PAGE_SIZE`, with one write per run of consecutive dirty pages.
+///
+/// Only the bytes inside the source's current length are written, so a page straddling the end
+/// of a file doesn't make the file longer. Pages that were written are marked clean. A failed
+/// write doesn't stop the remaining runs from being attempted.
+///
+/// # Errors
+/// The first error the `DataSource` reported.
+pub fn write_back<'a>(
+    source: &'a dyn DataSource,
+    offset: usize,
+    pages: &mut BTreeMap<usize, Page>,
+    range: Range<usize>,
+) -> Result<(), &'a str> {
+    let mut first_error = None;
+    let mut run: Vec<(&usize, &mut Page)> = Vec::new();
+    let mut dirty = pages
+        .range_mut(range)
+        .filter(|(_, page)| page.dirty)
+        .peekable();
+    while let Some(entry) = dirty.next() {
+        let next = *entry.0 + 1;
+        run.push(entry);
+        if dirty.peek().is_some_and(|(index, _)| **index == next) {
+            continue;
+        }
+        let start = offset + run[0].0 * PAGE_SIZE;
+        let buffers: Vec<Vec<u8>> = run.iter().map(|(_, page)| page.data.to_vec()).collect();
+        // only the part of the run inside the source goes back, so writing back never grows it
+        let mut room = source
+            .length()
+            .map_or(usize::MAX, |length| length.saturating_sub(start));
+        let frames: Vec<&[u8]> = buffers
+            .iter()
+            .map(|buffer| {
+                let len = buffer.len().min(room);
+                room -= len;
+                &buffer[..len]
+            })
+            .filter(|frame| !frame.is_empty())
+            .collect();
+        let written = if frames.is_empty() {
+            Ok(())
+        } else {
+            source.write_vectored(start, &frames)
+        };
+        match written {
+            Ok(()) => run.iter_mut().for_each(|(_, page)| page.dirty = false),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+        run.clear();
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Free-frame thresholds, in frames, that drive reclaim. See `CacheCoordinator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Below this, a faulting `AddressSpace` reclaims from itself before taking a frame.
+    pub min: usize,
+    /// Below this, background reclaim starts.
+    pub low: usize,
+    /// Background reclaim carries on until this many frames are free.
+    pub high: usize,
+}
+
+/// Keeps count of the frames holding resident pages across every `AddressSpace` that uses it,
+/// and decides when to reclaim them.
+///
+/// Nothing reclaims in the background on its own: the kernel runs `step` from a reclaim thread
+/// whenever `needs_reclaim` says so, and tests call it directly.
+pub struct CacheCoordinator {
+    total: usize,
+    used: AtomicUsize,
+    min: AtomicUsize,
+    low: AtomicUsize,
+    high: AtomicUsize,
+    reclaiming: AtomicBool, // background reclaim started below `low` and hasn't reached `high`
+}
+
+impl CacheCoordinator {
+    /// A coordinator for `total` frames.
+    #[must_use]
+    pub const fn new(total: usize, watermarks: Watermarks) -> Self {
+        Self {
+            total,
+            used: AtomicUsize::new(0),
+            min: AtomicUsize::new(watermarks.min),
+            low: AtomicUsize::new(watermarks.low),
+            high: AtomicUsize::new(watermarks.high),
+            reclaiming: AtomicBool::new(false),
+        }
+    }
+
+    #[must_use]
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    #[must_use]
+    pub fn free_frames(&self) -> usize {
+        self.total.saturating_sub(self.used.load(Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn watermarks(&self) -> Watermarks {
+        Watermarks {
+            min: self.min.load(Ordering::Relaxed),
+            low: self.low.load(Ordering::Relaxed),
+            high: self.high.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_watermarks(&self, watermarks: Watermarks) {
+        self.min.store(watermarks.min, Ordering::Relaxed);
+        self.low.store(watermarks.low, Ordering::Relaxed);
+        self.high.store(watermarks.high, Ordering::Relaxed);
+    }
+
+    /// Should background reclaim run? True from when free frames fall below the low watermark
+    /// until `step` has brought them back up to the high one.
+    #[must_use]
+    pub fn needs_reclaim(&self) -> bool {
+        self.reclaiming.load(Ordering::Relaxed)
+            || self.free_frames() < self.low.load(Ordering::Relaxed)
+    }
+
+    /// Are free frames so short that faults must reclaim for themselves?
+    #[must_use]
+    pub fn below_min(&self) -> bool {
+        self.free_frames() < self.min.load(Ordering::Relaxed)
+    }
+
+    /// One round of background reclaim: if it's needed, scan up to `scan` pages across `spaces`,
+    /// in order, evicting what can be until the high watermark is reached. Spaces in memory
+    /// groups over their soft limit are reclaimed from first, down to that limit. Returns how
+    /// many pages were evicted.
+    pub fn step(&self, spaces: &mut [&mut AddressSpace], scan: usize) -> usize {
+        if !self.needs_reclaim() {
+            return 0;
+        }
+        self.reclaiming.store(true, Ordering::Relaxed);
+        let high = self.high.load(Ordering::Relaxed);
+        let mut scanned = 0;
+        let mut evicted = 0;
+        for space in spaces.iter_mut() {
+            let Some(group) = space
+                .memory_group()
+                .filter(|group| group.over_soft_limit())
+                .cloned()
+            else {
+                continue;
+            };
+            if scanned >= scan || self.free_frames() >= high {
+                break;
+            }
+            let (space_scanned, space_evicted) = space.shrink_lists(scan - scanned, || {
+                self.free_frames() >= high || !group.over_soft_limit()
+            });
+            group.note_reclaimed(space_evicted);
+            scanned += space_scanned;
+            evicted += space_evicted;
+        }
+        for space in spaces.iter_mut() {
+            if scanned >= scan || self.free_frames() >= high {
+                break;
+            }
+            let (space_scanned, space_evicted) =
+                space.shrink_lists(scan - scanned, || self.free_frames() >= high);
+            scanned += space_scanned;
+            evicted += space_evicted;
+        }
+        if self.free_frames() >= high {
+            self.reclaiming.store(false, Ordering::Relaxed);
+        }
+        evicted
+    }
+
+    pub(crate) fn take(&self, frames: usize) {
+        self.used.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub(crate) fn give_back(&self, frames: usize) {
+        self.used.fetch_sub(frames, Ordering::Relaxed);
+    }
+}
+
+/// Which of the `LruLists` a page is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LruList {
+    ActiveFile,
+    InactiveFile,
+    ActiveAnon,
+    InactiveAnon,
+}
+
+impl LruList {
+    const fn index(self) -> usize {
+        match self {
+            LruList::ActiveFile => 0,
+            LruList::InactiveFile => 1,
+            LruList::ActiveAnon => 2,
+            LruList::InactiveAnon => 3,
+        }
+    }
+
+    #[must_use]
+    pub const fn active(self) -> Self {
+        match self {
+            LruList::ActiveFile | LruList::InactiveFile => LruList::ActiveFile,
+            LruList::ActiveAnon | LruList::InactiveAnon => LruList::ActiveAnon,
+        }
+    }
+
+    #[must_use]
+    pub const fn inactive(self) -> Self {
+        match self {
+            LruList::ActiveFile | LruList::InactiveFile => LruList::InactiveFile,
+            LruList::ActiveAnon | LruList::InactiveAnon => LruList::InactiveAnon,
+        }
+    }
+}
+
+/// Active and inactive lists of resident pages, by address, for file and anonymous memory.
+///
+/// New pages start at the back of an inactive list. Reclaim takes pages from the front: one
+/// that's been referenced since moves to the active list, and one that hasn't is evicted. The
+/// active lists are aged into the inactive ones to keep them from growing bigger.
+///
+/// Moving or removing a page doesn't search the lists; the page's old place is just left to be
+/// skipped when it comes up. Each place is stamped with when it was taken, so a page that leaves
+/// a list and comes back is only found at its new place. A list is swept of old places once they
+/// outnumber the pages on it.
+#[derive(Default)]
+pub struct LruLists {
+    lists: [VecDeque<(usize, usize)>; 4], // (address, stamp)
+    lengths: [usize; 4],
+    on: BTreeMap<usize, (LruList, usize)>, // the list each page is on, and its place's stamp
+    stamp: usize,
+}
+
+impl LruLists {
+    /// Put the page at `addr` on the back of `list`, taking it off any list it was on.
+    pub fn push(&mut self, addr: usize, list: LruList) {
+        self.remove(addr);
+        self.stamp += 1;
+        self.on.insert(addr, (list, self.stamp));
+        self.lists[list.index()].push_back((addr, self.stamp));
+        self.lengths[list.index()] += 1;
+    }
+
+    pub fn remove(&mut self, addr: usize) {
+        if let Some((list, _)) = self.on.remove(&addr) {
+            self.lengths[list.index()] -= 1;
+            self.compact(list);
+        }
+    }
+
+    /// Number of pages on `list`.
+    #[must_use]
+    pub fn len(&self, list: LruList) -> usize {
+        self.lengths[list.index()]
+    }
+
+    /// Take the page at the front of `list`.
+    pub fn pop(&mut self, list: LruList) -> Option<usize> {
+        while let Some((addr, stamp)) = self.lists[list.index()].pop_front() {
+            if self.on.get(&addr) == Some(&(list, stamp)) {
+                self.remove(addr);
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    /// Drop the places left behind on `list` once there are more of them than pages on it.
+    fn compact(&mut self, list: LruList) {
+        let places = &mut self.lists[list.index()];
+        if places.len() > 2 * self.lengths[list.index()] {
+            let on = &self.on;
+            places.retain(|&(addr, stamp)| on.get(&addr) == Some(&(list, stamp)));
+        }
+    }
+
+    /// Move the page at `addr` to the back of the active list of its kind.
+    pub fn activate(&mut self, addr: usize, list: LruList) {
+        self.push(addr, list.active());
+    }
+
+    /// Move the page at `addr` to the back of the inactive list of its kind.
+    pub fn deactivate(&mut self, addr: usize, list: LruList) {
+        self.push(addr, list.inactive());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_lists_skip_and_sweep_old_places() {
+        let mut lru = LruLists::default();
+        for addr in 0..4 {
+            lru.push(addr, LruList::InactiveAnon);
+        }
+        // a page that leaves and comes back is behind the pages that stayed
+        lru.remove(0);
+        lru.push(0, LruList::InactiveAnon);
+        lru.activate(1, LruList::InactiveAnon);
+        assert_eq!(lru.len(LruList::InactiveAnon), 3);
+        assert_eq!(lru.pop(LruList::InactiveAnon), Some(2));
+        assert_eq!(lru.pop(LruList::InactiveAnon), Some(3));
+        assert_eq!(lru.pop(LruList::InactiveAnon), Some(0));
+        assert_eq!(lru.pop(LruList::InactiveAnon), None);
+
+        // moving pages back and forth doesn't make the lists grow
+        for _ in 0..1000 {
+            lru.deactivate(1, LruList::ActiveAnon);
+            lru.activate(1, LruList::InactiveAnon);
+        }
+        assert!(lru.lists.iter().map(VecDeque::len).sum::<usize>() <= 2);
+        assert_eq!(lru.pop(LruList::ActiveAnon), Some(1));
+    }
+}
@@ -0,0 +1,121 @@
+// Commit accounting: how much memory every address space together has been promised.
+//
+// Private writable and anonymous mappings may need a fresh page for every page they cover, so
+// they're charged against a `CommitAccountant` shared by all the address spaces when they're
+// created, and the charge is given back when they're unmapped. Whether a charge can go over the
+// limit depends on the overcommit policy, after Linux's `vm.overcommit_memory`.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// A charge was refused by the overcommit policy.
+pub const COMMIT_LIMIT: &str = "Not enough memory to commit to the mapping.";
+
+/// When to refuse charges, like `vm.overcommit_memory`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overcommit {
+    /// Refuse only a single charge bigger than the limit, which could never be satisfied.
+    #[default]
+    Heuristic,
+    /// Never refuse.
+    Always,
+    /// Refuse any charge that would take the total over the limit. Mappings asking not to be
+    /// charged are charged anyway.
+    Never,
+}
+
+impl Overcommit {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Overcommit::Always,
+            2 => Overcommit::Never,
+            _ => Overcommit::Heuristic,
+        }
+    }
+
+    const fn to_u8(self) -> u8 {
+        match self {
+            Overcommit::Heuristic => 0,
+            Overcommit::Always => 1,
+            Overcommit::Never => 2,
+        }
+    }
+}
+
+/// Keeps count of the pages committed to across every `AddressSpace` it's shared with. See
+/// `AddressSpace::set_commit_accountant`.
+pub struct CommitAccountant {
+    limit: AtomicUsize, // in pages
+    committed: AtomicUsize,
+    policy: AtomicU8,
+}
+
+impl CommitAccountant {
+    /// An accountant allowing `limit` pages to be committed under `policy`.
+    #[must_use]
+    pub const fn new(limit: usize, policy: Overcommit) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            committed: AtomicUsize::new(0),
+            policy: AtomicU8::new(policy.to_u8()),
+        }
+    }
+
+    /// Pages currently committed.
+    #[must_use]
+    pub fn committed(&self) -> usize {
+        self.committed.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn policy(&self) -> Overcommit {
+        Overcommit::from_u8(self.policy.load(Ordering::Relaxed))
+    }
+
+    pub fn set_policy(&self, policy: Overcommit) {
+        self.policy.store(policy.to_u8(), Ordering::Relaxed);
+    }
+
+    /// Charge `pages` pages, returning whether they were charged. With `noreserve`, nothing is
+    /// charged unless the policy is `Overcommit::Never`.
+    ///
+    /// # Errors
+    /// If the policy refuses the charge.
+    pub fn charge(&self, pages: usize, noreserve: bool) -> Result<bool, &'static str> {
+        let policy = self.policy();
+        if noreserve && policy != Overcommit::Never {
+            return Ok(false);
+        }
+        let limit = self.limit();
+        self.committed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |committed| {
+                let total = committed.checked_add(pages)?;
+                match policy {
+                    Overcommit::Always => Some(total),
+                    Overcommit::Heuristic => (pages <= limit).then_some(total),
+                    Overcommit::Never => (total <= limit).then_some(total),
+                }
+            })
+            .map(|_| true)
+            .map_err(|_| COMMIT_LIMIT)
+    }
+
+    /// Give back `pages` pages charged earlier.
+    pub fn uncharge(&self, pages: usize) {
+        self.committed.fetch_sub(pages, Ordering::Relaxed);
+    }
+
+    /// Take back `pages` pages given back by a mapping that's been put back, whatever the
+    /// policy.
+    pub(crate) fn restore(&self, pages: usize) {
+        self.committed.fetch_add(pages, Ordering::Relaxed);
+    }
+}
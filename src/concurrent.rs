@@ -0,0 +1,766 @@
+// An address space that many threads can share.
+//
+// The mappings live in a tree behind a reader-writer lock, and each mapping has its own mutex
+// over its flags and resident pages. Faults and lookups only read the tree, long enough to find
+// their mapping, and then work under that mapping's lock. Mapping, unmapping and protecting
+// write the tree just long enough to swap the affected mappings for fresh ones, so they don't
+// wait on I/O for faults elsewhere in the address space. They do lock the whole tree while they
+// do it, not just the range they change, so they hold up every lookup for that long, and each
+// other.
+//
+// Nothing is ever locked while a DataSource is being read. A fault that misses marks the pages
+// it's fetching as loading and drops the mapping lock for the I/O; other faults on those pages
+// wait for the load to finish instead of starting their own. Swapping a mapping out retires the
+// old one, so a fault that finishes its I/O on a retired mapping throws the data away and looks
+// its address up again.
+
+use std::collections::BTreeMap;
+use std::string::{String, ToString};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::vec::Vec;
+
+use crate::address_space::{AddressSpace, Advice, FlagBuilder, PAGE_SIZE};
+use crate::cacher::{self, Page, ReadAhead};
+use crate::data_source::DataSource;
+
+type VirtualAddress = usize;
+
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("Bad things are happening.")
+}
+
+/// Pages a fault is reading in. Other faults on them wait until it's done.
+struct PageLoad {
+    done: Mutex<bool>,
+    arrived: Condvar,
+}
+
+impl PageLoad {
+    fn new() -> Self {
+        Self {
+            done: Mutex::new(false),
+            arrived: Condvar::new(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut done = lock(&self.done);
+        while !*done {
+            done = self.arrived.wait(done).expect("Bad things are happening.");
+        }
+    }
+
+    fn finish(&self) {
+        *lock(&self.done) = true;
+        self.arrived.notify_all();
+    }
+}
+
+enum Slot {
+    Loading(Arc<PageLoad>),
+    Resident(Page),
+}
+
+struct MappingState {
+    flags: FlagBuilder,
+    pages: BTreeMap<usize, Slot>, // keyed by page index within the mapping
+    readahead: ReadAhead,
+    retired: bool, // replaced in the tree; faults should look their address up again
+}
+
+struct SharedMapping {
+    source: Arc<dyn DataSource>,
+    offset: usize,
+    addr: VirtualAddress,
+    span: usize,
+    state: Mutex<MappingState>,
+}
+
+impl SharedMapping {
+    fn new(
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        addr: VirtualAddress,
+        span: usize,
+        flags: FlagBuilder,
+        pages: BTreeMap<usize, Slot>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            source,
+            offset,
+            addr,
+            span,
+            state: Mutex::new(MappingState {
+                flags,
+                pages,
+                readahead: ReadAhead::new(),
+                retired: false,
+            }),
+        })
+    }
+
+    fn page_count(&self) -> usize {
+        self.span / PAGE_SIZE
+    }
+
+    fn overlaps(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        self.addr < end && start < self.addr + self.span
+    }
+}
+
+/// An address space whose mappings can be faulted on and changed from many threads at once.
+///
+/// This covers the core of `AddressSpace`: mapping, unmapping, protecting, faulting and copying
+/// bytes in and out.
+pub struct ConcurrentAddressSpace {
+    name: String,
+    mappings: RwLock<BTreeMap<VirtualAddress, Arc<SharedMapping>>>, // keyed by start address
+}
+
+impl ConcurrentAddressSpace {
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            mappings: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add a mapping from a `DataSource`, leaving a free page either side of it, like
+    /// `AddressSpace::add_mapping`.
+    ///
+    /// # Errors
+    /// If there's no room for the mapping, or `source` can't back it.
+    pub fn add_mapping(
+        &self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        flags: FlagBuilder,
+    ) -> Result<VirtualAddress, &str> {
+        let span = span
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or("No memory chunk available.")?;
+        AddressSpace::check_source(source.as_ref(), offset, span, flags)?;
+        let mut tree = self.mappings.write().expect("Bad things are happening.");
+        let mut start = PAGE_SIZE;
+        for mapping in tree.values() {
+            if mapping.addr >= start && mapping.addr - start >= span + PAGE_SIZE {
+                break;
+            }
+            start = mapping.addr + mapping.span + PAGE_SIZE;
+        }
+        if start.checked_add(span).is_none() {
+            return Err("No memory chunk available.");
+        }
+        tree.insert(
+            start,
+            SharedMapping::new(source, offset, start, span, flags, BTreeMap::new()),
+        );
+        Ok(start)
+    }
+
+    /// Add a mapping from a `DataSource` at `start`, keeping a free page between it and the
+    /// mappings either side, like `AddressSpace::add_mapping_at`. The whole tree is locked while
+    /// it's added.
+    ///
+    /// # Errors
+    /// If the mapping would come within a page of another one, or `source` can't back it.
+    pub fn add_mapping_at(
+        &self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        flags: FlagBuilder,
+    ) -> Result<(), &str> {
+        let span = span
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or("No memory chunk available.")?;
+        let end = start
+            .checked_add(span)
+            .ok_or("No memory chunk available.")?;
+        AddressSpace::check_source(source.as_ref(), offset, span, flags)?;
+        let mut tree = self.mappings.write().expect("Bad things are happening.");
+        let prev_end = tree
+            .range(..start)
+            .next_back()
+            .map_or(0, |(_, mapping)| mapping.addr + mapping.span);
+        let next = tree.range(start..).next().map(|(_, mapping)| mapping.addr);
+        if prev_end >= start.saturating_sub(PAGE_SIZE)
+            || next.is_some_and(|next| end.checked_add(PAGE_SIZE).is_none_or(|limit| next < limit))
+        {
+            return Err("Insufficient free memory in desired region.");
+        }
+        tree.insert(
+            start,
+            SharedMapping::new(source, offset, start, span, flags, BTreeMap::new()),
+        );
+        Ok(())
+    }
+
+    /// Remove every page touched by `[start, start + len)`, like `AddressSpace::remove_range`.
+    /// The whole tree is locked while the mappings are taken out, not just the range. Dirty
+    /// pages of shared mappings are written back once the mappings are out of the tree.
+    ///
+    /// # Errors
+    /// If the range wraps around the address space.
+    pub fn remove_range(&self, start: VirtualAddress, len: usize) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        let removed = {
+            let mut tree = self.mappings.write().expect("Bad things are happening.");
+            let removed = Self::carve(&mut tree, start, end);
+            for mapping in &removed {
+                tree.remove(&mapping.addr);
+            }
+            removed
+        };
+        for mapping in removed {
+            let mut state = lock(&mapping.state);
+            state.retired = true;
+            if state.flags.shared && !mapping.source.is_anonymous() {
+                let mut pages = resident_pages(&mut state.pages);
+                let _ = cacher::write_back(
+                    mapping.source.as_ref(),
+                    mapping.offset,
+                    &mut pages,
+                    0..mapping.page_count(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the read, write and execute permissions of every page touched by
+    /// `[start, start + len)`, like `AddressSpace::protect`. The whole tree is locked while
+    /// they're changed, not just the range.
+    ///
+    /// # Errors
+    /// If any part of the range is unmapped, the resulting flags wouldn't be valid, or a
+    /// mapping's source doesn't allow `perms`.
+    pub fn protect(
+        &self,
+        start: VirtualAddress,
+        len: usize,
+        perms: FlagBuilder,
+    ) -> Result<(), &str> {
+        let end = start
+            .checked_add(len)
+            .ok_or("Range wraps around the address space.")?;
+        let mut tree = self.mappings.write().expect("Bad things are happening.");
+        let mut covered = start;
+        for mapping in tree.values().filter(|mapping| mapping.overlaps(start, end)) {
+            if mapping.addr > covered {
+                break;
+            }
+            covered = mapping.addr + mapping.span;
+        }
+        if covered < end {
+            return Err("Range is not entirely mapped.");
+        }
+        for mapping in tree.values().filter(|mapping| mapping.overlaps(start, end)) {
+            if !lock(&mapping.state).flags.with_perms(perms).is_valid() {
+                return Err("Invalid flags for mapping.");
+            }
+        }
+        for mapping in tree.values().filter(|mapping| mapping.overlaps(start, end)) {
+            AddressSpace::check_source_flags(
+                mapping.source.as_ref(),
+                lock(&mapping.state).flags.with_perms(perms),
+            )?;
+        }
+        for mapping in Self::carve(&mut tree, start, end) {
+            let mut state = lock(&mapping.state);
+            state.flags = state.flags.with_perms(perms);
+        }
+        Ok(())
+    }
+
+    /// Look up the `DataSource` for `addr` and the offset in it that the mapping holding `addr`
+    /// starts at, if `access_type` is allowed, like `AddressSpace::get_source_for_addr`.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped or the access isn't allowed.
+    pub fn get_source_for_addr(
+        &self,
+        addr: VirtualAddress,
+        access_type: FlagBuilder,
+    ) -> Result<(Arc<dyn DataSource>, usize), &str> {
+        let mapping = self.lookup(addr)?;
+        if !lock(&mapping.state).flags.check_access_perms(access_type) {
+            return Err("Given access type is not allowed for the data source at target address.");
+        }
+        Ok((mapping.source.clone(), mapping.offset))
+    }
+
+    /// Is the page containing `addr` currently resident?
+    #[must_use]
+    pub fn is_resident(&self, addr: VirtualAddress) -> bool {
+        self.lookup(addr).is_ok_and(|mapping| {
+            let index = (addr - mapping.addr) / PAGE_SIZE;
+            matches!(
+                lock(&mapping.state).pages.get(&index),
+                Some(Slot::Resident(_))
+            )
+        })
+    }
+
+    /// Resolve a page fault at `addr`, like `AddressSpace::fault`. Faults on different pages
+    /// proceed in parallel, and concurrent faults on a page that's being read wait for that one
+    /// read rather than issuing their own.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, the access isn't allowed, or the `DataSource` read fails.
+    pub fn fault(&self, addr: VirtualAddress, access_type: FlagBuilder) -> Result<(), &str> {
+        self.with_page(addr, access_type, |_, _| ())
+    }
+
+    /// Copy bytes out starting at `addr` to fill `buffer`, like `AddressSpace::read_bytes`.
+    ///
+    /// # Errors
+    /// If some byte can't be read, along with how many bytes were copied before it.
+    pub fn read_bytes(&self, addr: VirtualAddress, buffer: &mut [u8]) -> Result<(), (usize, &str)> {
+        let mut copied = 0;
+        while copied < buffer.len() {
+            let at = addr
+                .checked_add(copied)
+                .ok_or((copied, "No mapping with target address."))?;
+            let count = (PAGE_SIZE - at % PAGE_SIZE).min(buffer.len() - copied);
+            self.with_page(at, FlagBuilder::read(), |page, within| {
+                let count = count.min(PAGE_SIZE - within);
+                page.data.read(within, &mut buffer[copied..copied + count]);
+                copied += count;
+            })
+            .map_err(|e| (copied, e))?;
+        }
+        Ok(())
+    }
+
+    /// Copy `buffer` in starting at `addr`, like `AddressSpace::write_bytes`.
+    ///
+    /// # Errors
+    /// If some byte can't be written, along with how many bytes were copied before it.
+    pub fn write_bytes(&self, addr: VirtualAddress, buffer: &[u8]) -> Result<(), (usize, &str)> {
+        let mut copied = 0;
+        while copied < buffer.len() {
+            let at = addr
+                .checked_add(copied)
+                .ok_or((copied, "No mapping with target address."))?;
+            let count = (PAGE_SIZE - at % PAGE_SIZE).min(buffer.len() - copied);
+            self.with_page(at, FlagBuilder::write(), |page, within| {
+                let count = count.min(PAGE_SIZE - within);
+                page.data.write(within, &buffer[copied..copied + count]);
+                copied += count;
+            })
+            .map_err(|e| (copied, e))?;
+        }
+        Ok(())
+    }
+
+    /// Find the mapping holding `addr`, holding the tree lock only while looking.
+    fn lookup(&self, addr: VirtualAddress) -> Result<Arc<SharedMapping>, &'static str> {
+        let tree = self.mappings.read().expect("Bad things are happening.");
+        tree.range(..=addr)
+            .next_back()
+            .map(|(_, mapping)| mapping)
+            .filter(|mapping| addr - mapping.addr < mapping.span)
+            .cloned()
+            .ok_or("No mapping with target address.")
+    }
+
+    /// Fault in the page holding `addr` for `access_type`, then call `f` with it and the offset
+    /// of `addr` within it while the mapping is locked.
+    fn with_page<F>(
+        &self,
+        addr: VirtualAddress,
+        access_type: FlagBuilder,
+        f: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut Page, usize),
+    {
+        loop {
+            let mapping = self.lookup(addr)?;
+            let index = (addr - mapping.addr) / PAGE_SIZE;
+            let mut state = lock(&mapping.state);
+            if state.retired {
+                continue;
+            }
+            if !state.flags.check_access_perms(access_type) {
+                return Err(
+                    "Given access type is not allowed for the data source at target address.",
+                );
+            }
+            match state.pages.get_mut(&index) {
+                Some(Slot::Resident(page)) => {
+                    let hit = core::mem::take(&mut page.speculative);
+                    page.dirty |= access_type.write;
+                    f(page, (addr - mapping.addr) % PAGE_SIZE);
+                    if hit {
+                        state.readahead.on_hit();
+                    }
+                    return Ok(());
+                }
+                Some(Slot::Loading(load)) => {
+                    let load = load.clone();
+                    drop(state);
+                    load.wait();
+                }
+                None => {
+                    let window = state.readahead.on_miss(index, Advice::Normal);
+                    let count = (index..mapping.page_count())
+                        .take(window)
+                        .take_while(|i| !state.pages.contains_key(i))
+                        .count();
+                    let load = Arc::new(PageLoad::new());
+                    for i in index..index + count {
+                        state.pages.insert(i, Slot::Loading(load.clone()));
+                    }
+                    drop(state);
+
+                    let fetched = cacher::fetch(
+                        mapping.source.as_ref(),
+                        mapping.offset + index * PAGE_SIZE,
+                        count,
+                        None,
+                    );
+
+                    let mut state = lock(&mapping.state);
+                    let result = match fetched {
+                        Ok(pages) if !state.retired => {
+                            state.readahead.on_fetch(index, count);
+                            for (i, mut page) in (index..).zip(pages) {
+                                page.speculative = i != index;
+                                state.pages.insert(i, Slot::Resident(page));
+                            }
+                            Ok(())
+                        }
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            if !state.retired {
+                                for i in index..index + count {
+                                    state.pages.remove(&i);
+                                }
+                            }
+                            Err(e)
+                        }
+                    };
+                    drop(state);
+                    load.finish();
+                    result?;
+                }
+            }
+        }
+    }
+
+    /// Replace every mapping that `[start, end)` touches with fresh pieces split at the range's
+    /// page boundaries, retiring the old ones, and return the pieces inside the range.
+    fn carve(
+        tree: &mut BTreeMap<VirtualAddress, Arc<SharedMapping>>,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Vec<Arc<SharedMapping>> {
+        let affected: Vec<_> = tree
+            .values()
+            .filter(|mapping| mapping.overlaps(start, end))
+            .cloned()
+            .collect();
+        let mut inside = Vec::new();
+        for old in affected {
+            let mut state = lock(&old.state);
+            state.retired = true;
+            let mut pages = resident_slots(&mut state.pages);
+            let first = start.saturating_sub(old.addr) / PAGE_SIZE;
+            let last = (end - old.addr).div_ceil(PAGE_SIZE).min(old.page_count());
+            tree.remove(&old.addr);
+            for (from, to) in [(0, first), (first, last), (last, old.page_count())] {
+                if from == to {
+                    continue;
+                }
+                let rest = pages.split_off(&to);
+                let piece_pages = pages
+                    .into_iter()
+                    .map(|(i, slot)| (i - from, slot))
+                    .collect();
+                pages = rest;
+                let piece = SharedMapping::new(
+                    old.source.clone(),
+                    old.offset + from * PAGE_SIZE,
+                    old.addr + from * PAGE_SIZE,
+                    (to - from) * PAGE_SIZE,
+                    state.flags,
+                    piece_pages,
+                );
+                tree.insert(piece.addr, piece.clone());
+                if from == first {
+                    inside.push(piece);
+                }
+            }
+        }
+        inside
+    }
+}
+
+/// Take the resident pages out of `slots`, leaving any that are still loading.
+fn resident_slots(slots: &mut BTreeMap<usize, Slot>) -> BTreeMap<usize, Slot> {
+    let (resident, loading) = core::mem::take(slots)
+        .into_iter()
+        .partition(|(_, slot)| matches!(slot, Slot::Resident(_)));
+    *slots = loading;
+    resident
+}
+
+/// Take the resident pages out of `slots` as plain pages.
+fn resident_pages(slots: &mut BTreeMap<usize, Slot>) -> BTreeMap<usize, Page> {
+    resident_slots(slots)
+        .into_iter()
+        .filter_map(|(i, slot)| match slot {
+            Slot::Resident(page) => Some((i, page)),
+            Slot::Loading(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// A `DataSource` whose reads block until the test lets them through.
+    struct GatedSource {
+        started: Mutex<mpsc::Sender<usize>>,
+        gate: Mutex<mpsc::Receiver<()>>,
+        reads: Mutex<usize>,
+    }
+
+    impl GatedSource {
+        fn new() -> (Arc<Self>, mpsc::Receiver<usize>, mpsc::Sender<()>) {
+            let (started_tx, started_rx) = mpsc::channel();
+            let (gate_tx, gate_rx) = mpsc::channel();
+            let source = Arc::new(Self {
+                started: Mutex::new(started_tx),
+                gate: Mutex::new(gate_rx),
+                reads: Mutex::new(0),
+            });
+            (source, started_rx, gate_tx)
+        }
+    }
+
+    impl DataSource for GatedSource {
+        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+            *lock(&self.reads) += 1;
+            lock(&self.started).send(offset).unwrap();
+            lock(&self.gate).recv().unwrap();
+            buffer[..length].fill(7);
+            Ok(())
+        }
+        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+            Err("read-only")
+        }
+        fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_io_blocks_nothing_else() {
+        let space = Arc::new(ConcurrentAddressSpace::new("threads"));
+        let (slow, started, gate) = GatedSource::new();
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let slow_addr = space
+            .add_mapping(slow.clone(), 0, 4 * PAGE_SIZE, flags)
+            .unwrap();
+        let other = space
+            .add_mapping(
+                Arc::new(crate::AnonymousDataSource),
+                0,
+                4 * PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+
+        let faulting = {
+            let space = space.clone();
+            thread::spawn(move || space.fault(slow_addr, FlagBuilder::read()).is_ok())
+        };
+        started.recv().unwrap();
+
+        // while that read is stuck, the rest of the address space carries on
+        space.write_bytes(other, b"busy").unwrap();
+        space
+            .protect(other, PAGE_SIZE, FlagBuilder::read())
+            .unwrap();
+        space
+            .remove_range(other + 2 * PAGE_SIZE, PAGE_SIZE)
+            .unwrap();
+        let added = space
+            .add_mapping(Arc::new(crate::AnonymousDataSource), 0, PAGE_SIZE, flags)
+            .unwrap();
+        space.fault(added, FlagBuilder::write()).unwrap();
+
+        gate.send(()).unwrap();
+        assert!(faulting.join().unwrap());
+        assert!(space.is_resident(slow_addr));
+        assert!(space.write_bytes(other, b"no").is_err());
+    }
+
+    #[test]
+    fn concurrent_faults_share_one_read() {
+        let space = Arc::new(ConcurrentAddressSpace::new("threads"));
+        let (slow, started, gate) = GatedSource::new();
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let addr = space
+            .add_mapping(slow.clone(), 0, PAGE_SIZE, flags)
+            .unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let space = space.clone();
+                thread::spawn(move || {
+                    let mut byte = [0];
+                    space.read_bytes(addr + i, &mut byte).unwrap();
+                    byte[0]
+                })
+            })
+            .collect();
+        started.recv().unwrap();
+        gate.send(()).unwrap();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 7);
+        }
+        assert_eq!(*lock(&slow.reads), 1);
+    }
+
+    #[test]
+    fn lookups_agree_with_address_space() {
+        let space = ConcurrentAddressSpace::new("threads");
+        let mut single = crate::AddressSpace::new("single");
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let source: Arc<dyn DataSource> = Arc::new(crate::AnonymousDataSource);
+        space
+            .add_mapping_at(source.clone(), 0x1000, 2 * PAGE_SIZE, 0x30_0000, flags)
+            .unwrap();
+        single
+            .add_mapping_at(source.clone(), 0x1000, 2 * PAGE_SIZE, 0x30_0000, flags)
+            .unwrap();
+
+        for addr in [0x30_0000, 0x30_1000, 0x30_1fff] {
+            let (from_space, offset) = space
+                .get_source_for_addr(addr, FlagBuilder::read())
+                .unwrap();
+            let (from_single, single_offset) = single
+                .get_source_for_addr::<crate::AnonymousDataSource>(addr, FlagBuilder::read())
+                .unwrap();
+            assert_eq!(offset, single_offset);
+            assert_eq!(from_space.id(), from_single.id());
+        }
+        assert!(space
+            .get_source_for_addr(0x30_1000, FlagBuilder::write())
+            .is_err());
+        assert!(single
+            .get_source_for_addr::<crate::AnonymousDataSource>(0x30_1000, FlagBuilder::write())
+            .is_err());
+    }
+
+    #[test]
+    fn mappings_must_suit_their_source() {
+        let space = ConcurrentAddressSpace::new("threads");
+        let read_only: Arc<dyn DataSource> =
+            Arc::new(crate::FileDataSource::new("Cargo.toml").unwrap());
+        let shared_write = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+        let read = FlagBuilder::new().toggle_read().toggle_private();
+
+        assert_eq!(
+            space.add_mapping(read_only.clone(), 0, PAGE_SIZE, shared_write),
+            Err(crate::NOT_PERMITTED)
+        );
+        assert_eq!(
+            space.add_mapping_at(read_only.clone(), 1 << 20, PAGE_SIZE, 0x10_0000, read),
+            Err(crate::PAST_END)
+        );
+        let addr = space
+            .add_mapping(
+                read_only,
+                0,
+                PAGE_SIZE,
+                FlagBuilder::new().toggle_read().toggle_shared(),
+            )
+            .unwrap();
+        assert_eq!(
+            space.protect(
+                addr,
+                PAGE_SIZE,
+                FlagBuilder::new().toggle_read().toggle_write()
+            ),
+            Err(crate::NOT_PERMITTED)
+        );
+    }
+
+    #[test]
+    fn placement_and_protection_rules_match_address_space() {
+        let space = ConcurrentAddressSpace::new("threads");
+        let mut single = AddressSpace::new("single");
+        let anon: Arc<dyn DataSource> = Arc::new(crate::AnonymousDataSource);
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        space
+            .add_mapping_at(anon.clone(), 0, PAGE_SIZE, 0x10_0000, flags)
+            .unwrap();
+        single
+            .add_mapping_at(anon.clone(), 0, PAGE_SIZE, 0x10_0000, flags)
+            .unwrap();
+        for start in (0x0f_d000..0x10_4000)
+            .step_by(PAGE_SIZE)
+            .filter(|&start| start != 0x10_0000)
+        {
+            assert_eq!(
+                space
+                    .add_mapping_at(anon.clone(), 0, PAGE_SIZE, start, flags)
+                    .is_ok(),
+                single
+                    .add_mapping_at(anon.clone(), 0, PAGE_SIZE, start, flags)
+                    .is_ok(),
+                "placing a page at {start:#x}",
+            );
+        }
+
+        let cow = FlagBuilder::new()
+            .toggle_read()
+            .toggle_private()
+            .toggle_cow();
+        space
+            .add_mapping_at(anon.clone(), 0, PAGE_SIZE, 0x20_0000, cow)
+            .unwrap();
+        let write = FlagBuilder::new().toggle_read().toggle_write();
+        assert_eq!(
+            space.protect(0x20_0000, PAGE_SIZE, write),
+            Err("Invalid flags for mapping.")
+        );
+    }
+
+    #[test]
+    fn unmap_during_io_fails_the_fault() {
+        let space = Arc::new(ConcurrentAddressSpace::new("threads"));
+        let (slow, started, gate) = GatedSource::new();
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let addr = space.add_mapping(slow, 0, 2 * PAGE_SIZE, flags).unwrap();
+
+        let faulting = {
+            let space = space.clone();
+            thread::spawn(move || space.fault(addr, FlagBuilder::read()).is_err())
+        };
+        started.recv().unwrap();
+        space.remove_range(addr, 2 * PAGE_SIZE).unwrap();
+        gate.send(()).unwrap();
+        assert!(faulting.join().unwrap());
+        assert!(!space.is_resident(addr));
+    }
+}
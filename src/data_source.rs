@@ -1,6 +1,57 @@
-use std::fs::File;
+use core::any::Any;
 
-pub trait DataSource {
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::address_space::PAGE_SIZE;
+
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+#[cfg(feature = "std")]
+use std::os::unix::fs::{FileExt, MetadataExt};
+#[cfg(feature = "std")]
+use std::string::{String, ToString};
+
+/// The source's `Capabilities` don't allow a mapping with the asked-for permissions.
+pub const NOT_PERMITTED: &str = "The data source doesn't allow the mapping's permissions.";
+/// The mapping runs past the end of a source that can't grow.
+pub const PAST_END: &str = "The mapping runs past the end of the data source.";
+
+/// What can be done with a `DataSource`, and so which mappings of it are allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub readable: bool,
+    /// Writes reach the source, so it can back writable shared mappings.
+    pub writable: bool,
+    /// It can be mapped executable; a file on a `noexec` mount couldn't be.
+    pub executable: bool,
+    /// Any offset can be read, not just the next one, like a file but unlike a pipe.
+    pub seekable: bool,
+    /// It can grow, so mappings may run past its current length.
+    pub resizable: bool,
+}
+
+/// Who a `DataSource` is, for telling sources apart and keying caches by them. Two live sources
+/// with the same identity hold the same data, even if they were opened separately.
+///
+/// `File` ids stay valid for as long as the file exists. `Object` ids are only valid while the
+/// source is alive: once it's dropped, its address, and so its id, may be reused by another
+/// source, so anything keyed by one must be dropped along with the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SourceId {
+    /// A file, by device and inode number.
+    File { device: u64, inode: u64 },
+    /// Some other source, by its address in memory. Only valid while the source is alive.
+    Object(usize),
+}
+
+/// Somewhere data for a mapping comes from and goes back to.
+///
+/// Sources are shared between mappings and threads, so they must be `Send + Sync`, and
+/// `get_source_for_addr` can check what type one is, so they must be `Any`.
+pub trait DataSource: Any + Send + Sync {
     // constructors are left to each implementation, once you have one, you can:
     //
     // TODO: instead of taking a `flagbuilder`, should we turn it into some kind of convenient
@@ -10,36 +61,272 @@ pub trait DataSource {
     fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str>;
     fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str>;
     fn flush(&self, offset: usize, length: usize) -> Result<(), &str>;
+
+    /// Read the bytes starting at `offset` into `buffers`, one after another, as one request.
+    ///
+    /// By default this reads the whole span with `read` and copies it out.
+    ///
+    /// # Errors
+    /// If the read fails.
+    fn read_vectored(&self, offset: usize, buffers: &mut [&mut [u8]]) -> Result<(), &str> {
+        let mut data = vec![0; buffers.iter().map(|buffer| buffer.len()).sum()];
+        self.read(offset, data.len(), &mut data)?;
+        let mut rest = &data[..];
+        for buffer in buffers {
+            let (chunk, tail) = rest.split_at(buffer.len());
+            buffer.copy_from_slice(chunk);
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    /// Write `buffers`, one after another, to the bytes starting at `offset`, as one request.
+    ///
+    /// By default this gathers them up and writes them with `write`.
+    ///
+    /// # Errors
+    /// If the write fails.
+    fn write_vectored(&self, offset: usize, buffers: &[&[u8]]) -> Result<(), &str> {
+        let data: Vec<u8> = buffers.concat();
+        self.write(offset, data.len(), &data)
+    }
+
+    /// Read consecutive pages, starting with page `first_page` of the source, into `frames`,
+    /// which needn't be next to each other but must each be a page long.
+    ///
+    /// # Errors
+    /// If a frame isn't a page long, or the read fails.
+    fn read_pages(&self, first_page: usize, frames: &mut [&mut [u8]]) -> Result<(), &str> {
+        if frames.iter().any(|frame| frame.len() != PAGE_SIZE) {
+            return Err("frame isn't a page long");
+        }
+        self.read_vectored(first_page * PAGE_SIZE, frames)
+    }
+
+    /// Write `frames` to consecutive pages of the source, starting with page `first_page`.
+    ///
+    /// # Errors
+    /// If a frame isn't a page long, or the write fails.
+    fn write_pages(&self, first_page: usize, frames: &[&[u8]]) -> Result<(), &str> {
+        if frames.iter().any(|frame| frame.len() != PAGE_SIZE) {
+            return Err("frame isn't a page long");
+        }
+        self.write_vectored(first_page * PAGE_SIZE, frames)
+    }
+
+    /// Is this anonymous memory, with no backing store behind it?
+    fn is_anonymous(&self) -> bool {
+        false
+    }
+
+    /// How many bytes the source holds now, or `None` if there's no end to it.
+    fn length(&self) -> Option<usize> {
+        None
+    }
+
+    /// By default a source can only be read. Sources that can do more have to say so.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            readable: true,
+            writable: false,
+            executable: false,
+            seekable: false,
+            resizable: false,
+        }
+    }
+
+    /// By default a source is only itself, so two sources are the same if they're the same
+    /// object. The id this gives is only valid while the source is alive; see `SourceId`.
+    /// Sources that outlive their users' caches should give an id of their own.
+    fn id(&self) -> SourceId {
+        SourceId::Object((self as *const Self).cast::<()>() as usize)
+    }
+}
+
+/// Zero-filled memory that isn't backed by anything, for anonymous mappings.
+pub struct AnonymousDataSource;
+
+impl DataSource for AnonymousDataSource {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+        buffer
+            .get_mut(..length)
+            .ok_or("buffer is shorter than length")?
+            .fill(0);
+        Ok(())
+    }
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+        Err("anonymous memory has no backing store")
+    }
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        Ok(())
+    }
+    fn is_anonymous(&self) -> bool {
+        true
+    }
+    /// Zeros can be read anywhere, but nothing written is kept.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            readable: true,
+            writable: false,
+            executable: true,
+            seekable: true,
+            resizable: false,
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 pub struct FileDataSource {
     file_handle: File,
     name: String,
+    writable: bool,
+    id: SourceId,
 }
 
+#[cfg(feature = "std")]
 impl FileDataSource {
     /// Create a new `FileDataSource`.
     ///
     /// # Errors
     /// If the file can't be opened.
     pub fn new(name: &str) -> Result<Self, &str> {
-        File::open(name).map_or(Err("couldn't open {name}"), |file_handle| {
-            Ok(Self {
-                file_handle,
-                name: name.to_string(),
-            })
+        let file_handle = File::open(name).map_err(|_| "couldn't open file")?;
+        Self::opened(file_handle, name, false)
+    }
+
+    /// Create a new `FileDataSource` that can also be written to.
+    ///
+    /// # Errors
+    /// If the file can't be opened for reading and writing.
+    pub fn new_writable(name: &str) -> Result<Self, &str> {
+        let file_handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(name)
+            .map_err(|_| "couldn't open file")?;
+        Self::opened(file_handle, name, true)
+    }
+
+    fn opened(file_handle: File, name: &str, writable: bool) -> Result<Self, &'static str> {
+        let metadata = file_handle.metadata().map_err(|_| "couldn't stat file")?;
+        Ok(Self {
+            file_handle,
+            name: name.to_string(),
+            writable,
+            id: SourceId::File {
+                device: metadata.dev(),
+                inode: metadata.ino(),
+            },
         })
     }
 }
 
+#[cfg(feature = "std")]
 impl DataSource for FileDataSource {
+    /// Read `length` bytes at `offset` into `buffer`. Anything past the end of the file reads as
+    /// zeros, the same as a mapped file would.
     fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
-        todo!()
+        let buffer = buffer
+            .get_mut(..length)
+            .ok_or("buffer is shorter than length")?;
+        let mut done = 0;
+        while done < length {
+            match self
+                .file_handle
+                .read_at(&mut buffer[done..], (offset + done) as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err("couldn't read from file"),
+            }
+        }
+        buffer[done..].fill(0);
+        Ok(())
     }
     fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
-        todo!()
+        let buffer = buffer
+            .get(..length)
+            .ok_or("buffer is shorter than length")?;
+        self.file_handle
+            .write_all_at(buffer, offset as u64)
+            .map_err(|_| "couldn't write to file")
+    }
+    /// Read with `preadv`, zero-filling past the end of the file like `read`.
+    fn read_vectored(&self, offset: usize, buffers: &mut [&mut [u8]]) -> Result<(), &str> {
+        let mut slices: Vec<IoSliceMut> = buffers
+            .iter_mut()
+            .map(|buffer| IoSliceMut::new(buffer))
+            .collect();
+        let mut rest = &mut slices[..];
+        let mut done = 0;
+        while !rest.is_empty() {
+            match self
+                .file_handle
+                .read_vectored_at(rest, (offset + done) as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => {
+                    IoSliceMut::advance_slices(&mut rest, n);
+                    done += n;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err("couldn't read from file"),
+            }
+        }
+        for slice in rest {
+            slice.fill(0);
+        }
+        Ok(())
+    }
+    /// Write with `pwritev`.
+    fn write_vectored(&self, offset: usize, buffers: &[&[u8]]) -> Result<(), &str> {
+        let mut slices: Vec<IoSlice> = buffers.iter().map(|buffer| IoSlice::new(buffer)).collect();
+        let mut rest = &mut slices[..];
+        let mut done = 0;
+        while !rest.is_empty() {
+            match self
+                .file_handle
+                .write_vectored_at(rest, (offset + done) as u64)
+            {
+                Ok(0) => return Err("couldn't write to file"),
+                Ok(n) => {
+                    IoSlice::advance_slices(&mut rest, n);
+                    done += n;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err("couldn't write to file"),
+            }
+        }
+        Ok(())
     }
+    /// Flush the whole file; there's no finer-grained way to do it.
     fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
-        todo!()
+        self.file_handle
+            .sync_data()
+            .map_err(|_| "couldn't flush file")
+    }
+    fn length(&self) -> Option<usize> {
+        self.file_handle
+            .metadata()
+            .ok()
+            .map(|metadata| metadata.len() as usize)
+    }
+    /// Regular files can be seeked, and grown if they were opened for writing.
+    fn capabilities(&self) -> Capabilities {
+        let regular = self
+            .file_handle
+            .metadata()
+            .is_ok_and(|metadata| metadata.is_file());
+        Capabilities {
+            readable: true,
+            writable: self.writable,
+            executable: true,
+            seekable: regular,
+            resizable: regular && self.writable,
+        }
+    }
+    fn id(&self) -> SourceId {
+        self.id
     }
 }
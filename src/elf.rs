@@ -0,0 +1,544 @@
+// Loading ELF64 programs into an AddressSpace, for exec.
+//
+// Each PT_LOAD segment is mapped privately from the program's DataSource at the segment's file
+// offset, and whatever the segment needs beyond the end of its file data (the BSS) is zeroed,
+// with anonymous memory for any whole pages past the file data. Only little-endian files are
+// understood.
+//
+// The pages the segments span are first claimed with `add_mapping_at`, so a program never lands
+// on top of mappings that are already there, and the segments are then mapped over that claim.
+// If anything goes wrong, the whole claim is unmapped again.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::address_space::{AddressSpace, FlagBuilder, PAGE_SIZE};
+use crate::data_source::{AnonymousDataSource, DataSource};
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+pub const PT_GNU_STACK: u32 = 0x6474_e551;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_BASE: usize = 7;
+pub const AT_FLAGS: usize = 8;
+pub const AT_ENTRY: usize = 9;
+
+/// The longest interpreter path `PT_INTERP` may give, counting its terminating zero.
+pub const PATH_MAX: usize = 4096;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// What `load_elf` set up.
+#[derive(Debug)]
+pub struct LoadedElf {
+    /// Where execution starts.
+    pub entry: usize,
+    /// How far the program was moved from its link-time addresses; zero for `ET_EXEC`.
+    pub load_bias: usize,
+    /// The end of the highest segment, where the program break heap starts.
+    pub brk: usize,
+    /// The flags the program's stack should have, from `PT_GNU_STACK`.
+    pub stack_flags: FlagBuilder,
+    /// The dynamic linker named by `PT_INTERP`, if any. It isn't loaded.
+    pub interpreter: Option<String>,
+    /// Auxiliary vector entries for the new program, as `(AT_*, value)` pairs.
+    pub auxv: Vec<(usize, usize)>,
+}
+
+/// One program header.
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            kind: u32_at(bytes, 0),
+            flags: u32_at(bytes, 4),
+            offset: u64_at(bytes, 8),
+            vaddr: u64_at(bytes, 16),
+            filesz: u64_at(bytes, 32),
+            memsz: u64_at(bytes, 40),
+        }
+    }
+
+    /// The pages this segment covers once moved by `load_bias`.
+    fn pages(&self, load_bias: usize) -> Result<Range<usize>, &'static str> {
+        let start = load_bias
+            .checked_add(self.vaddr)
+            .ok_or("Bad segment address.")?;
+        let file_end = start.checked_add(self.filesz).ok_or("Bad segment size.")?;
+        let mem_end = start.checked_add(self.memsz).ok_or("Bad segment size.")?;
+        if self.memsz < self.filesz || start % PAGE_SIZE != self.offset % PAGE_SIZE {
+            return Err("Bad segment layout.");
+        }
+        let mem_top = mem_end
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or("Bad segment size.")?;
+        Ok(start - start % PAGE_SIZE..mem_top)
+    }
+
+    /// The `FlagBuilder` permissions for this segment's `p_flags`.
+    fn perms(&self) -> FlagBuilder {
+        let mut flags = FlagBuilder::new();
+        if self.flags & PF_R != 0 {
+            flags = flags.toggle_read();
+        }
+        if self.flags & PF_W != 0 {
+            flags = flags.toggle_write();
+        }
+        if self.flags & PF_X != 0 {
+            flags = flags.toggle_execute();
+        }
+        flags
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(
+        bytes[at..at + 2]
+            .try_into()
+            .expect("Bad things are happening."),
+    )
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(
+        bytes[at..at + 4]
+            .try_into()
+            .expect("Bad things are happening."),
+    )
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> usize {
+    u64::from_le_bytes(
+        bytes[at..at + 8]
+            .try_into()
+            .expect("Bad things are happening."),
+    ) as usize
+}
+
+/// Map the ELF64 program in `source` into `space`.
+///
+/// An `ET_DYN` program is loaded at `base`, which must be page aligned; `base` is ignored for
+/// `ET_EXEC`. The program break of `space` is set up to start after the highest segment.
+///
+/// # Errors
+/// If `source` can't be read or isn't an ELF64 little-endian executable, a segment is malformed,
+/// the segments would overlap or crowd a mapping already in `space`, or a segment can't be
+/// mapped. Nothing is left mapped when loading fails.
+pub fn load_elf(
+    space: &mut AddressSpace,
+    source: Arc<dyn DataSource>,
+    base: usize,
+) -> Result<LoadedElf, &'static str> {
+    let mut ehdr = [0; EHDR_SIZE];
+    source
+        .read(0, EHDR_SIZE, &mut ehdr)
+        .map_err(|_| "Couldn't read the ELF header.")?;
+    if ehdr[..4] != *b"\x7fELF" || ehdr[4] != 2 || ehdr[5] != 1 || ehdr[6] != 1 {
+        return Err("Not an ELF64 little-endian file.");
+    }
+    let load_bias = match u16_at(&ehdr, 16) {
+        ET_EXEC => 0,
+        ET_DYN if base.is_multiple_of(PAGE_SIZE) => base,
+        ET_DYN => return Err("The load base must be page aligned."),
+        _ => return Err("Not an executable or shared object."),
+    };
+    let entry = u64_at(&ehdr, 24);
+    let phoff = u64_at(&ehdr, 32);
+    let phentsize = usize::from(u16_at(&ehdr, 54));
+    let phnum = usize::from(u16_at(&ehdr, 56));
+    if phentsize != PHDR_SIZE || phnum == 0 {
+        return Err("Bad program header table.");
+    }
+
+    let mut table = vec![0; phnum * PHDR_SIZE];
+    source
+        .read(phoff, table.len(), &mut table)
+        .map_err(|_| "Couldn't read the program headers.")?;
+    let headers: Vec<_> = table.chunks(PHDR_SIZE).map(ProgramHeader::parse).collect();
+
+    let mut image: Option<Range<usize>> = None;
+    for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        let pages = header.pages(load_bias)?;
+        image = Some(match image {
+            Some(image) => image.start.min(pages.start)..image.end.max(pages.end),
+            None => pages,
+        });
+    }
+    let image = image.ok_or("No loadable segments.")?;
+    let entry = load_bias.checked_add(entry).ok_or("Bad entry point.")?;
+    space
+        .add_mapping_at(
+            Arc::new(AnonymousDataSource),
+            0,
+            image.len(),
+            image.start,
+            FlagBuilder::new().toggle_private().toggle_noreserve(),
+        )
+        .map_err(|_| "The program overlaps or crowds an existing mapping.")?;
+
+    let mut brk = 0;
+    let mut phdr = None;
+    let mut interpreter = None;
+    let mut stack_flags = FlagBuilder::new()
+        .toggle_read()
+        .toggle_write()
+        .toggle_execute()
+        .toggle_private();
+    let mut claimed_to = image.start;
+    for header in &headers {
+        let loaded = match header.kind {
+            PT_LOAD => {
+                load_segment(space, &source, header, load_bias, &mut claimed_to).map(|end| {
+                    brk = brk.max(end);
+                    if header.offset <= phoff && phoff - header.offset < header.filesz {
+                        phdr.get_or_insert(load_bias + header.vaddr + (phoff - header.offset));
+                    }
+                })
+            }
+            PT_PHDR => {
+                phdr = Some(load_bias + header.vaddr);
+                Ok(())
+            }
+            PT_GNU_STACK => {
+                stack_flags = header.perms().toggle_private();
+                Ok(())
+            }
+            PT_INTERP => read_interpreter(&source, header).map(|path| interpreter = Some(path)),
+            _ => Ok(()),
+        };
+        if let Err(e) = loaded {
+            space
+                .remove_range(image.start, image.len())
+                .expect("Bad things are happening.");
+            return Err(e);
+        }
+    }
+    space.init_brk(brk);
+
+    let auxv = vec![
+        (AT_PHDR, phdr.unwrap_or(0)),
+        (AT_PHENT, PHDR_SIZE),
+        (AT_PHNUM, phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, entry),
+    ];
+    Ok(LoadedElf {
+        entry,
+        load_bias,
+        brk,
+        stack_flags,
+        interpreter,
+        auxv,
+    })
+}
+
+/// Read the dynamic linker's path out of a `PT_INTERP` segment.
+fn read_interpreter(
+    source: &Arc<dyn DataSource>,
+    header: &ProgramHeader,
+) -> Result<String, &'static str> {
+    if header.filesz > PATH_MAX {
+        return Err("The interpreter path is too long.");
+    }
+    let mut path = vec![0; header.filesz];
+    source
+        .read(header.offset, path.len(), &mut path)
+        .map_err(|_| "Couldn't read the interpreter path.")?;
+    let path = path.split(|&byte| byte == 0).next().unwrap_or_default();
+    Ok(String::from_utf8_lossy(path).into_owned())
+}
+
+/// Map one `PT_LOAD` segment over the program's claim, returning the address just past its end
+/// in memory. `claimed_to` is how far the claim has been used; any of it skipped over to reach
+/// this segment is unmapped.
+fn load_segment(
+    space: &mut AddressSpace,
+    source: &Arc<dyn DataSource>,
+    header: &ProgramHeader,
+    load_bias: usize,
+    claimed_to: &mut usize,
+) -> Result<usize, &'static str> {
+    let pages = header.pages(load_bias)?;
+    let (page_start, mem_top) = (pages.start, pages.end);
+    let start = load_bias + header.vaddr;
+    let file_end = start + header.filesz;
+    let mem_end = start + header.memsz;
+    let file_top = file_end
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or("Bad segment size.")?;
+    let flags = header.perms().toggle_private();
+    if page_start > *claimed_to {
+        space
+            .remove_range(*claimed_to, page_start - *claimed_to)
+            .map_err(|_| "Couldn't map a segment.")?;
+    }
+    *claimed_to = (*claimed_to).max(mem_top);
+
+    // a later segment that shares a page with an earlier one takes the page over, as with
+    // MAP_FIXED, and segments may sit right against each other
+    if file_top > page_start {
+        space
+            .replace_mapping_at(
+                source.clone(),
+                header.offset - start % PAGE_SIZE,
+                file_top - page_start,
+                page_start,
+                flags,
+            )
+            .map_err(|_| "Couldn't map a segment.")?;
+        if mem_end > file_end {
+            space.zero_fill(file_end, file_top.min(mem_end) - file_end)?;
+        }
+    }
+    if mem_top > file_top {
+        let bss_start = file_top.max(page_start);
+        space
+            .replace_mapping_at(
+                Arc::new(AnonymousDataSource),
+                0,
+                mem_top - bss_start,
+                bss_start,
+                flags,
+            )
+            .map_err(|_| "Couldn't map a segment.")?;
+    }
+    Ok(mem_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_source::Capabilities;
+
+    /// A `DataSource` over a byte vector.
+    struct Bytes(Vec<u8>);
+
+    impl DataSource for Bytes {
+        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+            for (i, byte) in buffer[..length].iter_mut().enumerate() {
+                *byte = self.0.get(offset + i).copied().unwrap_or(0);
+            }
+            Ok(())
+        }
+        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+            Err("read-only")
+        }
+        fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+            Ok(())
+        }
+        /// Text segments are mapped executable.
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                readable: true,
+                writable: false,
+                executable: true,
+                seekable: true,
+                resizable: false,
+            }
+        }
+    }
+
+    /// Build an ELF file with a text segment at 0x10000 and a data segment at 0x11800 with
+    /// 0x2000 bytes of BSS, each filled with a marker byte.
+    fn program(kind: u16, stack: Option<u32>) -> Vec<u8> {
+        let mut elf = vec![0; 0x3000];
+        elf[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        elf[16..18].copy_from_slice(&kind.to_le_bytes());
+        elf[18..20].copy_from_slice(&243u16.to_le_bytes());
+        elf[24..32].copy_from_slice(&0x10078u64.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        let mut phdrs = vec![
+            // p_type, p_flags, p_offset, p_vaddr, p_filesz, p_memsz
+            (PT_LOAD, PF_R | PF_X, 0x0, 0x10000, 0x1000, 0x1000),
+            (PT_LOAD, PF_R | PF_W, 0x1800, 0x11800, 0x900, 0x2900),
+        ];
+        if let Some(flags) = stack {
+            phdrs.push((PT_GNU_STACK, flags, 0, 0, 0, 0));
+        }
+        elf[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for (i, (kind, flags, offset, vaddr, filesz, memsz)) in phdrs.into_iter().enumerate() {
+            let at = 64 + i * PHDR_SIZE;
+            elf[at..at + 4].copy_from_slice(&kind.to_le_bytes());
+            elf[at + 4..at + 8].copy_from_slice(&flags.to_le_bytes());
+            elf[at + 8..at + 16].copy_from_slice(&(offset as u64).to_le_bytes());
+            elf[at + 16..at + 24].copy_from_slice(&(vaddr as u64).to_le_bytes());
+            elf[at + 32..at + 40].copy_from_slice(&(filesz as u64).to_le_bytes());
+            elf[at + 40..at + 48].copy_from_slice(&(memsz as u64).to_le_bytes());
+        }
+        elf[0x200..0x1000].fill(0xaa);
+        elf[0x1000..0x3000].fill(0xdd);
+        elf
+    }
+
+    #[test]
+    fn load_exec() {
+        let mut space = AddressSpace::new("exec");
+        let image = load_elf(
+            &mut space,
+            Arc::new(Bytes(program(ET_EXEC, Some(PF_R | PF_W)))),
+            0x4000_0000,
+        )
+        .unwrap();
+
+        assert_eq!(image.entry, 0x10078);
+        assert_eq!(image.brk, 0x14100);
+        assert_eq!(space.brk_start(), 0x14100);
+        assert!(image.auxv.contains(&(AT_PHDR, 0x10040)));
+        assert!(image.auxv.contains(&(AT_ENTRY, 0x10078)));
+        assert!(image.auxv.contains(&(AT_PHNUM, 3)));
+        assert_eq!(
+            image.stack_flags,
+            FlagBuilder::new()
+                .toggle_read()
+                .toggle_write()
+                .toggle_private()
+        );
+
+        let mut byte = [0];
+        space.read_bytes(0x10200, &mut byte).unwrap();
+        assert_eq!(byte, [0xaa]);
+        assert!(space.write_bytes(0x10200, b"x").is_err());
+
+        // file data, then the zeroed tail of its last page, then anonymous BSS
+        let mut data = [0xff; 0x2900];
+        space.read_bytes(0x11800, &mut data).unwrap();
+        assert!(data[..0x900].iter().all(|&byte| byte == 0xdd));
+        assert!(data[0x900..].iter().all(|&byte| byte == 0));
+        space.write_bytes(0x14000, b"bss").unwrap();
+        assert!(!space.is_mapped(0x15000, 1));
+    }
+
+    #[test]
+    fn load_dyn_at_base() {
+        let mut space = AddressSpace::new("exec");
+        let image = load_elf(
+            &mut space,
+            Arc::new(Bytes(program(ET_DYN, None))),
+            0x4000_0000,
+        )
+        .unwrap();
+
+        assert_eq!(image.load_bias, 0x4000_0000);
+        assert_eq!(image.entry, 0x4001_0078);
+        assert!(image.auxv.contains(&(AT_PHDR, 0x4001_0040)));
+        assert!(image.stack_flags.check_access_perms(FlagBuilder::execute()));
+        let mut byte = [0];
+        space.read_bytes(0x4001_1800, &mut byte).unwrap();
+        assert_eq!(byte, [0xdd]);
+
+        let mut other = AddressSpace::new("exec");
+        assert!(load_elf(
+            &mut other,
+            Arc::new(Bytes(program(ET_DYN, None))),
+            0x4000_0001
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn never_load_over_existing_mappings() {
+        let mut space = AddressSpace::new("exec");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        space
+            .add_mapping_at(Arc::new(AnonymousDataSource), 0, PAGE_SIZE, 0x13000, flags)
+            .unwrap();
+        space.write_bytes(0x13000, b"mine").unwrap();
+        assert!(load_elf(&mut space, Arc::new(Bytes(program(ET_EXEC, None))), 0).is_err());
+
+        let mut mine = [0; 4];
+        space.read_bytes(0x13000, &mut mine).unwrap();
+        assert_eq!(&mine, b"mine");
+        assert!(!space.is_mapped(0x10000, 1));
+
+        // nor right up against them
+        let mut space = AddressSpace::new("exec");
+        space
+            .add_mapping_at(Arc::new(AnonymousDataSource), 0, PAGE_SIZE, 0x15000, flags)
+            .unwrap();
+        assert!(load_elf(&mut space, Arc::new(Bytes(program(ET_EXEC, None))), 0).is_err());
+        assert!(!space.is_mapped(0x10000, 1));
+    }
+
+    #[test]
+    fn gaps_between_segments_stay_unmapped() {
+        // move the data segment up two pages
+        let mut elf = program(ET_EXEC, None);
+        let at = 64 + PHDR_SIZE;
+        elf[at + 16..at + 24].copy_from_slice(&0x13800u64.to_le_bytes());
+        let mut space = AddressSpace::new("exec");
+        let image = load_elf(&mut space, Arc::new(Bytes(elf)), 0).unwrap();
+
+        assert_eq!(image.brk, 0x16100);
+        assert!(space.is_mapped(0x10000, 0x1000));
+        assert!(!space.is_mapped(0x11000, 1));
+        assert!(!space.is_mapped(0x12fff, 1));
+        let mut byte = [0];
+        space.read_bytes(0x13800, &mut byte).unwrap();
+        assert_eq!(byte, [0xdd]);
+    }
+
+    #[test]
+    fn reject_non_elf() {
+        let mut space = AddressSpace::new("exec");
+        assert!(load_elf(&mut space, Arc::new(Bytes(b"#!/bin/sh\n".to_vec())), 0).is_err());
+
+        let mut elf = program(ET_EXEC, None);
+        elf[4] = 1; // ELFCLASS32
+        assert!(load_elf(&mut space, Arc::new(Bytes(elf)), 0).is_err());
+    }
+
+    #[test]
+    fn reject_long_interpreter_paths() {
+        // turn the PT_GNU_STACK header into a PT_INTERP one with an absurd length
+        let mut elf = program(ET_EXEC, Some(PF_R | PF_W));
+        let at = 64 + 2 * PHDR_SIZE;
+        elf[at..at + 4].copy_from_slice(&PT_INTERP.to_le_bytes());
+        elf[at + 8..at + 16].copy_from_slice(&0x200u64.to_le_bytes());
+        elf[at + 32..at + 40].copy_from_slice(&(u64::MAX >> 1).to_le_bytes());
+        let mut space = AddressSpace::new("exec");
+        assert_eq!(
+            load_elf(&mut space, Arc::new(Bytes(elf.clone())), 0).unwrap_err(),
+            "The interpreter path is too long."
+        );
+        // the segments mapped before the bad header are gone again
+        assert!(!space.is_mapped(0x10000, 1));
+        assert!(!space.is_mapped(0x11800, 1));
+
+        elf[at + 32..at + 40].copy_from_slice(&(PATH_MAX as u64).to_le_bytes());
+        let mut space = AddressSpace::new("exec");
+        assert!(load_elf(&mut space, Arc::new(Bytes(elf)), 0)
+            .unwrap()
+            .interpreter
+            .is_some());
+    }
+}
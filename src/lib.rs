@@ -1,18 +1,150 @@
 #![feature(linked_list_cursors)]
-
+#![cfg_attr(feature = "std", feature(unix_file_vectored_at))]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(dead_code, unused_variables)]
 
+extern crate alloc;
+
+mod accounting;
 mod address_space;
+#[cfg(feature = "std")]
+mod aio;
+pub mod buddy;
 mod cacher;
+pub mod commit;
+#[cfg(feature = "std")]
+mod concurrent;
 mod data_source;
+pub mod elf;
+pub mod limits;
+pub mod memcg;
+pub mod numa;
+pub mod physical;
+mod swap;
+pub mod syscall;
+mod tlb;
+pub mod userfault;
 
-pub use address_space::{AddressSpace, FlagBuilder};
-pub use data_source::{DataSource, FileDataSource};
-use std::sync::Arc;
+pub use accounting::{MappingUsage, MemoryUsage};
+pub use address_space::{AddressSpace, Advice, FlagBuilder, SyncMode};
+#[cfg(feature = "std")]
+pub use aio::{
+    AsyncDataSource, PageFetcher, PageFuture, ReadCompletion, SyncAdapter, WriteCompletion,
+};
+pub use buddy::{BuddyAllocator, FragmentationStats};
+pub use cacher::{CacheCoordinator, Watermarks};
+pub use commit::{CommitAccountant, Overcommit};
+#[cfg(feature = "std")]
+pub use concurrent::ConcurrentAddressSpace;
+#[cfg(feature = "std")]
+pub use data_source::FileDataSource;
+pub use data_source::{
+    AnonymousDataSource, Capabilities, DataSource, SourceId, NOT_PERMITTED, PAST_END,
+};
+pub use limits::Limits;
+pub use memcg::{GroupStats, MemoryGroup};
+pub use numa::{MemoryPolicy, NodeMask, NodeStats};
+pub use physical::{Frame, PhysicalMemory};
+pub use swap::SwapSpace;
+pub use tlb::TlbInvalidator;
+pub use userfault::{UserFaultEvent, UserFaultKind, UserFaultMode};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// An in-memory `DataSource` that records every read and write it serves as
+    /// `(offset, length)`.
+    struct MemorySource {
+        data: Mutex<Vec<u8>>,
+        reads: Mutex<Vec<(usize, usize)>>,
+        writes: Mutex<Vec<(usize, usize)>>,
+        flushes: Mutex<usize>,
+        fail_writes: bool,
+    }
+
+    impl MemorySource {
+        /// A source of `pages` pages, each filled with its own page number.
+        fn new(pages: usize) -> Self {
+            Self {
+                data: Mutex::new(
+                    (0..pages * address_space::PAGE_SIZE)
+                        .map(|i| (i / address_space::PAGE_SIZE) as u8)
+                        .collect(),
+                ),
+                reads: Mutex::new(Vec::new()),
+                writes: Mutex::new(Vec::new()),
+                flushes: Mutex::new(0),
+                fail_writes: false,
+            }
+        }
+
+        /// Lengths of the reads served so far, in pages.
+        fn read_sizes(&self) -> Vec<usize> {
+            self.reads
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, length)| length / address_space::PAGE_SIZE)
+                .collect()
+        }
+
+        /// The writes served so far, as `(first page, page count)`.
+        fn written_pages(&self) -> Vec<(usize, usize)> {
+            self.writes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(offset, length)| {
+                    (
+                        offset / address_space::PAGE_SIZE,
+                        length / address_space::PAGE_SIZE,
+                    )
+                })
+                .collect()
+        }
+    }
+
+    impl DataSource for MemorySource {
+        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+            self.reads.lock().unwrap().push((offset, length));
+            let data = self.data.lock().unwrap();
+            for (i, byte) in buffer[..length].iter_mut().enumerate() {
+                *byte = data.get(offset + i).copied().unwrap_or(0);
+            }
+            Ok(())
+        }
+        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+            self.writes.lock().unwrap().push((offset, length));
+            if self.fail_writes {
+                return Err("MemorySource write failed");
+            }
+            let mut data = self.data.lock().unwrap();
+            if data.len() < offset + length {
+                data.resize(offset + length, 0);
+            }
+            data[offset..offset + length].copy_from_slice(&buffer[..length]);
+            Ok(())
+        }
+        fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+            *self.flushes.lock().unwrap() += 1;
+            Ok(())
+        }
+        /// Writes past the end grow the data, so anything goes.
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                readable: true,
+                writable: true,
+                executable: true,
+                seekable: true,
+                resizable: true,
+            }
+        }
+    }
+
+    /// A file several pages long, so that mappings can start past its first page.
+    const FIXTURE: &str = "tests/fixtures/mapped.txt";
 
     #[test]
     fn constructors() {
@@ -27,20 +159,24 @@ mod tests {
     #[test]
     fn test_add_mapping() {
         let mut addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let data_source: FileDataSource = FileDataSource::new(FIXTURE).unwrap();
         let offset: usize = 0;
         let length: usize = 1;
         let read_flags = FlagBuilder::new().toggle_read();
 
         let ds_arc = Arc::new(data_source);
 
-        let addr = addr_space.add_mapping(ds_arc.clone(), offset, length, read_flags).unwrap();
+        let addr = addr_space
+            .add_mapping(ds_arc.clone(), offset, length, read_flags)
+            .unwrap();
         assert!(addr != 0);
 
-        let addr2 = addr_space.add_mapping(ds_arc.clone(), address_space::PAGE_SIZE, length, read_flags).unwrap();
+        let addr2 = addr_space
+            .add_mapping(ds_arc.clone(), address_space::PAGE_SIZE, length, read_flags)
+            .unwrap();
         assert!(addr2 != 0);
         assert!(addr != addr2);
-        
+
         // we should move these tests into addr_space, since they access non-public internals of the structure:
         // assert_eq!(addr_space.mappings.is_empty(), false);
         // assert_eq!(addr_space.mappings.front().source, Some(&data_source));
@@ -51,95 +187,143 @@ mod tests {
     #[test]
     fn add_mapping_at_correct() {
         let mut addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let data_source: FileDataSource = FileDataSource::new(FIXTURE).unwrap();
         let offset: usize = 0;
         let length: usize = 1;
         let read_flags = FlagBuilder::new().toggle_read();
 
         let ds_arc = Arc::new(data_source);
 
-        let addr = addr_space.add_mapping_at(ds_arc.clone(), offset, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            offset,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr {
-          Ok(_) => println!("First address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let addr2 = addr_space.add_mapping_at(ds_arc.clone(), address_space::PAGE_SIZE, length, 3 * address_space::PAGE_SIZE + 3, read_flags);
+        let addr2 = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            address_space::PAGE_SIZE,
+            length,
+            3 * address_space::PAGE_SIZE + 3,
+            read_flags,
+        );
         match addr2 {
-          Ok(_) => println!("Second address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("Second address added successfully."),
+            Err(e) => panic!("{}", e),
         }
     }
 
     #[test]
     fn consec_mapping_at_failure() {
         let mut addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let data_source: FileDataSource = FileDataSource::new(FIXTURE).unwrap();
         let offset: usize = 0;
         let length: usize = 1;
         let read_flags = FlagBuilder::new().toggle_read();
 
         let ds_arc = Arc::new(data_source);
 
-        let addr = addr_space.add_mapping_at(ds_arc.clone(), offset, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            offset,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr {
-          Ok(_) => println!("First address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let addr2 = addr_space.add_mapping_at(ds_arc.clone(), address_space::PAGE_SIZE, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr2 = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            address_space::PAGE_SIZE,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         assert!(addr2.is_err())
-    }   
+    }
 
     #[test]
     fn consec_mapping_at_with_remove() {
         let mut addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let data_source: FileDataSource = FileDataSource::new(FIXTURE).unwrap();
         let offset: usize = 0;
         let length: usize = 1;
         let read_flags = FlagBuilder::new().toggle_read();
 
         let ds_arc = Arc::new(data_source);
 
-        let addr = addr_space.add_mapping_at(ds_arc.clone(), offset, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            offset,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr {
-          Ok(_) => println!("First address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
         let r = addr_space.remove_mapping(ds_arc.clone(), address_space::PAGE_SIZE + 1);
         match r {
-          Ok(_) => println!("First address removed successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address removed successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let addr2 = addr_space.add_mapping_at(ds_arc.clone(), address_space::PAGE_SIZE, length, 3 * address_space::PAGE_SIZE + 1, read_flags);
+        let addr2 = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            address_space::PAGE_SIZE,
+            length,
+            3 * address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr2 {
-          Ok(_) => println!("Second address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("Second address added successfully."),
+            Err(e) => panic!("{}", e),
         }
     }
 
     #[test]
     fn mapping_end() {
         let mut addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let data_source: FileDataSource = FileDataSource::new(FIXTURE).unwrap();
         let offset: usize = 0;
         let length: usize = 1;
         let read_flags = FlagBuilder::new().toggle_read();
 
         let ds_arc = Arc::new(data_source);
 
-        let addr = addr_space.add_mapping_at(ds_arc.clone(), offset, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            offset,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr {
-          Ok(_) => println!("First address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let addr2 = addr_space.add_mapping_at(ds_arc.clone(), address_space::PAGE_SIZE, length, usize::MAX - 2 * address_space::PAGE_SIZE - 1, read_flags);
+        let addr2 = addr_space.add_mapping_at(
+            ds_arc.clone(),
+            address_space::PAGE_SIZE,
+            length,
+            usize::MAX - 2 * address_space::PAGE_SIZE - 1,
+            read_flags,
+        );
         match addr2 {
-          Ok(_) => println!("Second address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("Second address added successfully."),
+            Err(e) => panic!("{}", e),
         }
     }
 
@@ -159,28 +343,2079 @@ mod tests {
 
         let ds_arc2: Arc<dyn DataSource> = fds_arc2.clone();
 
-        let addr = addr_space.add_mapping_at(fds_arc.clone(), offset, length, address_space::PAGE_SIZE + 1, read_flags);
+        let addr = addr_space.add_mapping_at(
+            fds_arc.clone(),
+            offset,
+            length,
+            address_space::PAGE_SIZE + 1,
+            read_flags,
+        );
         match addr {
-          Ok(_) => println!("First address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("First address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let addr2 = addr_space.add_mapping_at(fds_arc2.clone(), offset, length, 3 * address_space::PAGE_SIZE + 3, read_flags);
+        let addr2 = addr_space.add_mapping_at(
+            fds_arc2.clone(),
+            offset,
+            length,
+            3 * address_space::PAGE_SIZE + 3,
+            read_flags,
+        );
         match addr2 {
-          Ok(_) => println!("Second address added successfully."),
-          Err(e) => panic!("{}", e),
+            Ok(_) => println!("Second address added successfully."),
+            Err(e) => panic!("{}", e),
         }
 
-        let result = addr_space.get_source_for_addr::<FileDataSource>(address_space::PAGE_SIZE + 1, read_flags);
+        let result = addr_space
+            .get_source_for_addr::<FileDataSource>(address_space::PAGE_SIZE + 1, read_flags);
         match result {
-          Ok((source_result, offset_result)) => println!("TODO: Arc comparison"),
-          Err(e) => panic!("{}", e),
+            Ok((source_result, offset_result)) => {
+                assert_eq!(source_result.id(), ds_arc.id());
+                assert_eq!(offset_result, offset);
+            }
+            Err(e) => panic!("{}", e),
         }
- 
-        let result2 = addr_space.get_source_for_addr::<FileDataSource>(3 * address_space::PAGE_SIZE + 3, read_flags);
+
+        let result2 = addr_space
+            .get_source_for_addr::<FileDataSource>(3 * address_space::PAGE_SIZE + 3, read_flags);
         match result2 {
-          Ok((source_result, offset_result)) => println!("TODO: Arc comparison"),
-          Err(e) => panic!("{}", e),
+            Ok((source_result, offset_result)) => {
+                assert_eq!(source_result.id(), ds_arc2.id());
+                assert_ne!(source_result.id(), ds_arc.id());
+                assert_eq!(offset_result, offset);
+            }
+            Err(e) => panic!("{}", e),
+        }
+
+        // the same file opened again is the same source
+        assert_eq!(FileDataSource::new("README.md").unwrap().id(), ds_arc2.id());
+        assert!(addr_space
+            .get_source_for_addr::<FileDataSource>(10 * address_space::PAGE_SIZE, read_flags)
+            .is_err());
+    }
+
+    #[test]
+    fn mappings_must_suit_their_source() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_only = Arc::new(FileDataSource::new("Cargo.toml").unwrap());
+        let shared_write = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+        let private_write = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        assert_eq!(
+            read_only.length(),
+            Some(std::fs::metadata("Cargo.toml").unwrap().len() as usize)
+        );
+        assert!(!read_only.capabilities().writable);
+
+        // a file opened read-only can only be written privately
+        assert!(addr_space
+            .add_mapping(read_only.clone(), 0, 1, shared_write)
+            .is_err());
+        let addr = addr_space
+            .add_mapping(read_only.clone(), 0, 1, private_write)
+            .unwrap();
+        assert!(addr_space.add_anonymous_mapping(1, shared_write).is_ok());
+
+        // and can't be mapped past its last page
+        let read_flags = FlagBuilder::new().toggle_read();
+        assert!(addr_space
+            .add_mapping(
+                read_only.clone(),
+                0,
+                2 * address_space::PAGE_SIZE,
+                read_flags
+            )
+            .is_err());
+        assert!(addr_space
+            .add_mapping(read_only.clone(), address_space::PAGE_SIZE, 1, read_flags)
+            .is_err());
+        assert!(addr_space
+            .add_mapping(read_only.clone(), usize::MAX, 1, read_flags)
+            .is_err());
+
+        // the source is only handed out as the type it is, and for accesses it allows
+        assert!(addr_space
+            .get_source_for_addr::<FileDataSource>(addr, read_flags)
+            .is_ok());
+        assert!(addr_space
+            .get_source_for_addr::<MemorySource>(addr, read_flags)
+            .is_err());
+
+        // a mapping is only removed by the source it maps
+        let other = Arc::new(FileDataSource::new("README.md").unwrap());
+        assert!(addr_space.remove_mapping(other, addr).is_err());
+        assert!(addr_space.remove_mapping(read_only, addr).is_ok());
+    }
+
+    #[test]
+    fn sources_only_allow_what_they_declare() {
+        /// A source that leaves `capabilities` alone.
+        struct Plain;
+        impl DataSource for Plain {
+            fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
+                buffer[..length].fill(1);
+                Ok(())
+            }
+            fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
+                Ok(())
+            }
+            fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+                Ok(())
+            }
+        }
+
+        let mut addr_space = AddressSpace::new("Test address space");
+        let plain = Arc::new(Plain);
+        let capabilities = plain.capabilities();
+        assert!(capabilities.readable);
+        assert!(!capabilities.writable && !capabilities.executable && !capabilities.resizable);
+
+        let read_flags = FlagBuilder::new().toggle_read();
+        let shared_write = read_flags.toggle_write().toggle_shared();
+        let exec_flags = read_flags.toggle_execute().toggle_private();
+        assert_eq!(
+            addr_space.add_mapping(plain.clone(), 0, 1, shared_write),
+            Err(NOT_PERMITTED)
+        );
+        assert_eq!(
+            addr_space.add_mapping(plain.clone(), 0, 1, exec_flags),
+            Err(NOT_PERMITTED)
+        );
+        let addr = addr_space
+            .add_mapping(plain, 0, 1, read_flags.toggle_private())
+            .unwrap();
+        let (_, offset) = addr_space
+            .get_source_for_addr::<Plain>(addr, read_flags)
+            .unwrap();
+        assert_eq!(offset, 0);
+        assert!(addr_space
+            .get_source_for_addr::<Plain>(addr, read_flags.toggle_execute())
+            .is_err());
+    }
+
+    #[test]
+    fn file_source_read() {
+        let data_source = FileDataSource::new("Cargo.toml").unwrap();
+        let mut buffer = [0xff; 16];
+        data_source.read(0, 9, &mut buffer).unwrap();
+        assert_eq!(&buffer[..9], b"[package]");
+
+        // reads past the end of the file come back as zeros
+        data_source.read(usize::MAX >> 2, 16, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; 16]);
+    }
+
+    #[test]
+    fn sequential_faults_widen_readahead() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(64));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 64 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        for page in 0..64 {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, read_flags)
+                .unwrap();
+        }
+        // the window doubles up to its maximum, and is clipped at the end of the mapping
+        assert_eq!(source.read_sizes(), vec![1, 2, 4, 8, 16, 32, 1]);
+        assert!(addr_space.is_resident(addr + 63 * address_space::PAGE_SIZE));
+    }
+
+    #[test]
+    fn random_faults_read_single_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(64));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 64 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        for page in [40, 3, 17, 60, 9] {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, read_flags)
+                .unwrap();
+        }
+        assert_eq!(source.read_sizes(), vec![1; 5]);
+        assert!(!addr_space.is_resident(addr + 41 * address_space::PAGE_SIZE));
+    }
+
+    #[test]
+    fn unused_readahead_shrinks_window() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(64));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 64 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        // pages 0..=6 get fetched in windows of 1, 2 and 4, but 4, 5 and 6 are never touched
+        for page in [0, 1, 2, 3, 7] {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, read_flags)
+                .unwrap();
+        }
+        assert_eq!(source.read_sizes(), vec![1, 2, 4, 2]);
+    }
+
+    #[test]
+    fn fault_checks_permissions() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(1));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source, 0, address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert!(addr_space.fault(addr, FlagBuilder::write()).is_err());
+        assert!(addr_space
+            .fault(addr + address_space::PAGE_SIZE, read_flags)
+            .is_err());
+        assert!(!addr_space.is_resident(addr));
+    }
+
+    #[test]
+    fn advise_sequential_and_random() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(64));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 64 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        addr_space
+            .advise(addr, 32 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        addr_space
+            .advise(
+                addr + 32 * address_space::PAGE_SIZE,
+                32 * address_space::PAGE_SIZE,
+                Advice::Sequential,
+            )
+            .unwrap();
+        for page in 0..4 {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, read_flags)
+                .unwrap();
+        }
+        addr_space
+            .fault(addr + 32 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert_eq!(source.read_sizes(), vec![1, 1, 1, 1, 32]);
+    }
+
+    #[test]
+    fn advise_willneed_and_dontneed() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(16));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 16 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        addr_space
+            .fault(addr + 8 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        addr_space
+            .advise(
+                addr + 4 * address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                Advice::WillNeed,
+            )
+            .unwrap();
+        // one read on either side of the page that was already resident
+        assert_eq!(source.read_sizes(), vec![1, 4, 3]);
+        assert!(!addr_space.is_resident(addr + 3 * address_space::PAGE_SIZE));
+        assert!(addr_space.is_resident(addr + 11 * address_space::PAGE_SIZE));
+        assert!(!addr_space.is_resident(addr + 12 * address_space::PAGE_SIZE));
+
+        addr_space
+            .advise(addr + 6 * address_space::PAGE_SIZE, 1, Advice::DontNeed)
+            .unwrap();
+        assert!(!addr_space.is_resident(addr + 6 * address_space::PAGE_SIZE));
+        assert!(addr_space.is_resident(addr + 5 * address_space::PAGE_SIZE));
+        assert!(addr_space.is_resident(addr + 7 * address_space::PAGE_SIZE));
+    }
+
+    #[test]
+    fn advise_needs_mapped_range() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        let addr2 = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        // the guard page between the two mappings isn't mapped
+        assert!(addr_space
+            .advise(
+                addr,
+                addr2 + address_space::PAGE_SIZE - addr,
+                Advice::Random
+            )
+            .is_err());
+
+        let file = Arc::new(MemorySource::new(1));
+        let addr3 = addr_space
+            .add_mapping(file, 0, address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert!(addr_space
+            .advise(addr3, address_space::PAGE_SIZE, Advice::Free)
+            .is_err());
+    }
+
+    #[test]
+    fn advise_free_reclaims_clean_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        let addr = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        for page in 0..4 {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, FlagBuilder::write())
+                .unwrap();
+        }
+        addr_space
+            .advise(addr, 4 * address_space::PAGE_SIZE, Advice::Free)
+            .unwrap();
+        // writing again after the advice means the page has to be kept
+        addr_space
+            .fault(addr + 2 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .unwrap();
+
+        assert_eq!(addr_space.reclaim_lazy_free(), 3);
+        assert!(addr_space.is_resident(addr + 2 * address_space::PAGE_SIZE));
+        assert!(!addr_space.is_resident(addr));
+    }
+
+    #[test]
+    fn locked_pages_are_not_evicted() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(8));
+        let read_flags = FlagBuilder::new().toggle_read();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 8 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        addr_space
+            .lock_range(
+                addr + 2 * address_space::PAGE_SIZE,
+                4 * address_space::PAGE_SIZE,
+            )
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 4);
+        assert_eq!(source.read_sizes(), vec![4]);
+        assert!(addr_space
+            .advise(addr, 3 * address_space::PAGE_SIZE, Advice::DontNeed)
+            .is_err());
+
+        addr_space.fault(addr, read_flags).unwrap();
+        assert_eq!(addr_space.evict_clean_pages(usize::MAX), 1);
+        assert!(addr_space.is_resident(addr + 2 * address_space::PAGE_SIZE));
+        assert!(addr_space.is_resident(addr + 5 * address_space::PAGE_SIZE));
+
+        addr_space
+            .unlock_range(
+                addr + 2 * address_space::PAGE_SIZE,
+                address_space::PAGE_SIZE,
+            )
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 3);
+        assert_eq!(addr_space.evict_clean_pages(usize::MAX), 1);
+        assert!(!addr_space.is_resident(addr + 2 * address_space::PAGE_SIZE));
+    }
+
+    #[test]
+    fn lock_limit_is_enforced() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_flags = FlagBuilder::new().toggle_read();
+        addr_space.set_lock_limit(3 * address_space::PAGE_SIZE);
+
+        let addr = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert!(addr_space
+            .lock_range(addr, 4 * address_space::PAGE_SIZE)
+            .is_err());
+        assert_eq!(addr_space.locked_pages(), 0);
+        addr_space
+            .lock_range(addr, 3 * address_space::PAGE_SIZE)
+            .unwrap();
+        // locking pages that are already locked doesn't count twice
+        addr_space
+            .lock_range(addr, 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 3);
+    }
+
+    #[test]
+    fn lock_all_future_locks_new_mappings() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_flags = FlagBuilder::new().toggle_read();
+        addr_space.set_lock_limit(4 * address_space::PAGE_SIZE);
+
+        let addr = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        addr_space.lock_all(true).unwrap();
+        let addr2 = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 3);
+        assert!(addr_space.is_resident(addr2 + address_space::PAGE_SIZE));
+
+        // a mapping that won't fit under the limit isn't added at all
+        assert!(addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, read_flags)
+            .is_err());
+        assert_eq!(addr_space.locked_pages(), 3);
+
+        addr_space.unlock_all();
+        let addr3 = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+        assert!(!addr_space.is_resident(addr3));
+        assert_eq!(addr_space.locked_pages(), 0);
+    }
+
+    #[test]
+    fn file_source_write_and_flush() {
+        let path = std::env::temp_dir().join(format!("reedos_write_{}", std::process::id()));
+        std::fs::write(&path, [0; 8]).unwrap();
+        let data_source = FileDataSource::new_writable(path.to_str().unwrap()).unwrap();
+        data_source.write(2, 3, b"abcdef").unwrap();
+        data_source.flush(0, 8).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0\0abc\0\0\0");
+        std::fs::remove_file(&path).unwrap();
+
+        let read_only = FileDataSource::new("Cargo.toml").unwrap();
+        assert!(read_only.write(0, 1, b"x").is_err());
+    }
+
+    #[test]
+    fn file_source_pages_are_scattered_and_gathered() {
+        let path = std::env::temp_dir().join(format!("reedos_vectored_{}", std::process::id()));
+        std::fs::write(&path, [0; address_space::PAGE_SIZE + 4]).unwrap();
+        let data_source = FileDataSource::new_writable(path.to_str().unwrap()).unwrap();
+        let first = [1; address_space::PAGE_SIZE];
+        let second = [2; address_space::PAGE_SIZE];
+        data_source.write_pages(1, &[&first, &second]).unwrap();
+
+        // the frames needn't be next to each other, and anything past the end reads as zeros
+        let mut frames = vec![vec![0xff; address_space::PAGE_SIZE]; 4];
+        let [zero, _, two, three] = &mut frames[..] else {
+            unreachable!()
+        };
+        data_source.read_pages(1, &mut [three, zero, two]).unwrap();
+        assert!(frames[3].iter().all(|&byte| byte == 1));
+        assert!(frames[0].iter().all(|&byte| byte == 2));
+        assert!(frames[2].iter().all(|&byte| byte == 0));
+        assert!(frames[1].iter().all(|&byte| byte == 0xff));
+
+        let mut short = [0; 16];
+        assert!(data_source.read_pages(0, &mut [&mut short]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_back_stops_at_the_end_of_the_file() {
+        let path = std::env::temp_dir().join(format!("reedos_write_back_{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let data_source = Arc::new(FileDataSource::new_writable(path.to_str().unwrap()).unwrap());
+        let mut addr_space = AddressSpace::new("Test address space");
+        let shared_flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+
+        let addr = addr_space
+            .add_mapping(data_source, 0, 11, shared_flags)
+            .unwrap();
+        addr_space.write_bytes(addr, b"J").unwrap();
+        addr_space
+            .sync_range(addr, address_space::PAGE_SIZE, SyncMode::Sync)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"Jello world");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sync_writes_back_dirty_shared_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let shared = Arc::new(MemorySource::new(8));
+        let private = Arc::new(MemorySource::new(8));
+        let shared_flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+        let private_flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        let addr = addr_space
+            .add_mapping(
+                shared.clone(),
+                2 * address_space::PAGE_SIZE,
+                6 * address_space::PAGE_SIZE,
+                shared_flags,
+            )
+            .unwrap();
+        let addr2 = addr_space
+            .add_mapping(
+                private.clone(),
+                0,
+                8 * address_space::PAGE_SIZE,
+                private_flags,
+            )
+            .unwrap();
+        for page in [0, 1, 2, 4] {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, FlagBuilder::write())
+                .unwrap();
+        }
+        addr_space.fault(addr2, FlagBuilder::write()).unwrap();
+
+        addr_space
+            .sync_range(addr, 4 * address_space::PAGE_SIZE, SyncMode::Async)
+            .unwrap();
+        // pages 0..=2 of the mapping sit at pages 2..=4 of the source; page 4 is outside the range
+        assert_eq!(shared.written_pages(), vec![(2, 3)]);
+        assert_eq!(*shared.flushes.lock().unwrap(), 0);
+
+        addr_space
+            .sync_range(addr, 6 * address_space::PAGE_SIZE, SyncMode::Sync)
+            .unwrap();
+        assert_eq!(shared.written_pages(), vec![(2, 3), (6, 1)]);
+        assert_eq!(*shared.flushes.lock().unwrap(), 1);
+
+        // private mappings never write back
+        let result = addr_space.sync_range(
+            addr,
+            addr2 + address_space::PAGE_SIZE - addr,
+            SyncMode::Sync,
+        );
+        assert_eq!(result, Err("Range is not entirely mapped."));
+        assert!(private.written_pages().is_empty());
+    }
+
+    #[test]
+    fn sync_reports_first_error_and_keeps_going() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let failing = Arc::new(MemorySource {
+            fail_writes: true,
+            ..MemorySource::new(2)
+        });
+        let working = Arc::new(MemorySource::new(2));
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+
+        let addr = addr_space
+            .add_mapping(failing.clone(), 0, 2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let addr2 = addr_space
+            .add_mapping(working.clone(), 0, 2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        for page in 0..2 {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, FlagBuilder::write())
+                .unwrap();
+            addr_space
+                .fault(
+                    addr2 + page * address_space::PAGE_SIZE,
+                    FlagBuilder::write(),
+                )
+                .unwrap();
+        }
+
+        // the guard page between the mappings is unmapped, but the write error is what's reported
+        let result = addr_space.sync_range(
+            addr,
+            addr2 + 2 * address_space::PAGE_SIZE - addr,
+            SyncMode::Invalidate,
+        );
+        assert_eq!(result, Err("MemorySource write failed"));
+        assert_eq!(working.written_pages(), vec![(0, 2)]);
+        // the pages that couldn't be written back are still dirty, so they're kept
+        assert!(addr_space.is_resident(addr));
+        assert!(!addr_space.is_resident(addr2));
+    }
+
+    #[test]
+    fn read_and_write_bytes_cross_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let source = Arc::new(MemorySource::new(4));
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+
+        let addr = addr_space
+            .add_mapping(source.clone(), 0, 4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let mut buffer = [0xff; 4];
+        addr_space
+            .read_bytes(addr + address_space::PAGE_SIZE - 2, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, [0, 0, 1, 1]);
+
+        addr_space
+            .write_bytes(addr + 2 * address_space::PAGE_SIZE - 1, b"hello")
+            .unwrap();
+        addr_space
+            .read_bytes(addr + 2 * address_space::PAGE_SIZE - 2, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer, b"\x01hel");
+
+        addr_space
+            .sync_range(addr, 4 * address_space::PAGE_SIZE, SyncMode::Sync)
+            .unwrap();
+        let data = source.data.lock().unwrap();
+        assert_eq!(
+            &data[2 * address_space::PAGE_SIZE - 1..2 * address_space::PAGE_SIZE + 4],
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn byte_copies_report_progress_before_fault() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_flags = FlagBuilder::new().toggle_read();
+        let write_flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        let addr = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, write_flags)
+            .unwrap();
+        let addr2 = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, read_flags)
+            .unwrap();
+
+        // the copy runs off the end of the mapping into the guard page
+        let mut buffer = [0; 16];
+        let result = addr_space.read_bytes(addr + address_space::PAGE_SIZE - 10, &mut buffer);
+        assert_eq!(result, Err((10, "No mapping with target address.")));
+
+        let result = addr_space.write_bytes(addr2, b"nope");
+        assert_eq!(result.map_err(|(copied, _)| copied), Err(0));
+    }
+
+    #[test]
+    fn dontneed_anonymous_reads_back_zeros() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        let addr = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space.write_bytes(addr + 100, b"secret").unwrap();
+        addr_space
+            .advise(addr, address_space::PAGE_SIZE, Advice::DontNeed)
+            .unwrap();
+
+        let mut buffer = [0xff; 6];
+        addr_space.read_bytes(addr + 100, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; 6]);
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks_heap() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let heap = 16 * address_space::PAGE_SIZE + 100;
+        assert!(addr_space.set_brk(heap).is_err());
+        addr_space.init_brk(heap);
+        assert!(addr_space.set_brk(heap - 1).is_err());
+
+        // the first page of the heap is the rest of the page the initial break is in
+        assert_eq!(addr_space.set_brk(heap + 10), Ok(heap + 10));
+        assert!(!addr_space.is_mapped(heap, 1));
+        assert_eq!(
+            addr_space.set_brk(heap + 2 * address_space::PAGE_SIZE),
+            Ok(heap + 2 * address_space::PAGE_SIZE)
+        );
+        assert!(addr_space.is_mapped(17 * address_space::PAGE_SIZE, 2 * address_space::PAGE_SIZE));
+        addr_space
+            .write_bytes(18 * address_space::PAGE_SIZE, b"heap")
+            .unwrap();
+
+        addr_space.set_brk(heap + address_space::PAGE_SIZE).unwrap();
+        assert!(!addr_space.is_mapped(18 * address_space::PAGE_SIZE, 1));
+        addr_space
+            .set_brk(heap + 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        let mut buffer = [0xff; 4];
+        addr_space
+            .read_bytes(18 * address_space::PAGE_SIZE, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, [0; 4]);
+        assert_eq!(addr_space.brk_start(), heap);
+    }
+
+    #[test]
+    fn brk_locks_new_heap_pages_under_lock_all() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        addr_space.set_lock_limit(3 * address_space::PAGE_SIZE);
+        let heap = 16 * address_space::PAGE_SIZE;
+        addr_space.init_brk(heap);
+        addr_space.lock_all(true).unwrap();
+
+        addr_space
+            .set_brk(heap + 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 2);
+        assert!(addr_space.is_resident(heap + address_space::PAGE_SIZE));
+
+        // growing the locked heap locks the new pages too, up to the limit
+        addr_space
+            .set_brk(heap + 3 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 3);
+        assert!(addr_space.is_resident(heap + 2 * address_space::PAGE_SIZE));
+        assert_eq!(
+            addr_space.set_brk(heap + 4 * address_space::PAGE_SIZE),
+            Err(limits::MEMLOCK_LIMIT)
+        );
+        assert_eq!(addr_space.brk(), heap + 3 * address_space::PAGE_SIZE);
+        assert!(!addr_space.is_mapped(heap + 3 * address_space::PAGE_SIZE, 1));
+        assert_eq!(addr_space.locked_pages(), 3);
+    }
+
+    #[test]
+    fn brk_stops_at_guard_page() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let read_flags = FlagBuilder::new().toggle_read();
+        addr_space.init_brk(4 * address_space::PAGE_SIZE);
+        addr_space
+            .add_mapping_at(
+                Arc::new(AnonymousDataSource),
+                0,
+                address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                read_flags,
+            )
+            .unwrap();
+
+        assert!(addr_space
+            .set_brk(7 * address_space::PAGE_SIZE + 1)
+            .is_err());
+        assert_eq!(addr_space.brk(), 4 * address_space::PAGE_SIZE);
+        addr_space.set_brk(7 * address_space::PAGE_SIZE).unwrap();
+        assert!(addr_space.is_mapped(4 * address_space::PAGE_SIZE, 3 * address_space::PAGE_SIZE));
+    }
+
+    #[test]
+    fn stack_grows_down_on_fault() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let top = 0x100_0000;
+
+        let bottom = addr_space
+            .add_stack_at(
+                top,
+                2 * address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        assert_eq!(bottom, top - 2 * address_space::PAGE_SIZE);
+        addr_space.write_bytes(top - 4, b"argc").unwrap();
+
+        // a push that runs off the bottom of the stack grows it
+        addr_space
+            .write_bytes(bottom - 10, b"0123456789ab")
+            .unwrap();
+        assert!(addr_space.is_mapped(
+            bottom - address_space::PAGE_SIZE,
+            3 * address_space::PAGE_SIZE
+        ));
+        let mut buffer = [0; 4];
+        addr_space.read_bytes(top - 4, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"argc");
+
+        addr_space
+            .fault(top - 8 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .unwrap();
+        assert!(addr_space
+            .fault(top - 8 * address_space::PAGE_SIZE - 1, FlagBuilder::write())
+            .is_err());
+        assert!(!addr_space.is_mapped(top - 9 * address_space::PAGE_SIZE, 1));
+    }
+
+    #[test]
+    fn locked_stack_growth_is_locked_within_the_limit() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let top = 0x100_0000;
+        addr_space.set_lock_limit(3 * address_space::PAGE_SIZE);
+        let bottom = addr_space
+            .add_stack_at(
+                top,
+                address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        addr_space.lock_all(true).unwrap();
+        assert_eq!(addr_space.locked_pages(), 1);
+
+        // every page the stack grows by is faulted in, not just the one that faulted
+        addr_space
+            .fault(bottom - 2 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .unwrap();
+        assert_eq!(addr_space.locked_pages(), 3);
+        assert!(addr_space.is_resident(bottom - address_space::PAGE_SIZE));
+
+        // and it doesn't grow past the lock limit
+        let below = bottom - 3 * address_space::PAGE_SIZE;
+        assert_eq!(
+            addr_space.fault(below, FlagBuilder::write()),
+            Err(limits::MEMLOCK_LIMIT)
+        );
+        assert!(!addr_space.is_mapped(below, 1));
+        assert_eq!(addr_space.locked_pages(), 3);
+        addr_space
+            .write_bytes(bottom - 2 * address_space::PAGE_SIZE, b"ok")
+            .unwrap();
+    }
+
+    #[test]
+    fn only_replacing_mappings_may_touch_their_neighbours() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let anon = Arc::new(AnonymousDataSource);
+        let page = |i: usize| i * address_space::PAGE_SIZE;
+        addr_space
+            .add_mapping_at(anon.clone(), 0, page(1), page(4), flags)
+            .unwrap();
+
+        // add_mapping_at keeps free pages between mappings, as it always has
+        assert!(addr_space
+            .add_mapping_at(anon.clone(), 0, page(1), page(5), flags)
+            .is_err());
+        assert!(addr_space
+            .add_mapping_at(anon.clone(), 0, page(1), page(6), flags)
+            .is_err());
+        assert!(addr_space
+            .add_mapping_at(anon.clone(), 0, page(1), page(3), flags)
+            .is_err());
+        addr_space
+            .add_mapping_at(anon.clone(), 0, page(1), page(7), flags)
+            .unwrap();
+
+        // MAP_FIXED placement doesn't
+        addr_space
+            .replace_mapping_at(anon.clone(), 0, page(1), page(5), flags)
+            .unwrap();
+        addr_space
+            .replace_mapping_at(anon, 0, page(1), page(3), flags)
+            .unwrap();
+        assert!(addr_space.is_mapped(page(3), page(3)));
+    }
+
+    #[test]
+    fn stack_guard_gap_is_kept_free() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let top = 0x100_0000;
+        let max = 16 * address_space::PAGE_SIZE;
+
+        let bottom = addr_space
+            .add_stack_at(top, address_space::PAGE_SIZE, max, flags)
+            .unwrap();
+        let in_gap = bottom - address_space::STACK_GUARD_GAP + address_space::PAGE_SIZE;
+        let anon = Arc::new(AnonymousDataSource);
+        assert!(addr_space
+            .add_mapping_at(anon.clone(), 0, address_space::PAGE_SIZE, in_gap, flags)
+            .is_err());
+
+        // a mapping placed just below the gap stops the stack growing any further
+        let below = bottom - address_space::STACK_GUARD_GAP - address_space::PAGE_SIZE;
+        addr_space
+            .add_mapping_at(anon, 0, address_space::PAGE_SIZE, below, flags)
+            .unwrap();
+        assert!(addr_space.fault(bottom - 1, FlagBuilder::write()).is_err());
+
+        // automatic placement skips the gap too
+        let addr = addr_space
+            .add_anonymous_mapping(address_space::PAGE_SIZE, flags)
+            .unwrap();
+        assert!(addr >= top || addr + 2 * address_space::PAGE_SIZE <= below);
+    }
+
+    #[test]
+    fn thread_stacks_have_their_own_limits() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        let main = addr_space
+            .add_stack(
+                address_space::PAGE_SIZE,
+                64 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        let thread = addr_space
+            .add_stack(
+                address_space::PAGE_SIZE,
+                2 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        assert!(thread > main);
+
+        addr_space
+            .fault(main - 32 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .unwrap();
+        addr_space.fault(thread - 1, FlagBuilder::write()).unwrap();
+        assert!(addr_space
+            .fault(thread - address_space::PAGE_SIZE - 1, FlagBuilder::write())
+            .is_err());
+    }
+
+    /// A `TlbInvalidator` that records every call it gets.
+    #[derive(Default)]
+    struct RecordingInvalidator {
+        calls: Mutex<Vec<(usize, Vec<std::ops::Range<usize>>)>>,
+    }
+
+    impl TlbInvalidator for RecordingInvalidator {
+        fn invalidate(&self, asid: usize, ranges: &[std::ops::Range<usize>]) {
+            self.calls.lock().unwrap().push((asid, ranges.to_vec()));
         }
     }
+
+    #[test]
+    fn unmapping_invalidates_resident_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let tlb = Arc::new(RecordingInvalidator::default());
+        addr_space.set_tlb_invalidator(7, tlb.clone());
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let touched = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let untouched = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space.fault(touched, FlagBuilder::write()).unwrap();
+
+        // nothing was ever translated in the untouched mapping, so there's nothing to invalidate
+        addr_space
+            .remove_range(untouched, 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert!(tlb.calls.lock().unwrap().is_empty());
+
+        addr_space
+            .remove_range(touched, 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        let whole = touched..touched + 2 * address_space::PAGE_SIZE;
+        assert_eq!(*tlb.calls.lock().unwrap(), vec![(7, vec![whole])]);
+        assert_eq!(addr_space.pending_tlb_pages(), 0);
+    }
+
+    #[test]
+    fn batched_invalidations_hold_freed_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let tlb = Arc::new(RecordingInvalidator::default());
+        addr_space.set_tlb_invalidator(3, tlb.clone());
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        for page in 0..4 {
+            addr_space
+                .fault(addr + page * address_space::PAGE_SIZE, FlagBuilder::write())
+                .unwrap();
+        }
+
+        addr_space.begin_tlb_batch();
+        addr_space
+            .remove_range(
+                addr + 3 * address_space::PAGE_SIZE,
+                address_space::PAGE_SIZE,
+            )
+            .unwrap();
+        addr_space
+            .advise(
+                addr + 2 * address_space::PAGE_SIZE,
+                address_space::PAGE_SIZE,
+                Advice::DontNeed,
+            )
+            .unwrap();
+        addr_space
+            .protect(addr, address_space::PAGE_SIZE, FlagBuilder::read())
+            .unwrap();
+        // adding a permission takes nothing away
+        addr_space
+            .protect(
+                addr + address_space::PAGE_SIZE,
+                address_space::PAGE_SIZE,
+                FlagBuilder::read().toggle_write().toggle_execute(),
+            )
+            .unwrap();
+        assert!(tlb.calls.lock().unwrap().is_empty());
+        assert_eq!(addr_space.pending_tlb_pages(), 2);
+
+        addr_space.flush_tlb();
+        assert_eq!(
+            *tlb.calls.lock().unwrap(),
+            vec![(
+                3,
+                vec![
+                    addr..addr + address_space::PAGE_SIZE,
+                    addr + 2 * address_space::PAGE_SIZE..addr + 4 * address_space::PAGE_SIZE
+                ]
+            )]
+        );
+        assert_eq!(addr_space.pending_tlb_pages(), 0);
+    }
+
+    #[test]
+    fn usage_follows_mappings_and_faults() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let file = Arc::new(MemorySource::new(8));
+        let mapped = addr_space
+            .add_mapping(file.clone(), 0, 8 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let anon = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let shared = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_shared();
+        let shm = addr_space
+            .add_mapping(
+                Arc::new(AnonymousDataSource),
+                0,
+                address_space::PAGE_SIZE,
+                shared,
+            )
+            .unwrap();
+
+        addr_space.fault(mapped, FlagBuilder::read()).unwrap();
+        addr_space.fault(anon, FlagBuilder::write()).unwrap();
+        addr_space.fault(shm, FlagBuilder::write()).unwrap();
+        addr_space
+            .lock_range(anon, address_space::PAGE_SIZE)
+            .unwrap();
+        let usage = addr_space.usage();
+        assert_eq!(usage.virtual_pages, 11);
+        assert_eq!(
+            (
+                usage.resident_file,
+                usage.resident_anon,
+                usage.resident_shared
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(usage.locked, 1);
+        // the root, one middle table and one leaf table cover everything so far
+        assert_eq!(usage.page_tables, 3);
+
+        addr_space
+            .remove_range(mapped, 8 * address_space::PAGE_SIZE)
+            .unwrap();
+        addr_space.evict_clean_pages(usize::MAX);
+        let usage = addr_space.usage();
+        assert_eq!(usage.virtual_pages, 3);
+        assert_eq!(usage.resident(), 2);
+        assert_eq!((usage.peak_virtual, usage.peak_resident), (11, 3));
+    }
+
+    #[test]
+    fn page_tables_follow_resident_pages() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let leaf_span = 512 * address_space::PAGE_SIZE;
+        let addr = addr_space
+            .add_anonymous_mapping(2 * leaf_span, flags)
+            .unwrap();
+        assert_eq!(addr_space.usage().page_tables, 1);
+
+        addr_space.fault(addr, FlagBuilder::write()).unwrap();
+        addr_space
+            .fault(addr + leaf_span, FlagBuilder::write())
+            .unwrap();
+        assert_eq!(addr_space.usage().page_tables, 4);
+
+        addr_space
+            .advise(addr + leaf_span, address_space::PAGE_SIZE, Advice::DontNeed)
+            .unwrap();
+        assert_eq!(addr_space.usage().page_tables, 3);
+    }
+
+    #[test]
+    fn smaps_breaks_usage_down_by_mapping() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let file = Arc::new(MemorySource::new(4));
+        let mapped = addr_space
+            .add_mapping(
+                file,
+                address_space::PAGE_SIZE,
+                3 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        let anon = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space.fault(mapped, FlagBuilder::read()).unwrap();
+        addr_space.fault(anon, FlagBuilder::write()).unwrap();
+        addr_space
+            .lock_range(anon, 2 * address_space::PAGE_SIZE)
+            .unwrap();
+
+        let smaps = addr_space.smaps();
+        assert_eq!(smaps.len(), 2);
+        assert_eq!(
+            (smaps[0].start, smaps[0].end),
+            (mapped, mapped + 3 * address_space::PAGE_SIZE)
+        );
+        assert_eq!(smaps[0].offset, address_space::PAGE_SIZE);
+        assert!(!smaps[0].anonymous);
+        assert_eq!(
+            (
+                smaps[0].size,
+                smaps[0].resident,
+                smaps[0].dirty,
+                smaps[0].locked
+            ),
+            (3, 1, 0, 0)
+        );
+        assert!(smaps[1].anonymous);
+        assert_eq!(
+            (
+                smaps[1].size,
+                smaps[1].resident,
+                smaps[1].dirty,
+                smaps[1].locked
+            ),
+            (2, 2, 1, 2)
+        );
+    }
+
+    #[test]
+    fn limits_stop_growth() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        addr_space.set_limits(Limits {
+            address_space: 16 * address_space::PAGE_SIZE,
+            data: 6 * address_space::PAGE_SIZE,
+            stack: 4 * address_space::PAGE_SIZE,
+            ..Limits::default()
+        });
+
+        let data = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_anonymous_mapping(3 * address_space::PAGE_SIZE, flags),
+            Err(limits::DATA_LIMIT)
+        );
+        let read_only = FlagBuilder::new().toggle_read().toggle_private();
+        let text = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, read_only)
+            .unwrap();
+        assert_eq!(
+            addr_space.protect(text, address_space::PAGE_SIZE * 3, flags),
+            Err(limits::DATA_LIMIT)
+        );
+
+        addr_space.init_brk(0x10_0000);
+        addr_space
+            .set_brk(0x10_0000 + 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(
+            addr_space.set_brk(0x10_0000 + 3 * address_space::PAGE_SIZE),
+            Err(limits::DATA_LIMIT)
+        );
+        assert_eq!(addr_space.brk(), 0x10_0000 + 2 * address_space::PAGE_SIZE);
+
+        let top = 0x100_0000;
+        assert_eq!(
+            addr_space.add_stack_at(
+                top,
+                5 * address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                flags
+            ),
+            Err(limits::STACK_LIMIT)
+        );
+        addr_space
+            .add_stack_at(
+                top,
+                address_space::PAGE_SIZE,
+                8 * address_space::PAGE_SIZE,
+                flags,
+            )
+            .unwrap();
+        addr_space
+            .fault(top - 4 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .unwrap();
+        assert!(addr_space
+            .fault(top - 5 * address_space::PAGE_SIZE, FlagBuilder::write())
+            .is_err());
+
+        // 4 data + 4 text + 2 heap + 4 stack leaves room for two more pages
+        assert_eq!(
+            addr_space.add_anonymous_mapping(3 * address_space::PAGE_SIZE, read_only),
+            Err(limits::ADDRESS_SPACE_LIMIT)
+        );
+        addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, read_only)
+            .unwrap();
+        addr_space
+            .remove_range(data, 4 * address_space::PAGE_SIZE)
+            .unwrap();
+        addr_space
+            .add_anonymous_mapping(3 * address_space::PAGE_SIZE, read_only)
+            .unwrap();
+    }
+
+    #[test]
+    fn map_count_limit_covers_splits() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        addr_space.set_limits(Limits {
+            max_map_count: 2,
+            ..Limits::default()
+        });
+        let addr = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+
+        // a hole in the middle makes two mappings out of one, which is fine...
+        addr_space
+            .remove_range(addr + address_space::PAGE_SIZE, address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_anonymous_mapping(address_space::PAGE_SIZE, flags),
+            Err(limits::MAP_COUNT_LIMIT)
+        );
+        // ...but splitting again isn't
+        let tail = addr + 2 * address_space::PAGE_SIZE;
+        assert_eq!(
+            addr_space.protect(tail, address_space::PAGE_SIZE, FlagBuilder::read()),
+            Err(limits::MAP_COUNT_LIMIT)
+        );
+        assert_eq!(
+            addr_space.remove_range(tail + address_space::PAGE_SIZE, 1),
+            Ok(())
+        );
+        assert_eq!(addr_space.smaps().len(), 2);
+    }
+
+    #[test]
+    fn commit_charges_are_shared_and_released() {
+        let commit = Arc::new(CommitAccountant::new(8, Overcommit::Never));
+        let mut first = AddressSpace::new("first");
+        let mut second = AddressSpace::new("second");
+        first.set_commit_accountant(commit.clone());
+        second.set_commit_accountant(commit.clone());
+        let private = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let read_only = FlagBuilder::new().toggle_read().toggle_private();
+        let file = Arc::new(MemorySource::new(8));
+
+        let anon = first
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        // a read-only file mapping can always be read back, so it costs nothing
+        let text = second
+            .add_mapping(file, 0, 8 * address_space::PAGE_SIZE, read_only)
+            .unwrap();
+        second
+            .add_anonymous_mapping(3 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        assert_eq!(commit.committed(), 7);
+        assert_eq!(
+            second.add_anonymous_mapping(2 * address_space::PAGE_SIZE, private),
+            Err(commit::COMMIT_LIMIT)
+        );
+        assert_eq!(
+            second.protect(text, 2 * address_space::PAGE_SIZE, private),
+            Err(commit::COMMIT_LIMIT)
+        );
+        second
+            .protect(text, address_space::PAGE_SIZE, private)
+            .unwrap();
+        assert_eq!(commit.committed(), 8);
+
+        first
+            .remove_range(anon, 2 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(commit.committed(), 6);
+        drop(first);
+        assert_eq!(commit.committed(), 4);
+        second
+            .remove_range(text, 8 * address_space::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(commit.committed(), 3);
+    }
+
+    #[test]
+    fn overcommit_policies() {
+        let commit = Arc::new(CommitAccountant::new(4, Overcommit::Heuristic));
+        let mut addr_space = AddressSpace::new("Test address space");
+        addr_space.set_commit_accountant(commit.clone());
+        let private = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+
+        // heuristic mode only refuses a single mapping that could never fit
+        addr_space
+            .add_anonymous_mapping(3 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        addr_space
+            .add_anonymous_mapping(3 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_anonymous_mapping(5 * address_space::PAGE_SIZE, private),
+            Err(commit::COMMIT_LIMIT)
+        );
+
+        commit.set_policy(Overcommit::Always);
+        addr_space
+            .add_anonymous_mapping(5 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        assert_eq!(commit.committed(), 11);
+
+        // no-reserve mappings are only charged when overcommit is off
+        let noreserve = private.toggle_noreserve();
+        commit.set_policy(Overcommit::Never);
+        assert_eq!(
+            addr_space.add_anonymous_mapping(address_space::PAGE_SIZE, noreserve),
+            Err(commit::COMMIT_LIMIT)
+        );
+        commit.set_policy(Overcommit::Heuristic);
+        addr_space
+            .add_anonymous_mapping(64 * address_space::PAGE_SIZE, noreserve)
+            .unwrap();
+        assert_eq!(commit.committed(), 11);
+
+        // including when mprotect makes a private file mapping writable
+        commit.set_policy(Overcommit::Never);
+        let read_only = FlagBuilder::new()
+            .toggle_read()
+            .toggle_private()
+            .toggle_noreserve();
+        let file = addr_space
+            .add_mapping(
+                Arc::new(MemorySource::new(2)),
+                0,
+                2 * address_space::PAGE_SIZE,
+                read_only,
+            )
+            .unwrap();
+        assert_eq!(
+            addr_space.protect(file, 2 * address_space::PAGE_SIZE, private),
+            Err(commit::COMMIT_LIMIT)
+        );
+        commit.set_policy(Overcommit::Heuristic);
+        addr_space
+            .protect(file, 2 * address_space::PAGE_SIZE, private)
+            .unwrap();
+        assert_eq!(commit.committed(), 11);
+    }
+
+    #[test]
+    fn swapped_pages_come_back_from_the_swap_space_they_went_to() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let first = Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 2));
+        let second = Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 2));
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space
+            .advise(addr, 2 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        addr_space.write_bytes(addr, &[1]).unwrap();
+        addr_space.set_swap(first.clone());
+        assert_eq!(addr_space.swap_out(usize::MAX), 1);
+
+        // both pages land in slot 0, each of its own swap space
+        addr_space
+            .write_bytes(addr + address_space::PAGE_SIZE, &[2])
+            .unwrap();
+        addr_space.set_swap(second.clone());
+        assert_eq!(addr_space.swap_out(usize::MAX), 1);
+        assert_eq!((first.used_slots(), second.used_slots()), (1, 1));
+
+        let mut byte = [0];
+        addr_space.read_bytes(addr, &mut byte).unwrap();
+        assert_eq!(byte, [1]);
+        addr_space
+            .read_bytes(addr + address_space::PAGE_SIZE, &mut byte)
+            .unwrap();
+        assert_eq!(byte, [2]);
+        assert_eq!((first.used_slots(), second.used_slots()), (0, 0));
+    }
+
+    #[test]
+    fn swapped_pages_come_back_on_fault() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let swap = Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 2));
+        addr_space.set_swap(swap.clone());
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        for page in 0..3 {
+            addr_space
+                .write_bytes(addr + page * address_space::PAGE_SIZE, &[page as u8 + 1])
+                .unwrap();
+        }
+        addr_space
+            .fault(addr + 3 * address_space::PAGE_SIZE, FlagBuilder::read())
+            .unwrap();
+
+        // only dirty pages go to swap, and only as many as there are slots
+        assert_eq!(addr_space.swap_out(usize::MAX), 2);
+        assert_eq!(swap.used_slots(), 2);
+        assert_eq!(addr_space.usage().swapped, 2);
+        assert!(!addr_space.is_resident(addr));
+
+        let mut byte = [0];
+        addr_space
+            .read_bytes(addr + address_space::PAGE_SIZE, &mut byte)
+            .unwrap();
+        assert_eq!(byte, [2]);
+        assert_eq!(swap.used_slots(), 1);
+
+        // locking brings the rest back, and dropping discards what's in swap
+        addr_space
+            .lock_range(addr, address_space::PAGE_SIZE)
+            .unwrap();
+        addr_space.read_bytes(addr, &mut byte).unwrap();
+        assert_eq!(byte, [1]);
+        addr_space.unlock_all();
+        addr_space.swap_out(usize::MAX);
+        assert_eq!(swap.used_slots(), 2);
+        addr_space
+            .advise(addr, 4 * address_space::PAGE_SIZE, Advice::DontNeed)
+            .unwrap();
+        assert_eq!(swap.used_slots(), 0);
+        assert_eq!(addr_space.usage().swapped, 0);
+        addr_space.read_bytes(addr, &mut byte).unwrap();
+        assert_eq!(byte, [0]);
+    }
+
+    #[test]
+    fn forked_children_share_swap_slots() {
+        let mut parent = AddressSpace::new("parent");
+        let swap = Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 4));
+        parent.set_swap(swap.clone());
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = parent
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        parent.write_bytes(addr, b"swapped").unwrap();
+        parent
+            .write_bytes(addr + address_space::PAGE_SIZE, b"resident")
+            .unwrap();
+        assert_eq!(parent.swap_out(1), 1);
+
+        let mut child = parent.fork("child").unwrap();
+        assert_eq!(swap.used_slots(), 1);
+        assert_eq!(swap.references(0), 2);
+        assert_eq!(child.usage().swapped, 1);
+
+        // the child's copy is its own
+        child
+            .write_bytes(addr + address_space::PAGE_SIZE, b"RESIDENT")
+            .unwrap();
+        let mut buffer = [0; 8];
+        parent
+            .read_bytes(addr + address_space::PAGE_SIZE, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer, b"resident");
+
+        let mut buffer = [0; 7];
+        child.read_bytes(addr, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"swapped");
+        assert_eq!(swap.references(0), 1);
+        drop(child);
+        assert_eq!(swap.used_slots(), 1);
+        drop(parent);
+        assert_eq!(swap.used_slots(), 0);
+    }
+
+    #[test]
+    fn background_reclaim_runs_between_watermarks() {
+        let frames = Arc::new(CacheCoordinator::new(
+            8,
+            Watermarks {
+                min: 1,
+                low: 3,
+                high: 5,
+            },
+        ));
+        let mut first = AddressSpace::new("first");
+        let mut second = AddressSpace::new("second");
+        first.set_cache_coordinator(frames.clone());
+        second.set_cache_coordinator(frames.clone());
+        let flags = FlagBuilder::new().toggle_read().toggle_private();
+        let file = Arc::new(MemorySource::new(8));
+        let a = first
+            .add_mapping(file.clone(), 0, 4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let b = second
+            .add_mapping(file, 0, 4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        for page in 0..3 {
+            first
+                .fault(a + page * address_space::PAGE_SIZE, FlagBuilder::read())
+                .unwrap();
+            second
+                .fault(b + page * address_space::PAGE_SIZE, FlagBuilder::read())
+                .unwrap();
+        }
+        assert_eq!(frames.free_frames(), 2);
+        assert!(frames.needs_reclaim());
+
+        // touching a page again keeps it around for another pass
+        first.fault(a, FlagBuilder::read()).unwrap();
+        let evicted = frames.step(&mut [&mut first, &mut second], 2);
+        assert_eq!(evicted, 1);
+        assert!(first.is_resident(a));
+        assert!(!first.is_resident(a + address_space::PAGE_SIZE));
+        assert!(frames.needs_reclaim());
+
+        assert_eq!(frames.step(&mut [&mut first, &mut second], 16), 2);
+        assert_eq!(frames.free_frames(), 5);
+        assert!(!frames.needs_reclaim());
+        assert_eq!(frames.step(&mut [&mut first, &mut second], 16), 0);
+    }
+
+    #[test]
+    fn direct_reclaim_only_below_min() {
+        let frames = Arc::new(CacheCoordinator::new(
+            4,
+            Watermarks {
+                min: 1,
+                low: 2,
+                high: 3,
+            },
+        ));
+        let mut addr_space = AddressSpace::new("Test address space");
+        addr_space.set_cache_coordinator(frames.clone());
+        let swap = Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 4));
+        addr_space.set_swap(swap.clone());
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(6 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space
+            .advise(addr, 6 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+
+        for page in 0..4 {
+            addr_space
+                .write_bytes(addr + page * address_space::PAGE_SIZE, &[page as u8 + 1])
+                .unwrap();
+        }
+        // nothing was taken back until the last free frame went
+        assert_eq!(frames.free_frames(), 0);
+        assert_eq!(swap.used_slots(), 0);
+
+        // the next fault swaps the oldest page out to make room
+        addr_space
+            .write_bytes(addr + 4 * address_space::PAGE_SIZE, &[5])
+            .unwrap();
+        assert_eq!(swap.used_slots(), 1);
+        assert!(!addr_space.is_resident(addr));
+        let mut byte = [0];
+        addr_space.read_bytes(addr, &mut byte).unwrap();
+        assert_eq!(byte, [1]);
+    }
+
+    #[test]
+    fn memory_group_charges_and_hard_limit() {
+        let group = Arc::new(MemoryGroup::new(3, 3));
+        let mut first = AddressSpace::new("first");
+        let mut second = AddressSpace::new("second");
+        first.join_memory_group(group.clone());
+        second.join_memory_group(group.clone());
+        first.set_swap(Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 4)));
+
+        let file = second
+            .add_mapping(
+                Arc::new(MemorySource::new(2)),
+                0,
+                2 * address_space::PAGE_SIZE,
+                FlagBuilder::read(),
+            )
+            .unwrap();
+        second
+            .advise(file, 2 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        second.fault(file, FlagBuilder::read()).unwrap();
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let anon = first
+            .add_anonymous_mapping(4 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        first
+            .advise(anon, 4 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        for page in 0..3 {
+            first
+                .write_bytes(anon + page * address_space::PAGE_SIZE, &[1])
+                .unwrap();
+        }
+        // the third page didn't fit, so the faulting space swapped its oldest page out
+        assert!(!first.is_resident(anon));
+        assert_eq!(
+            group.stats(),
+            GroupStats {
+                anon: 2,
+                file: 1,
+                swap: 1,
+                peak: 3,
+                members: 2,
+                limit_hits: 1,
+                reclaimed: 1
+            }
+        );
+
+        // with nothing of its own to evict, the fault fails, even though the others have pages
+        let mut third = AddressSpace::new("third");
+        third.join_memory_group(group.clone());
+        let other = third
+            .add_anonymous_mapping(address_space::PAGE_SIZE, flags)
+            .unwrap();
+        assert_eq!(third.write_bytes(other, &[1]), Err((0, memcg::GROUP_OOM)));
+
+        // until the group is reclaimed from as a whole
+        group.set_hard_limit(2);
+        assert_eq!(
+            group.reclaim(&mut [&mut first, &mut second, &mut third], 16),
+            1
+        );
+        group.set_hard_limit(3);
+        assert_eq!(third.write_bytes(other, &[1]), Ok(()));
+
+        drop(first);
+        drop(second);
+        drop(third);
+        let stats = group.stats();
+        assert_eq!(
+            (stats.anon, stats.file, stats.swap, stats.members),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn groups_over_soft_limit_are_reclaimed_first() {
+        let frames = Arc::new(CacheCoordinator::new(
+            8,
+            Watermarks {
+                min: 1,
+                low: 3,
+                high: 4,
+            },
+        ));
+        let group = Arc::new(MemoryGroup::new(usize::MAX, 1));
+        let mut grouped = AddressSpace::new("grouped");
+        let mut other = AddressSpace::new("other");
+        grouped.join_memory_group(group.clone());
+        let file = Arc::new(MemorySource::new(3));
+        for space in [&mut other, &mut grouped] {
+            space.set_cache_coordinator(frames.clone());
+            let addr = space
+                .add_mapping(
+                    file.clone(),
+                    0,
+                    3 * address_space::PAGE_SIZE,
+                    FlagBuilder::read(),
+                )
+                .unwrap();
+            space
+                .advise(addr, 3 * address_space::PAGE_SIZE, Advice::Random)
+                .unwrap();
+            for page in 0..3 {
+                space
+                    .fault(addr + page * address_space::PAGE_SIZE, FlagBuilder::read())
+                    .unwrap();
+            }
+        }
+        assert!(frames.needs_reclaim());
+
+        assert_eq!(frames.step(&mut [&mut other, &mut grouped], 16), 2);
+        assert_eq!(group.resident(), 1);
+        assert_eq!(group.stats().reclaimed, 2);
+        assert_eq!(other.usage().resident(), 3);
+        assert!(!frames.needs_reclaim());
+    }
+
+    #[test]
+    fn frames_shared_across_threads_are_freed_once() {
+        use crate::physical::{PageData, Placement};
+        let memory = Arc::new(PhysicalMemory::new(2, Some(0xaa)));
+        let placement = Placement {
+            memory: &memory,
+            policy: MemoryPolicy::Local,
+            node: 0,
+        };
+        let page = PageData::new(Some(placement), b"shared").unwrap();
+        let frame = page.frame().unwrap();
+        assert_eq!(memory.fragmentation(0).free_blocks[..2], [1, 0]);
+
+        // copies come and go on every thread while the first one keeps the frame alive
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let page = page.clone();
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        drop(page.clone());
+                    }
+                });
+            }
+        });
+        assert_eq!(memory.references(frame), 1);
+        let mut bytes = [0; 6];
+        memory.read(frame.addr(), &mut bytes).unwrap();
+        assert_eq!(&bytes, b"shared");
+
+        drop(page);
+        assert_eq!(memory.free_frames(), 2);
+        // the frame went back to the node's buddy allocator, merged with its buddy
+        assert_eq!(memory.fragmentation(0).free_blocks[..2], [0, 1]);
+        memory.read(frame.addr(), &mut bytes).unwrap();
+        assert_eq!(bytes, [0xaa; 6]);
+    }
+
+    #[test]
+    fn fork_cow_and_eviction_through_physical_memory() {
+        let memory = Arc::new(PhysicalMemory::new(8, Some(0xaa)));
+        let mut parent = AddressSpace::new("parent");
+        parent.set_physical_memory(memory.clone());
+        parent.set_swap(Arc::new(SwapSpace::new(Arc::new(MemorySource::new(0)), 4)));
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = parent
+            .add_anonymous_mapping(2 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        parent
+            .advise(addr, 2 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        parent.write_bytes(addr, b"parent").unwrap();
+        parent
+            .write_bytes(addr + address_space::PAGE_SIZE, b"both")
+            .unwrap();
+        assert_eq!(memory.free_frames(), 6);
+        let at = |space: &AddressSpace, addr, len| {
+            let mut bytes = vec![0; len];
+            memory
+                .read(space.translate(addr).unwrap(), &mut bytes)
+                .unwrap();
+            bytes
+        };
+        assert_eq!(at(&parent, addr, 6), b"parent");
+
+        // the child shares both frames until it writes to one
+        let mut child = parent.fork("child").unwrap();
+        assert_eq!(memory.free_frames(), 6);
+        assert_eq!(child.translate(addr), parent.translate(addr));
+        child.write_bytes(addr, b"child!").unwrap();
+        assert_eq!(memory.free_frames(), 5);
+        assert_ne!(child.translate(addr), parent.translate(addr));
+        assert_eq!(at(&parent, addr, 6), b"parent");
+        assert_eq!(at(&child, addr, 6), b"child!");
+        assert_eq!(
+            child.translate(addr + address_space::PAGE_SIZE),
+            parent.translate(addr + address_space::PAGE_SIZE)
+        );
+
+        // swapping the parent's page out frees and poisons its frame
+        let old = parent.translate(addr).unwrap();
+        assert_eq!(parent.swap_out(1), 1);
+        assert_eq!(parent.translate(addr), None);
+        let mut stale = [0; 6];
+        memory.read(old, &mut stale).unwrap();
+        assert_eq!(stale, [0xaa; 6]);
+        assert_eq!(memory.free_frames(), 6);
+        let mut back = [0; 6];
+        parent.read_bytes(addr, &mut back).unwrap();
+        assert_eq!(&back, b"parent");
+        assert_eq!(at(&parent, addr + address_space::PAGE_SIZE, 4), b"both");
+
+        drop(child);
+        assert_eq!(memory.free_frames(), 6);
+    }
+
+    #[test]
+    fn memory_policies_pick_nodes() {
+        let memory = Arc::new(PhysicalMemory::with_nodes(&[4, 4], None));
+        let mut addr_space = AddressSpace::new("Test address space");
+        addr_space.set_physical_memory(memory.clone());
+        assert_eq!(addr_space.set_current_node(2), Err(numa::NO_SUCH_NODE));
+        addr_space.set_current_node(1).unwrap();
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(9 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        addr_space
+            .advise(addr, 9 * address_space::PAGE_SIZE, Advice::Random)
+            .unwrap();
+        let page = |i: usize| addr + i * address_space::PAGE_SIZE;
+        let two = 2 * address_space::PAGE_SIZE;
+        addr_space
+            .set_memory_policy(page(2), two, MemoryPolicy::Preferred(0))
+            .unwrap();
+        addr_space
+            .set_memory_policy(
+                page(4),
+                two,
+                MemoryPolicy::Interleave(NodeMask::of(&[0, 1]).unwrap()),
+            )
+            .unwrap();
+        addr_space
+            .set_memory_policy(
+                page(6),
+                two,
+                MemoryPolicy::Bind(NodeMask::of(&[1]).unwrap()),
+            )
+            .unwrap();
+        assert!(addr_space
+            .set_memory_policy(page(6), two, MemoryPolicy::Bind(NodeMask::default()))
+            .is_err());
+        assert_eq!(NodeMask::of(&[64]), Err(numa::NO_SUCH_NODE));
+        let missing = [
+            MemoryPolicy::Preferred(2),
+            MemoryPolicy::Bind(NodeMask::of(&[1, 2]).unwrap()),
+        ];
+        for policy in missing {
+            assert_eq!(
+                addr_space.set_memory_policy(page(6), two, policy),
+                Err(numa::NO_SUCH_NODE)
+            );
+        }
+        // a node that's gone is tried first and found full, rather than standing in for another
+        assert_eq!(MemoryPolicy::Preferred(5).nodes(1, 2, 0), [5, 0, 1]);
+        assert!(MemoryPolicy::Local.nodes(0, 0, 0).is_empty());
+
+        let node = |space: &AddressSpace, i| {
+            memory.node_of(Frame::containing(space.translate(page(i)).unwrap()))
+        };
+        for i in 0..7 {
+            addr_space.write_bytes(page(i), &[1]).unwrap();
+        }
+        assert_eq!(
+            (0..7).map(|i| node(&addr_space, i)).collect::<Vec<_>>(),
+            [1, 1, 0, 0, 0, 1, 1]
+        );
+
+        // node 1 is full: a bound page can't go anywhere else, but a local one can
+        assert_eq!(
+            addr_space.write_bytes(page(7), &[1]),
+            Err((0, "Out of physical memory."))
+        );
+        addr_space.write_bytes(page(8), &[1]).unwrap();
+        assert_eq!(node(&addr_space, 8), 0);
+
+        assert_eq!(
+            memory.node_stats(0),
+            NodeStats {
+                frames: 4,
+                free: 0,
+                hits: 3,
+                misses: 1,
+                interleaved: 1
+            }
+        );
+        assert_eq!(
+            memory.node_stats(1),
+            NodeStats {
+                frames: 4,
+                free: 0,
+                hits: 4,
+                misses: 0,
+                interleaved: 1
+            }
+        );
+    }
+
+    #[test]
+    fn user_faults_are_queued_and_resolved() {
+        let mut addr_space = AddressSpace::new("Test address space");
+        let flags = FlagBuilder::new()
+            .toggle_read()
+            .toggle_write()
+            .toggle_private();
+        let addr = addr_space
+            .add_anonymous_mapping(3 * address_space::PAGE_SIZE, flags)
+            .unwrap();
+        let page = |i: usize| addr + i * address_space::PAGE_SIZE;
+        let mode = UserFaultMode {
+            missing: true,
+            write_protect: true,
+        };
+        addr_space
+            .register_userfault(addr, 3 * address_space::PAGE_SIZE, mode)
+            .unwrap();
+
+        // a missing page is queued once, however often the access is retried
+        let mut byte = [0];
+        assert_eq!(
+            addr_space.read_bytes(page(0), &mut byte),
+            Err((0, userfault::WAITING))
+        );
+        assert_eq!(
+            addr_space.read_bytes(page(0), &mut byte),
+            Err((0, userfault::WAITING))
+        );
+        let event = UserFaultEvent {
+            addr: page(0),
+            write: false,
+            kind: UserFaultKind::Missing,
+        };
+        assert_eq!(addr_space.read_userfault(), Some(event));
+        assert_eq!(addr_space.read_userfault(), None);
+        addr_space
+            .userfault_copy(page(0), &[7; address_space::PAGE_SIZE], true)
+            .unwrap();
+        assert!(!addr_space.userfault_waiting(page(0)));
+        addr_space.read_bytes(page(0), &mut byte).unwrap();
+        assert_eq!(byte, [7]);
+        assert_eq!(
+            addr_space.userfault_copy(page(0), &[7; address_space::PAGE_SIZE], true),
+            Err("A page is already there.")
+        );
+
+        // filling a page without waking leaves the fault waiting until it's woken
+        assert!(addr_space.write_bytes(page(1), &[1]).is_err());
+        assert_eq!(
+            addr_space.read_userfault().unwrap().kind,
+            UserFaultKind::Missing
+        );
+        addr_space
+            .userfault_zeropage(page(1), address_space::PAGE_SIZE, false)
+            .unwrap();
+        assert!(addr_space.userfault_waiting(page(1)));
+        assert_eq!(
+            addr_space.write_bytes(page(1), &[1]),
+            Err((0, userfault::WAITING))
+        );
+        assert_eq!(addr_space.read_userfault(), None);
+        addr_space.userfault_wake(page(1), address_space::PAGE_SIZE);
+        addr_space.write_bytes(page(1), &[1]).unwrap();
+
+        // waking a page that's still missing sends the retried fault back to the handler
+        assert!(addr_space.read_bytes(page(2), &mut byte).is_err());
+        addr_space.read_userfault().unwrap();
+        addr_space.userfault_wake(page(2), address_space::PAGE_SIZE);
+        assert!(addr_space.read_bytes(page(2), &mut byte).is_err());
+        assert_eq!(addr_space.read_userfault().unwrap().addr, page(2));
+
+        // writes to write-protected pages go to the handler too
+        addr_space
+            .userfault_write_protect(page(0), address_space::PAGE_SIZE, true)
+            .unwrap();
+        addr_space.read_bytes(page(0), &mut byte).unwrap();
+        assert_eq!(
+            addr_space.write_bytes(page(0), &[8]),
+            Err((0, userfault::WAITING))
+        );
+        let event = UserFaultEvent {
+            addr: page(0),
+            write: true,
+            kind: UserFaultKind::WriteProtect,
+        };
+        assert_eq!(addr_space.read_userfault(), Some(event));
+        addr_space
+            .userfault_write_protect(page(0), address_space::PAGE_SIZE, false)
+            .unwrap();
+        addr_space.write_bytes(page(0), &[8]).unwrap();
+
+        // once unregistered, missing pages are read in as usual
+        addr_space
+            .unregister_userfault(addr, 3 * address_space::PAGE_SIZE)
+            .unwrap();
+        addr_space.read_bytes(page(2), &mut byte).unwrap();
+        assert_eq!(byte, [0]);
+        assert!(addr_space
+            .userfault_write_protect(addr, address_space::PAGE_SIZE, true)
+            .is_err());
+    }
 }
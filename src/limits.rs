@@ -0,0 +1,46 @@
+// Resource limits on an `AddressSpace`, after `setrlimit` and `vm.max_map_count`.
+//
+// Every operation that grows memory checks them before changing anything, and fails with one of
+// the errors below so that callers can tell which limit they ran into.
+
+/// The address space would grow past `Limits::address_space`.
+pub const ADDRESS_SPACE_LIMIT: &str = "The address space limit would be exceeded.";
+/// The heap and private writable mappings would grow past `Limits::data`.
+pub const DATA_LIMIT: &str = "The data limit would be exceeded.";
+/// A stack would grow past `Limits::stack`.
+pub const STACK_LIMIT: &str = "The stack limit would be exceeded.";
+/// More memory would be locked than `Limits::memlock` allows.
+pub const MEMLOCK_LIMIT: &str = "Locking the range would exceed the lock limit.";
+/// There would be more mappings than `Limits::max_map_count`.
+pub const MAP_COUNT_LIMIT: &str = "Too many mappings.";
+
+/// The default for `Limits::max_map_count`, the same as Linux's.
+pub const DEFAULT_MAX_MAP_COUNT: usize = 65530;
+
+/// Limits on how much memory an `AddressSpace` may use. Sizes are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// All mappings together, like `RLIMIT_AS`.
+    pub address_space: usize,
+    /// The heap plus every private writable mapping that isn't a stack, like `RLIMIT_DATA`.
+    pub data: usize,
+    /// Each stack, like `RLIMIT_STACK`. A stack's own maximum size can only lower this.
+    pub stack: usize,
+    /// Locked memory, like `RLIMIT_MEMLOCK`.
+    pub memlock: usize,
+    /// The number of separate mappings, like `vm.max_map_count`.
+    pub max_map_count: usize,
+}
+
+impl Default for Limits {
+    /// No limits, apart from the usual number of mappings.
+    fn default() -> Self {
+        Self {
+            address_space: usize::MAX,
+            data: usize::MAX,
+            stack: usize::MAX,
+            memlock: usize::MAX,
+            max_map_count: DEFAULT_MAX_MAP_COUNT,
+        }
+    }
+}
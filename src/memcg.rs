@@ -0,0 +1,202 @@
+// Memory groups: caps on the memory of several address spaces together, after Linux's memory
+// cgroups.
+//
+// Every `AddressSpace` that joins a `MemoryGroup` charges its resident pages to it, anonymous
+// and file-backed separately, along with its pages out on swap. A fault that would take the
+// group over its hard limit reclaims from the faulting address space, and only from it: an
+// `AddressSpace` can't reach the other members of its group, so if it has nothing left to evict
+// the fault fails with `GROUP_OOM`, and it's up to whoever owns the members to `reclaim` from
+// them and try again. The soft limit is only a preference: groups over it are reclaimed
+// from first when `CacheCoordinator::step` runs.
+//
+// The counts are atomics, like `CommitAccountant`'s, so that the address spaces of a group don't
+// need a lock to share it.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::accounting::PageKind;
+use crate::address_space::AddressSpace;
+
+/// A fault couldn't make room under the group's hard limit.
+pub const GROUP_LIMIT: &str = "The memory group's hard limit would be exceeded.";
+/// A fault reached the group's hard limit and the faulting address space had nothing left to
+/// evict. Other members may still have pages to give back; see `MemoryGroup::reclaim`.
+pub const GROUP_OOM: &str =
+    "The memory group is at its hard limit and the faulting address space has nothing left to reclaim.";
+
+/// A snapshot of a `MemoryGroup`'s counters, like `memory.stat`. Counts are in pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GroupStats {
+    /// Resident pages of anonymous memory.
+    pub anon: usize,
+    /// Resident pages cached from files.
+    pub file: usize,
+    /// Pages written out to swap.
+    pub swap: usize,
+    /// The most resident pages there have been.
+    pub peak: usize,
+    /// `AddressSpace`s in the group.
+    pub members: usize,
+    /// Times the hard limit was reached and a fault had to reclaim.
+    pub limit_hits: usize,
+    /// Pages reclaimed to keep the group under one of its limits.
+    pub reclaimed: usize,
+}
+
+/// Resident and swapped memory charged by every `AddressSpace` that's joined it. See
+/// `AddressSpace::join_memory_group`.
+pub struct MemoryGroup {
+    hard_limit: AtomicUsize, // in resident pages
+    soft_limit: AtomicUsize,
+    anon: AtomicUsize,
+    file: AtomicUsize,
+    swap: AtomicUsize,
+    peak: AtomicUsize,
+    members: AtomicUsize,
+    limit_hits: AtomicUsize,
+    reclaimed: AtomicUsize,
+}
+
+impl MemoryGroup {
+    /// An empty group allowing `hard_limit` resident pages, preferring to stay under
+    /// `soft_limit`.
+    #[must_use]
+    pub const fn new(hard_limit: usize, soft_limit: usize) -> Self {
+        Self {
+            hard_limit: AtomicUsize::new(hard_limit),
+            soft_limit: AtomicUsize::new(soft_limit),
+            anon: AtomicUsize::new(0),
+            file: AtomicUsize::new(0),
+            swap: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            members: AtomicUsize::new(0),
+            limit_hits: AtomicUsize::new(0),
+            reclaimed: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn hard_limit(&self) -> usize {
+        self.hard_limit.load(Ordering::Relaxed)
+    }
+
+    /// Lowering the hard limit below what's resident doesn't evict anything by itself; see
+    /// `reclaim`.
+    pub fn set_hard_limit(&self, limit: usize) {
+        self.hard_limit.store(limit, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn soft_limit(&self) -> usize {
+        self.soft_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_soft_limit(&self, limit: usize) {
+        self.soft_limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Resident pages charged to the group.
+    #[must_use]
+    pub fn resident(&self) -> usize {
+        self.anon.load(Ordering::Relaxed) + self.file.load(Ordering::Relaxed)
+    }
+
+    /// How many more pages can be made resident before the hard limit is reached.
+    #[must_use]
+    pub fn room(&self) -> usize {
+        self.hard_limit().saturating_sub(self.resident())
+    }
+
+    #[must_use]
+    pub fn over_soft_limit(&self) -> bool {
+        self.resident() > self.soft_limit()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> GroupStats {
+        GroupStats {
+            anon: self.anon.load(Ordering::Relaxed),
+            file: self.file.load(Ordering::Relaxed),
+            swap: self.swap.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            members: self.members.load(Ordering::Relaxed),
+            limit_hits: self.limit_hits.load(Ordering::Relaxed),
+            reclaimed: self.reclaimed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reclaim from the members among `spaces`, scanning up to `scan` pages, until the group is
+    /// back under its hard limit. Returns how many pages were evicted. Spaces outside the group
+    /// are left alone.
+    pub fn reclaim(self: &Arc<Self>, spaces: &mut [&mut AddressSpace], scan: usize) -> usize {
+        let mut scanned = 0;
+        let mut evicted = 0;
+        for space in spaces.iter_mut().filter(|space| {
+            space
+                .memory_group()
+                .is_some_and(|group| Arc::ptr_eq(group, self))
+        }) {
+            if scanned >= scan || self.resident() <= self.hard_limit() {
+                break;
+            }
+            let (space_scanned, space_evicted) =
+                space.shrink_lists(scan - scanned, || self.resident() <= self.hard_limit());
+            scanned += space_scanned;
+            evicted += space_evicted;
+        }
+        self.note_reclaimed(evicted);
+        evicted
+    }
+
+    /// Add a member that already has `anon` and `file` pages resident and `swap` pages
+    /// swapped out.
+    pub(crate) fn join(&self, anon: usize, file: usize, swap: usize) {
+        self.members.fetch_add(1, Ordering::Relaxed);
+        self.anon.fetch_add(anon, Ordering::Relaxed);
+        self.file.fetch_add(file, Ordering::Relaxed);
+        self.swap.fetch_add(swap, Ordering::Relaxed);
+        self.peak.fetch_max(self.resident(), Ordering::Relaxed);
+    }
+
+    /// Take a member, and everything it has charged, back out.
+    pub(crate) fn leave(&self, anon: usize, file: usize, swap: usize) {
+        self.members.fetch_sub(1, Ordering::Relaxed);
+        self.anon.fetch_sub(anon, Ordering::Relaxed);
+        self.file.fetch_sub(file, Ordering::Relaxed);
+        self.swap.fetch_sub(swap, Ordering::Relaxed);
+    }
+
+    pub(crate) fn charge(&self, kind: PageKind) {
+        self.resident_of(kind).fetch_add(1, Ordering::Relaxed);
+        self.peak.fetch_max(self.resident(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn uncharge(&self, kind: PageKind) {
+        self.resident_of(kind).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn charge_swap(&self) {
+        self.swap.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uncharge_swap(&self) {
+        self.swap.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn note_limit_hit(&self) {
+        self.limit_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn note_reclaimed(&self, pages: usize) {
+        self.reclaimed.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    fn resident_of(&self, kind: PageKind) -> &AtomicUsize {
+        if kind.is_file() {
+            &self.file
+        } else {
+            &self.anon
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Memory policies for machines with several memory nodes, after Linux's `mbind`.
+//
+// `PhysicalMemory` can be split into nodes, each standing for the memory attached to one socket.
+// Every mapping carries a `MemoryPolicy` saying which nodes its frames should come from, and a
+// fault picks a node from the policy and the node of the CPU it happened on. The nodes are just
+// ranges of frames, so all of this can be tried out on one machine.
+
+use alloc::vec::Vec;
+
+/// A policy named a node that isn't there.
+pub const NO_SUCH_NODE: &str = "The policy names a node that doesn't exist.";
+
+/// A set of nodes, one bit each, so up to 64 of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeMask(pub u64);
+
+impl NodeMask {
+    /// The mask holding each node in `nodes`.
+    ///
+    /// # Errors
+    /// If a node is 64 or more, which a mask can't hold.
+    pub fn of(nodes: &[usize]) -> Result<Self, &'static str> {
+        nodes
+            .iter()
+            .try_fold(NodeMask(0), |mask, &node| match node < 64 {
+                true => Ok(NodeMask(mask.0 | 1 << node)),
+                false => Err(NO_SUCH_NODE),
+            })
+    }
+
+    #[must_use]
+    pub const fn contains(self, node: usize) -> bool {
+        node < 64 && self.0 & 1 << node != 0
+    }
+
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Which nodes a mapping's frames come from. See `AddressSpace::set_memory_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// The faulting CPU's node, or the others if it's full.
+    #[default]
+    Local,
+    /// The given node, or the others if it's full.
+    Preferred(usize),
+    /// Only these nodes, the faulting CPU's first if it's one of them.
+    Bind(NodeMask),
+    /// These nodes in turn, one page each, moving on to the next of them if one is full.
+    Interleave(NodeMask),
+}
+
+impl MemoryPolicy {
+    /// Check that the policy names at least one node, and only nodes below `nodes`.
+    pub(crate) fn check(self, nodes: usize) -> Result<(), &'static str> {
+        match self {
+            MemoryPolicy::Local => Ok(()),
+            MemoryPolicy::Preferred(node) if node < nodes => Ok(()),
+            MemoryPolicy::Preferred(_) => Err(NO_SUCH_NODE),
+            MemoryPolicy::Bind(mask) | MemoryPolicy::Interleave(mask) if mask.is_empty() => {
+                Err("The policy names no nodes.")
+            }
+            MemoryPolicy::Bind(mask) | MemoryPolicy::Interleave(mask) => {
+                match nodes < 64 && mask.0 >> nodes != 0 {
+                    true => Err(NO_SUCH_NODE),
+                    false => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// The nodes to try, in order, out of `nodes`, for a fault on a CPU of node `local`. The
+    /// first is the one the policy intended; if that node doesn't exist it's still first, to be
+    /// found full, and the rest follow from node 0. `turn` counts interleaved allocations so far.
+    pub(crate) fn nodes(self, local: usize, nodes: usize, turn: usize) -> Vec<usize> {
+        if nodes == 0 {
+            return Vec::new();
+        }
+        let from = |first: usize, mask: Option<NodeMask>| -> Vec<usize> {
+            let missing = (first >= nodes).then_some(first);
+            let start = if first < nodes { first } else { 0 };
+            missing
+                .into_iter()
+                .chain((0..nodes).map(|i| (start + i) % nodes))
+                .filter(|&node| mask.is_none_or(|mask| mask.contains(node)))
+                .collect()
+        };
+        match self {
+            MemoryPolicy::Local => from(local, None),
+            MemoryPolicy::Preferred(node) => from(node, None),
+            MemoryPolicy::Bind(mask) => from(local, Some(mask)),
+            MemoryPolicy::Interleave(mask) => {
+                let allowed = from(0, Some(mask));
+                if allowed.is_empty() {
+                    return allowed;
+                }
+                from(allowed[turn % allowed.len()], Some(mask))
+            }
+        }
+    }
+}
+
+/// How one node's frames have been handed out, like `numastat`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    pub frames: usize,
+    pub free: usize,
+    /// Frames allocated here because the policy asked for this node.
+    pub hits: usize,
+    /// Frames allocated here because the node the policy asked for was full.
+    pub misses: usize,
+    /// Hits from `MemoryPolicy::Interleave`.
+    pub interleaved: usize,
+}
@@ -0,0 +1,448 @@
+// Simulated physical memory, for checking what ends up in RAM without real hardware.
+//
+// A `PhysicalMemory` is an array of bytes split into frames. Once an `AddressSpace` is given
+// one, every page it faults in is kept in a frame of it: reads from `DataSource`s and swap are
+// copied into frames, and user byte copies read and write the frames. Forking shares frames
+// between parent and child until one of them writes, so copy-on-write can be seen happening.
+//
+// The frames can be split into NUMA nodes; see `numa`.
+//
+// Each node's free frames are handed out by a `BuddyAllocator` of its own, behind a spin lock
+// that's only held while a frame is taken or given back. The bytes and per-frame reference counts
+// are atomics, like `SwapSpace`'s slots, so sharing and reading frames needs no lock at all.
+// Nothing stops two pages being written through the same frame at once, but only frames that
+// aren't shared are ever written.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::address_space::PAGE_SIZE;
+use crate::buddy::{BuddyAllocator, FragmentationStats};
+use crate::numa::{MemoryPolicy, NodeStats};
+
+/// A physical frame number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Frame(pub usize);
+
+impl Frame {
+    /// The frame holding physical address `addr`.
+    #[must_use]
+    pub const fn containing(addr: usize) -> Self {
+        Frame(addr / PAGE_SIZE)
+    }
+
+    /// Physical address of the frame's first byte.
+    #[must_use]
+    pub const fn addr(self) -> usize {
+        self.0 * PAGE_SIZE
+    }
+}
+
+/// The reference count of a frame whose last page has let go of it, while it's being poisoned.
+const FREEING: usize = usize::MAX;
+
+/// One node's share of the frames, and how they've been handed out.
+struct Node {
+    frames: Range<usize>,
+    allocator: SpinLock<BuddyAllocator>, // the node's free frames, by physical address
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    interleaved: AtomicUsize,
+}
+
+/// Byte-addressable memory made of frames. See `AddressSpace::set_physical_memory`.
+pub struct PhysicalMemory {
+    bytes: Box<[AtomicU8]>,
+    references: Box<[AtomicUsize]>, // pages sharing each frame; 0 if it's free
+    nodes: Box<[Node]>,
+    turn: AtomicUsize,  // interleaved allocations so far
+    poison: Option<u8>, // written over frames as they're freed
+}
+
+impl PhysicalMemory {
+    /// Memory of `frames` frames on a single node, all free and zeroed. With `poison`, freed
+    /// frames are filled with that byte, so reads through stale translations stand out.
+    #[must_use]
+    pub fn new(frames: usize, poison: Option<u8>) -> Self {
+        Self::with_nodes(&[frames], poison)
+    }
+
+    /// Memory split into nodes, one after another, with as many frames as each entry of
+    /// `frames`.
+    ///
+    /// # Panics
+    /// If there are no nodes.
+    #[must_use]
+    pub fn with_nodes(frames: &[usize], poison: Option<u8>) -> Self {
+        assert!(
+            !frames.is_empty(),
+            "Physical memory needs at least one node."
+        );
+        let total: usize = frames.iter().sum();
+        let mut start = 0;
+        let nodes = frames
+            .iter()
+            .map(|&count| {
+                start += count;
+                let frames = start - count..start;
+                let bytes = frames.start * PAGE_SIZE..frames.end * PAGE_SIZE;
+                let allocator = BuddyAllocator::new(core::slice::from_ref(&bytes))
+                    .expect("Bad things are happening.");
+                Node {
+                    frames,
+                    allocator: SpinLock::new(allocator),
+                    hits: AtomicUsize::new(0),
+                    misses: AtomicUsize::new(0),
+                    interleaved: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+        Self {
+            bytes: (0..total * PAGE_SIZE).map(|_| AtomicU8::new(0)).collect(),
+            references: (0..total).map(|_| AtomicUsize::new(0)).collect(),
+            nodes,
+            turn: AtomicUsize::new(0),
+            poison,
+        }
+    }
+
+    #[must_use]
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The node `frame` belongs to.
+    #[must_use]
+    pub fn node_of(&self, frame: Frame) -> usize {
+        self.nodes
+            .iter()
+            .position(|node| node.frames.contains(&frame.0))
+            .expect("Bad things are happening.")
+    }
+
+    #[must_use]
+    pub fn node_stats(&self, node: usize) -> NodeStats {
+        let node = &self.nodes[node];
+        NodeStats {
+            frames: node.frames.len(),
+            free: node.allocator.with(|allocator| allocator.free_frames()),
+            hits: node.hits.load(Ordering::Relaxed),
+            misses: node.misses.load(Ordering::Relaxed),
+            interleaved: node.interleaved.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How node `node`'s free frames are split up.
+    #[must_use]
+    pub fn fragmentation(&self, node: usize) -> FragmentationStats {
+        self.nodes[node]
+            .allocator
+            .with(|allocator| allocator.stats())
+    }
+
+    #[must_use]
+    pub fn frames(&self) -> usize {
+        self.references.len()
+    }
+
+    #[must_use]
+    pub fn free_frames(&self) -> usize {
+        self.nodes
+            .iter()
+            .map(|node| node.allocator.with(|allocator| allocator.free_frames()))
+            .sum()
+    }
+
+    /// How many pages share `frame`.
+    #[must_use]
+    pub fn references(&self, frame: Frame) -> usize {
+        match self.references[frame.0].load(Ordering::Relaxed) {
+            FREEING => 0,
+            count => count,
+        }
+    }
+
+    /// Copy the bytes starting at physical address `addr` into `buffer`.
+    ///
+    /// # Errors
+    /// If the range runs past the end of memory.
+    pub fn read(&self, addr: usize, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let bytes = self.range(addr, buffer.len())?;
+        for (byte, cell) in buffer.iter_mut().zip(bytes) {
+            *byte = cell.load(Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Copy `buffer` to the bytes starting at physical address `addr`.
+    ///
+    /// # Errors
+    /// If the range runs past the end of memory.
+    pub fn write(&self, addr: usize, buffer: &[u8]) -> Result<(), &'static str> {
+        let bytes = self.range(addr, buffer.len())?;
+        for (byte, cell) in buffer.iter().zip(bytes) {
+            cell.store(*byte, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Take a free frame from a node chosen by `policy` for a fault on a CPU of node `local`,
+    /// if one of the nodes the policy allows has one.
+    pub(crate) fn allocate(&self, policy: MemoryPolicy, local: usize) -> Option<Frame> {
+        let turn = match policy {
+            MemoryPolicy::Interleave(_) => self.turn.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        let candidates = policy.nodes(local, self.node_count(), turn);
+        let (i, frame) = candidates
+            .iter()
+            .enumerate()
+            .find_map(|(i, &node)| self.allocate_on(node).map(|frame| (i, frame)))?;
+        let node = &self.nodes[candidates[i]];
+        if i > 0 {
+            node.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            node.hits.fetch_add(1, Ordering::Relaxed);
+            if matches!(policy, MemoryPolicy::Interleave(_)) {
+                node.interleaved.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Some(frame)
+    }
+
+    fn allocate_on(&self, node: usize) -> Option<Frame> {
+        let frame = Frame::containing(
+            self.nodes
+                .get(node)?
+                .allocator
+                .with(|allocator| allocator.allocate(0))?,
+        );
+        self.references[frame.0].store(1, Ordering::Release);
+        Some(frame)
+    }
+
+    /// Note that one more page shares `frame`. Only a page that already holds the frame can
+    /// share it; a frame that's free, or being freed, is never brought back.
+    pub(crate) fn share(&self, frame: Frame) {
+        self.references[frame.0]
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                (count != 0 && count != FREEING).then_some(count + 1)
+            })
+            .expect("Bad things are happening.");
+    }
+
+    /// Let go of `frame`, poisoning it and giving it back to its node's allocator if no other page
+    /// shares it. The last page to let go holds the frame at `FREEING` while it's poisoned, so that
+    /// it can't be shared until it's been handed out again.
+    pub(crate) fn release(&self, frame: Frame) {
+        let references = &self.references[frame.0];
+        let count = references
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |count| match count {
+                0 | FREEING => None,
+                1 => Some(FREEING),
+                count => Some(count - 1),
+            })
+            .expect("Bad things are happening.");
+        if count == 1 {
+            if let Some(poison) = self.poison {
+                for cell in &self.bytes[frame.addr()..frame.addr() + PAGE_SIZE] {
+                    cell.store(poison, Ordering::Relaxed);
+                }
+            }
+            references.store(0, Ordering::Release);
+            self.nodes[self.node_of(frame)]
+                .allocator
+                .with(|allocator| allocator.free(frame.addr()))
+                .expect("Bad things are happening.");
+        }
+    }
+
+    fn range(&self, addr: usize, len: usize) -> Result<&[AtomicU8], &'static str> {
+        addr.checked_add(len)
+            .and_then(|end| self.bytes.get(addr..end))
+            .ok_or("Physical address out of range.")
+    }
+}
+
+/// A lock that spins, for the nodes' allocators, which are only held for a few free list
+/// operations at a time.
+struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is only reached through `with`, by one thread at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Run `f` on the value, with the lock held.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: holding the lock means nothing else has the value.
+        let result = f(unsafe { &mut *self.value.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// Where the frames for new pages come from.
+#[derive(Clone, Copy)]
+pub struct Placement<'a> {
+    pub memory: &'a Arc<PhysicalMemory>,
+    pub policy: MemoryPolicy,
+    /// The node of the faulting CPU.
+    pub node: usize,
+}
+
+/// Where a resident page's bytes are kept.
+pub enum PageData {
+    /// On the heap, for an `AddressSpace` without `PhysicalMemory`.
+    Heap(Box<[u8]>),
+    /// In a frame, which may be shared with copies of the page until one of them is written.
+    Frame(Arc<PhysicalMemory>, Frame),
+}
+
+impl PageData {
+    /// A page holding `bytes`, in a frame placed by `placement` if it's given.
+    ///
+    /// # Errors
+    /// If none of the nodes the placement allows has a free frame.
+    pub fn new(placement: Option<Placement>, bytes: &[u8]) -> Result<Self, &'static str> {
+        let Some(Placement {
+            memory,
+            policy,
+            node,
+        }) = placement
+        else {
+            return Ok(PageData::Heap(bytes.into()));
+        };
+        let frame = memory
+            .allocate(policy, node)
+            .ok_or("Out of physical memory.")?;
+        memory
+            .write(frame.addr(), bytes)
+            .expect("Bad things are happening.");
+        Ok(PageData::Frame(memory.clone(), frame))
+    }
+
+    /// The frame holding the page, if it's in one.
+    #[must_use]
+    pub fn frame(&self) -> Option<Frame> {
+        match self {
+            PageData::Heap(_) => None,
+            PageData::Frame(_, frame) => Some(*frame),
+        }
+    }
+
+    /// Is the page's frame shared with another page?
+    #[must_use]
+    pub fn is_shared(&self) -> bool {
+        match self {
+            PageData::Heap(_) => false,
+            PageData::Frame(memory, frame) => memory.references(*frame) > 1,
+        }
+    }
+
+    /// Give the page a frame of its own, copying the shared one, so that it can be written. The
+    /// copy is placed by `policy` for a fault on a CPU of `node`.
+    ///
+    /// # Errors
+    /// If there's no free frame to copy to.
+    pub fn make_exclusive(
+        &mut self,
+        policy: MemoryPolicy,
+        node: usize,
+    ) -> Result<(), &'static str> {
+        let memory = match self {
+            PageData::Frame(memory, frame) if memory.references(*frame) > 1 => memory.clone(),
+            _ => return Ok(()),
+        };
+        *self = PageData::new(
+            Some(Placement {
+                memory: &memory,
+                policy,
+                node,
+            }),
+            &self.to_vec(),
+        )?;
+        Ok(())
+    }
+
+    /// Copy the bytes starting at `offset` into `buffer`.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) {
+        match self {
+            PageData::Heap(data) => buffer.copy_from_slice(&data[offset..offset + buffer.len()]),
+            PageData::Frame(memory, frame) => {
+                memory
+                    .read(frame.addr() + offset, buffer)
+                    .expect("Bad things are happening.");
+            }
+        }
+    }
+
+    /// Copy `buffer` to the bytes starting at `offset`. The page mustn't be shared; see
+    /// `make_exclusive`.
+    pub fn write(&mut self, offset: usize, buffer: &[u8]) {
+        debug_assert!(!self.is_shared());
+        match self {
+            PageData::Heap(data) => data[offset..offset + buffer.len()].copy_from_slice(buffer),
+            PageData::Frame(memory, frame) => {
+                memory
+                    .write(frame.addr() + offset, buffer)
+                    .expect("Bad things are happening.");
+            }
+        }
+    }
+
+    /// Set `len` bytes starting at `offset` to `byte`.
+    pub fn fill(&mut self, offset: usize, len: usize, byte: u8) {
+        self.write(offset, &vec![byte; len]);
+    }
+
+    /// All of the page's bytes.
+    #[must_use]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = vec![0; PAGE_SIZE];
+        self.read(0, &mut bytes);
+        bytes
+    }
+}
+
+impl Clone for PageData {
+    /// Copies of a page in a frame share the frame.
+    fn clone(&self) -> Self {
+        match self {
+            PageData::Heap(data) => PageData::Heap(data.clone()),
+            PageData::Frame(memory, frame) => {
+                memory.share(*frame);
+                PageData::Frame(memory.clone(), *frame)
+            }
+        }
+    }
+}
+
+impl Drop for PageData {
+    fn drop(&mut self) {
+        if let PageData::Frame(memory, frame) = self {
+            memory.release(*frame);
+        }
+    }
+}
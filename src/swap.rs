@@ -0,0 +1,111 @@
+// Swap: somewhere to put pages that have nowhere else to go.
+//
+// Anonymous pages, and pages of private mappings that have been written to, can't be read back
+// from their `DataSource`, so they can't simply be dropped to free memory. Instead they're
+// written to a slot on a swap `DataSource`, and the mapping remembers the slot in place of the
+// page until a fault reads it back.
+//
+// A slot is shared when a mapping holding it is forked, so every slot has a reference count,
+// and it's only free again once every mapping holding it has let go. The counts are atomics so
+// that one `SwapSpace` can serve many address spaces without a lock.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::address_space::PAGE_SIZE;
+use crate::cacher::Page;
+use crate::data_source::DataSource;
+use crate::physical::{PageData, Placement};
+
+/// Swap slots on a `DataSource`, one page each. See `AddressSpace::set_swap`.
+pub struct SwapSpace {
+    source: Arc<dyn DataSource>,
+    references: Box<[AtomicUsize]>, // how many mappings hold each slot; 0 if it's free
+    next: AtomicUsize,              // where to start looking for a free slot
+}
+
+impl SwapSpace {
+    /// Swap space of `slots` pages, stored from the start of `source`.
+    #[must_use]
+    pub fn new(source: Arc<dyn DataSource>, slots: usize) -> Self {
+        Self {
+            source,
+            references: (0..slots).map(|_| AtomicUsize::new(0)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of slots.
+    #[must_use]
+    pub fn slots(&self) -> usize {
+        self.references.len()
+    }
+
+    /// Number of slots holding a page.
+    #[must_use]
+    pub fn used_slots(&self) -> usize {
+        self.references
+            .iter()
+            .filter(|count| count.load(Ordering::Relaxed) > 0)
+            .count()
+    }
+
+    /// Take a free slot, if there is one.
+    pub(crate) fn allocate(&self) -> Option<usize> {
+        let start = self.next.load(Ordering::Relaxed);
+        (0..self.slots())
+            .map(|i| (start + i) % self.slots())
+            .find(|&slot| {
+                let taken = self.references[slot]
+                    .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok();
+                if taken {
+                    self.next.store(slot + 1, Ordering::Relaxed);
+                }
+                taken
+            })
+    }
+
+    /// Note that one more mapping holds `slot`.
+    pub(crate) fn duplicate(&self, slot: usize) {
+        self.references[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Let go of `slot`, freeing it if nothing else holds it.
+    pub(crate) fn release(&self, slot: usize) {
+        self.references[slot].fetch_sub(1, Ordering::Release);
+    }
+
+    /// How many mappings hold `slot`.
+    pub(crate) fn references(&self, slot: usize) -> usize {
+        self.references[slot].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn write_out(&self, slot: usize, page: &Page) -> Result<(), &'static str> {
+        self.source
+            .write(slot * PAGE_SIZE, PAGE_SIZE, &page.data.to_vec())
+            .map_err(|_| "Swap write failed.")
+    }
+
+    /// Read the page in `slot` back, into a frame placed by `placement` if it's given. The page
+    /// is dirty, as the slot may be freed once it's read.
+    pub(crate) fn read_in(
+        &self,
+        slot: usize,
+        placement: Option<Placement>,
+    ) -> Result<Page, &'static str> {
+        let mut data = vec![0; PAGE_SIZE];
+        self.source
+            .read(slot * PAGE_SIZE, PAGE_SIZE, &mut data)
+            .map_err(|_| "Swap read failed.")?;
+        Ok(Page {
+            data: PageData::new(placement, &data)?,
+            dirty: true,
+            speculative: false,
+            referenced: false,
+            write_protected: false,
+        })
+    }
+}
@@ -0,0 +1,498 @@
+// The syscall bridge: user space asks for changes to its address space with the raw arguments of
+// the Linux memory-management system calls, and gets back either a value or a negative errno,
+// just as it would from Linux. File descriptors are resolved to `DataSource`s by whatever
+// `DescriptorTable` the kernel plugs in.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use crate::address_space::{AddressSpace, Advice, FlagBuilder, SyncMode, PAGE_SIZE};
+use crate::data_source::{self, AnonymousDataSource, DataSource};
+use crate::{commit, limits};
+
+pub const PROT_NONE: u32 = 0x0;
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_SHARED_VALIDATE: u32 = 0x03;
+pub const MAP_TYPE: u32 = 0x0f;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+pub const MAP_NORESERVE: u32 = 0x4000;
+
+pub const MS_ASYNC: u32 = 1;
+pub const MS_INVALIDATE: u32 = 2;
+pub const MS_SYNC: u32 = 4;
+
+pub const MADV_NORMAL: u32 = 0;
+pub const MADV_RANDOM: u32 = 1;
+pub const MADV_SEQUENTIAL: u32 = 2;
+pub const MADV_WILLNEED: u32 = 3;
+pub const MADV_DONTNEED: u32 = 4;
+pub const MADV_FREE: u32 = 8;
+
+pub const EPERM: isize = 1;
+pub const EIO: isize = 5;
+pub const EBADF: isize = 9;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EACCES: isize = 13;
+pub const EINVAL: isize = 22;
+pub const ENOSYS: isize = 38;
+pub const EOPNOTSUPP: isize = 95;
+
+// RISC-V (asm-generic) system call numbers
+pub const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MPROTECT: usize = 226;
+pub const SYS_MSYNC: usize = 227;
+pub const SYS_MADVISE: usize = 233;
+
+/// Resolves a process's file descriptors to the `DataSource`s they refer to.
+pub trait DescriptorTable {
+    fn get(&self, fd: i32) -> Option<Arc<dyn DataSource>>;
+}
+
+impl DescriptorTable for BTreeMap<i32, Arc<dyn DataSource>> {
+    fn get(&self, fd: i32) -> Option<Arc<dyn DataSource>> {
+        BTreeMap::get(self, &fd).cloned()
+    }
+}
+
+/// Carries out memory-management system calls on an `AddressSpace`.
+///
+/// ```
+/// # use std::collections::BTreeMap;
+/// # use reedos_address_space::AddressSpace;
+/// # use reedos_address_space::syscall::*;
+/// let mut space = AddressSpace::new("init");
+/// let files = BTreeMap::new();
+/// let mut bridge = SyscallBridge::new(&mut space, &files);
+/// let addr = bridge.mmap(0, 8192, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+/// assert!(addr > 0);
+/// assert_eq!(bridge.munmap(addr as usize, 8192), 0);
+/// ```
+pub struct SyscallBridge<'a> {
+    space: &'a mut AddressSpace,
+    files: &'a dyn DescriptorTable,
+}
+
+impl<'a> SyscallBridge<'a> {
+    #[must_use]
+    pub fn new(space: &'a mut AddressSpace, files: &'a dyn DescriptorTable) -> Self {
+        Self { space, files }
+    }
+
+    /// Dispatch system call `number` with its raw register arguments.
+    pub fn syscall(&mut self, number: usize, args: [usize; 6]) -> isize {
+        match number {
+            SYS_BRK => self.brk(args[0]) as isize,
+            SYS_MMAP => self.mmap(
+                args[0],
+                args[1],
+                args[2] as u32,
+                args[3] as u32,
+                args[4] as i32,
+                args[5],
+            ),
+            SYS_MUNMAP => self.munmap(args[0], args[1]),
+            SYS_MPROTECT => self.mprotect(args[0], args[1], args[2] as u32),
+            SYS_MSYNC => self.msync(args[0], args[1], args[2] as u32),
+            SYS_MADVISE => self.madvise(args[0], args[1], args[2] as u32),
+            _ => -ENOSYS,
+        }
+    }
+
+    /// The raw `brk` system call, which returns the new break, or the current one if the break
+    /// can't be moved to `addr`.
+    pub fn brk(&mut self, addr: usize) -> usize {
+        let _ = self.space.set_brk(addr);
+        self.space.brk()
+    }
+
+    /// `mmap(2)`. Returns the address of the new mapping.
+    ///
+    /// Without `MAP_FIXED`, `addr` is only a hint and is ignored. Unlike Linux, a `MAP_FIXED`
+    /// mapping can't be placed in a stack's guard gap. A `MAP_FIXED` mapping that fails leaves
+    /// whatever it would have replaced mapped.
+    pub fn mmap(
+        &mut self,
+        addr: usize,
+        length: usize,
+        prot: u32,
+        flags: u32,
+        fd: i32,
+        offset: usize,
+    ) -> isize {
+        if length == 0
+            || !offset.is_multiple_of(PAGE_SIZE)
+            || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        {
+            return -EINVAL;
+        }
+        let Some(length) = length.checked_next_multiple_of(PAGE_SIZE) else {
+            return -ENOMEM;
+        };
+        let mut map_flags = perms(prot);
+        match flags & MAP_TYPE {
+            MAP_SHARED | MAP_SHARED_VALIDATE => map_flags = map_flags.toggle_shared(),
+            MAP_PRIVATE => map_flags = map_flags.toggle_private(),
+            _ => return -EINVAL,
+        }
+        if flags & MAP_NORESERVE != 0 {
+            map_flags = map_flags.toggle_noreserve();
+        }
+        if flags & MAP_TYPE == MAP_SHARED_VALIDATE
+            && flags & !(MAP_TYPE | MAP_FIXED | MAP_ANONYMOUS | MAP_NORESERVE) != 0
+        {
+            return -EOPNOTSUPP;
+        }
+        let source: Arc<dyn DataSource> = if flags & MAP_ANONYMOUS != 0 {
+            Arc::new(AnonymousDataSource)
+        } else {
+            match self.files.get(fd) {
+                Some(source) => source,
+                None => return -EBADF,
+            }
+        };
+        if flags & MAP_FIXED == 0 {
+            return self
+                .space
+                .add_mapping(source, offset, length, map_flags)
+                .map_or_else(|e| -errno(e, ENOMEM), |addr| addr as isize);
+        }
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return -EINVAL;
+        }
+        if addr < PAGE_SIZE {
+            return -EPERM;
+        }
+        self.space
+            .replace_mapping_at(source, offset, length, addr, map_flags)
+            .map_or_else(|e| -errno(e, ENOMEM), |()| addr as isize)
+    }
+
+    /// `munmap(2)`.
+    pub fn munmap(&mut self, addr: usize, length: usize) -> isize {
+        if !addr.is_multiple_of(PAGE_SIZE) || length == 0 {
+            return -EINVAL;
+        }
+        self.space
+            .remove_range(addr, length)
+            .map_or_else(|e| -errno(e, EINVAL), |()| 0)
+    }
+
+    /// `mprotect(2)`.
+    pub fn mprotect(&mut self, addr: usize, length: usize, prot: u32) -> isize {
+        if !addr.is_multiple_of(PAGE_SIZE) || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return -EINVAL;
+        }
+        if !self.space.is_mapped(addr, length) {
+            return -ENOMEM;
+        }
+        self.space
+            .protect(addr, length, perms(prot))
+            .map_or_else(|e| -errno(e, EINVAL), |()| 0)
+    }
+
+    /// `msync(2)`.
+    pub fn msync(&mut self, addr: usize, length: usize, flags: u32) -> isize {
+        if !addr.is_multiple_of(PAGE_SIZE)
+            || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+            || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+        {
+            return -EINVAL;
+        }
+        let mode = if flags & MS_INVALIDATE != 0 {
+            SyncMode::Invalidate
+        } else if flags & MS_SYNC != 0 {
+            SyncMode::Sync
+        } else {
+            SyncMode::Async
+        };
+        let mapped = self.space.is_mapped(addr, length);
+        match self.space.sync_range(addr, length, mode) {
+            Ok(()) => 0,
+            Err(_) if !mapped => -ENOMEM,
+            Err(_) => -EIO,
+        }
+    }
+
+    /// `madvise(2)`.
+    pub fn madvise(&mut self, addr: usize, length: usize, advice: u32) -> isize {
+        let advice = match advice {
+            MADV_NORMAL => Advice::Normal,
+            MADV_RANDOM => Advice::Random,
+            MADV_SEQUENTIAL => Advice::Sequential,
+            MADV_WILLNEED => Advice::WillNeed,
+            MADV_DONTNEED => Advice::DontNeed,
+            MADV_FREE => Advice::Free,
+            _ => return -EINVAL,
+        };
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return -EINVAL;
+        }
+        if !self.space.is_mapped(addr, length) {
+            return -ENOMEM;
+        }
+        match self.space.advise(addr, length, advice) {
+            Ok(()) => 0,
+            Err(_) if advice == Advice::WillNeed => -EIO,
+            Err(_) => -EINVAL,
+        }
+    }
+}
+
+/// The errno for a call that failed with `error`, or `otherwise` if it's none of the errors
+/// with an errno of their own.
+fn errno(error: &str, otherwise: isize) -> isize {
+    if error == data_source::NOT_PERMITTED {
+        EACCES
+    } else if error == data_source::PAST_END {
+        EINVAL
+    } else if error == limits::MEMLOCK_LIMIT {
+        EAGAIN
+    } else if [
+        commit::COMMIT_LIMIT,
+        limits::DATA_LIMIT,
+        limits::MAP_COUNT_LIMIT,
+    ]
+    .contains(&error)
+    {
+        ENOMEM
+    } else {
+        otherwise
+    }
+}
+
+/// The `FlagBuilder` permissions for a `PROT_*` bitmask.
+fn perms(prot: u32) -> FlagBuilder {
+    let mut flags = FlagBuilder::new();
+    if prot & PROT_READ != 0 {
+        flags = flags.toggle_read();
+    }
+    if prot & PROT_WRITE != 0 {
+        flags = flags.toggle_write();
+    }
+    if prot & PROT_EXEC != 0 {
+        flags = flags.toggle_execute();
+    }
+    flags
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::commit::{CommitAccountant, Overcommit};
+    use crate::Limits;
+
+    fn files() -> BTreeMap<i32, Arc<dyn DataSource>> {
+        let mut files: BTreeMap<i32, Arc<dyn DataSource>> = BTreeMap::new();
+        files.insert(
+            3,
+            Arc::new(crate::FileDataSource::new("Cargo.toml").unwrap()),
+        );
+        files
+    }
+
+    #[test]
+    fn mmap_validates_arguments() {
+        let mut space = AddressSpace::new("syscalls");
+        let files = files();
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+
+        assert_eq!(
+            bridge.mmap(0, 0, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0),
+            -EINVAL
+        );
+        assert_eq!(
+            bridge.mmap(0, 4096, PROT_READ, MAP_ANONYMOUS, -1, 0),
+            -EINVAL
+        );
+        assert_eq!(
+            bridge.mmap(
+                0,
+                4096,
+                PROT_READ | 0x80,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0
+            ),
+            -EINVAL
+        );
+        assert_eq!(
+            bridge.mmap(0, 4096, PROT_READ, MAP_PRIVATE, 3, 100),
+            -EINVAL
+        );
+        assert_eq!(bridge.mmap(0, 4096, PROT_READ, MAP_PRIVATE, 4, 0), -EBADF);
+        assert_eq!(
+            bridge.mmap(0x10001, 4096, PROT_READ, MAP_PRIVATE | MAP_FIXED, 3, 0),
+            -EINVAL
+        );
+        assert_eq!(
+            bridge.mmap(0, usize::MAX, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0),
+            -ENOMEM
+        );
+        assert_eq!(bridge.syscall(SYS_MMAP + 1, [0; 6]), -ENOSYS);
+    }
+
+    #[test]
+    fn mmap_file_and_read_through_it() {
+        let mut space = AddressSpace::new("syscalls");
+        let files = files();
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+
+        let addr = bridge.syscall(
+            SYS_MMAP,
+            [
+                0x40000,
+                100,
+                PROT_READ as usize,
+                (MAP_PRIVATE | MAP_FIXED) as usize,
+                3,
+                0,
+            ],
+        );
+        assert_eq!(addr, 0x40000);
+        let mut buffer = [0; 9];
+        space.read_bytes(0x40000, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"[package]");
+    }
+
+    #[test]
+    fn failed_fixed_mmap_keeps_the_old_mapping() {
+        let mut space = AddressSpace::new("syscalls");
+        let commit = Arc::new(CommitAccountant::new(4, Overcommit::Never));
+        space.set_commit_accountant(commit.clone());
+        let files = files();
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+        let anonymous = MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED;
+
+        assert_eq!(
+            bridge.mmap(
+                0x10_0000,
+                2 * PAGE_SIZE,
+                PROT_READ | PROT_WRITE,
+                anonymous,
+                -1,
+                0
+            ),
+            0x10_0000
+        );
+        bridge.space.write_bytes(0x10_0000, b"kept").unwrap();
+        // the file was opened read-only, so it can't be written through a shared mapping
+        let shared = MAP_SHARED | MAP_FIXED;
+        assert_eq!(
+            bridge.mmap(0x10_0000, PAGE_SIZE, PROT_READ | PROT_WRITE, shared, 3, 0),
+            -EACCES
+        );
+        // and this one is only refused once the old mapping's charge has been given back
+        assert_eq!(
+            bridge.mmap(
+                0x10_0000,
+                8 * PAGE_SIZE,
+                PROT_READ | PROT_WRITE,
+                anonymous,
+                -1,
+                0
+            ),
+            -ENOMEM
+        );
+
+        assert!(space.is_mapped(0x10_0000, 2 * PAGE_SIZE));
+        let mut buffer = [0; 4];
+        space.read_bytes(0x10_0000, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"kept");
+        assert_eq!(commit.committed(), 2);
+    }
+
+    #[test]
+    fn mprotect_munmap_and_friends() {
+        let mut space = AddressSpace::new("syscalls");
+        let files = files();
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+
+        let addr = bridge.mmap(
+            0,
+            4 * PAGE_SIZE,
+            PROT_READ,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        ) as usize;
+        assert_eq!(
+            bridge.mprotect(addr + PAGE_SIZE, PAGE_SIZE, PROT_READ | PROT_WRITE),
+            0
+        );
+        assert_eq!(bridge.mprotect(addr, 5 * PAGE_SIZE, PROT_READ), -ENOMEM);
+        assert_eq!(bridge.msync(addr, PAGE_SIZE, MS_ASYNC | MS_SYNC), -EINVAL);
+        assert_eq!(bridge.msync(addr, 4 * PAGE_SIZE, MS_SYNC), 0);
+        assert_eq!(bridge.madvise(addr, PAGE_SIZE, 100), -EINVAL);
+        assert_eq!(bridge.madvise(addr + 1, PAGE_SIZE, MADV_DONTNEED), -EINVAL);
+        assert_eq!(bridge.madvise(addr, 4 * PAGE_SIZE, MADV_FREE), 0);
+        assert_eq!(bridge.munmap(addr + 2 * PAGE_SIZE, 2 * PAGE_SIZE), 0);
+        assert_eq!(bridge.madvise(addr, 4 * PAGE_SIZE, MADV_WILLNEED), -ENOMEM);
+
+        assert!(space.write_bytes(addr + PAGE_SIZE, b"ok").is_ok());
+        assert!(space.write_bytes(addr, b"no").is_err());
+        assert!(!space.is_mapped(addr + 2 * PAGE_SIZE, 1));
+    }
+
+    #[test]
+    fn brk_returns_current_break_on_failure() {
+        let mut space = AddressSpace::new("syscalls");
+        space.init_brk(0x10_0800);
+        let files = files();
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+
+        assert_eq!(bridge.syscall(SYS_BRK, [0; 6]), 0x10_0800);
+        assert_eq!(bridge.brk(0x10_4000), 0x10_4000);
+        assert_eq!(bridge.brk(0x1000), 0x10_4000);
+    }
+
+    #[test]
+    fn mprotect_and_munmap_errnos() {
+        let mut space = AddressSpace::new("syscalls");
+        let commit = Arc::new(CommitAccountant::new(1, Overcommit::Never));
+        space.set_commit_accountant(commit);
+        let mut files = files();
+        files.insert(
+            5,
+            Arc::new(crate::FileDataSource::new("tests/fixtures/mapped.txt").unwrap()),
+        );
+        let mut bridge = SyscallBridge::new(&mut space, &files);
+        let rw = PROT_READ | PROT_WRITE;
+
+        // making two pages of a private file mapping writable needs more commit than there is
+        let file = bridge.mmap(0, 2 * PAGE_SIZE, PROT_READ, MAP_PRIVATE, 5, 0) as usize;
+        assert_eq!(bridge.mprotect(file, 2 * PAGE_SIZE, rw), -ENOMEM);
+        assert_eq!(bridge.mprotect(file, 2 * PAGE_SIZE, 0x80), -EINVAL);
+
+        // so does going over the data limit
+        bridge.space.set_limits(Limits {
+            data: PAGE_SIZE,
+            ..Limits::default()
+        });
+        assert_eq!(bridge.mprotect(file, PAGE_SIZE, rw), 0);
+        assert_eq!(bridge.mprotect(file + PAGE_SIZE, PAGE_SIZE, rw), -ENOMEM);
+
+        // and splitting a mapping when there can't be any more of them
+        let other = bridge.mmap(0, 3 * PAGE_SIZE, PROT_READ, MAP_PRIVATE, 5, 0) as usize;
+        // the file mapping was split in two by the first mprotect
+        bridge.space.set_limits(Limits {
+            max_map_count: 3,
+            ..Limits::default()
+        });
+        assert_eq!(
+            bridge.mprotect(other + PAGE_SIZE, PAGE_SIZE, PROT_NONE),
+            -ENOMEM
+        );
+        assert_eq!(bridge.munmap(other + PAGE_SIZE, PAGE_SIZE), -ENOMEM);
+        assert_eq!(bridge.munmap(other, usize::MAX), -EINVAL);
+        assert_eq!(bridge.munmap(other, 3 * PAGE_SIZE), 0);
+    }
+}
@@ -0,0 +1,87 @@
+// Keeping other harts' TLBs honest.
+//
+// When an `AddressSpace` takes a translation away, by unmapping or dropping a page or by
+// removing a permission, other harts may still have the old translation cached. The address
+// space gathers the affected ranges into a batch, asks a `TlbInvalidator` to flush them all at
+// once, and only lets go of the dropped pages after the invalidator has returned.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::cacher::Page;
+
+/// Flushes cached translations of an address space from every hart that might hold them.
+///
+/// reedos implements this with SBI remote fences; tests use a mock that records the calls.
+pub trait TlbInvalidator: Send + Sync {
+    /// Invalidate the translations for every range in `ranges` tagged with `asid`, returning once
+    /// every hart has acknowledged. The ranges are sorted and don't overlap.
+    fn invalidate(&self, asid: usize, ranges: &[Range<usize>]);
+}
+
+/// Ranges waiting to be invalidated, and the pages that were mapped there.
+#[derive(Default)]
+pub struct TlbBatch {
+    ranges: Vec<Range<usize>>,
+    freed: Vec<Page>,
+    open: bool, // opened by `AddressSpace::begin_tlb_batch`; flushing waits for it to close
+}
+
+impl TlbBatch {
+    /// Note that translations for `range` may be stale.
+    pub fn add_range(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        match self.ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.ranges.push(range),
+        }
+    }
+
+    /// Hold on to `pages` until their translations have been invalidated.
+    pub fn defer_free(&mut self, pages: impl IntoIterator<Item = Page>) {
+        self.freed.extend(pages);
+    }
+
+    /// Move everything gathered in `other` into this batch.
+    pub fn append(&mut self, mut other: TlbBatch) {
+        for range in other.ranges.drain(..) {
+            self.add_range(range);
+        }
+        self.freed.append(&mut other.freed);
+    }
+
+    pub fn open(&mut self) {
+        self.open = true;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Number of pages waiting on an invalidation before they can be reused.
+    pub fn pending_pages(&self) -> usize {
+        self.freed.len()
+    }
+
+    /// Invalidate everything gathered so far with `invalidator`, then release the deferred
+    /// pages. Closes the batch.
+    pub fn flush(&mut self, asid: usize, invalidator: Option<&dyn TlbInvalidator>) {
+        self.open = false;
+        if !self.ranges.is_empty() {
+            self.ranges.sort_by_key(|range| range.start);
+            let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.ranges.len());
+            for range in self.ranges.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                    _ => merged.push(range),
+                }
+            }
+            if let Some(invalidator) = invalidator {
+                invalidator.invalidate(asid, &merged);
+            }
+        }
+        self.freed.clear();
+    }
+}
@@ -0,0 +1,76 @@
+// Handing page faults to user space, after Linux's `userfaultfd`.
+//
+// A range registered for missing-page faults doesn't read anything in when a page is missing:
+// the fault is queued as an event and fails with `WAITING`, and the faulting thread is left
+// waiting on the page until a handler fills it in with `AddressSpace::userfault_copy` or
+// `userfault_zeropage`, or wakes it with `userfault_wake`. A range registered for write-protect
+// tracking does the same for writes to pages the handler has write-protected.
+//
+// Waiting is only recorded, not done: the faulting side retries the access once it's been woken,
+// so a handler and the faults it serves can be driven from a single thread.
+
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// The fault was queued for the user fault handler, and the access should be retried once the
+/// handler has woken it.
+pub const WAITING: &str = "The fault is waiting on a user fault handler.";
+
+/// Which faults in a registered range go to the handler.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UserFaultMode {
+    /// Faults on pages that aren't there, like `UFFDIO_REGISTER_MODE_MISSING`.
+    pub missing: bool,
+    /// Writes to write-protected pages, like `UFFDIO_REGISTER_MODE_WP`.
+    pub write_protect: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserFaultKind {
+    Missing,
+    WriteProtect,
+}
+
+/// A fault waiting on the handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserFaultEvent {
+    /// Start of the faulting page.
+    pub addr: usize,
+    pub write: bool,
+    pub kind: UserFaultKind,
+}
+
+/// Events not yet read by the handler, and the pages faults are waiting on.
+#[derive(Default)]
+pub struct UserFaultQueue {
+    events: VecDeque<UserFaultEvent>,
+    waiting: BTreeSet<usize>,
+}
+
+impl UserFaultQueue {
+    /// Queue `event`, unless a fault is already waiting on its page.
+    pub fn deliver(&mut self, event: UserFaultEvent) {
+        if self.waiting.insert(event.addr) {
+            self.events.push_back(event);
+        }
+    }
+
+    /// Take the oldest event. Its fault keeps waiting until it's woken.
+    pub fn read(&mut self) -> Option<UserFaultEvent> {
+        self.events.pop_front()
+    }
+
+    /// Wake the faults waiting on pages starting in `range`.
+    pub fn wake(&mut self, range: Range<usize>) {
+        let woken: Vec<usize> = self.waiting.range(range).copied().collect();
+        for addr in woken {
+            self.waiting.remove(&addr);
+        }
+    }
+
+    #[must_use]
+    pub fn is_waiting(&self, page: usize) -> bool {
+        self.waiting.contains(&page)
+    }
+}